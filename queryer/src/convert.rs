@@ -1,15 +1,27 @@
+use crate::{function, loader::Format};
 use anyhow::{anyhow, Result};
 use polars::prelude::*;
 use sqlparser::ast::{
-    BinaryOperator as SqlBinaryOperator, Expr as SqlExpr, Offset as SqlOffset, OrderByExpr, Select,
-    SelectItem, SetExpr, Statement, TableFactor, TableWithJoins, Value as SqlValue,
+    BinaryOperator as SqlBinaryOperator, DataType as SqlDataType, Expr as SqlExpr,
+    Function as SqlFunction, FunctionArg, Ident, Join as SqlJoin, JoinConstraint, JoinOperator,
+    Offset as SqlOffset, OrderByExpr, Select, SelectItem, SetExpr, Statement, TableFactor,
+    TableWithJoins, UnaryOperator, Value as SqlValue,
 };
 
+// 聚合之前给每一行加上的常量列，COUNT(*)统计这一列，没有GROUP BY时按这一列分组
+pub(crate) const ALL_ROWS: &str = "__all";
+// 聚合时计算HAVING条件的列
+pub(crate) const HAVING: &str = "__having";
+
 // 解析出来的sql
 pub struct Sql<'a> {
     pub(crate) selection: Vec<Expr>,
     pub(crate) condition: Option<Expr>,
-    pub(crate) source: &'a str,
+    pub(crate) source: Table<'a>,
+    pub(crate) joins: Vec<Join<'a>>,
+    pub(crate) aggregation: Option<Aggregation>,
+    // SELECT和ORDER BY中需要计算的列，在排序之前加到数据里
+    pub(crate) computed: Vec<Expr>,
     pub(crate) order_by: Vec<(String, bool)>,
     pub(crate) offset: Option<i64>,
     pub(crate) limit: Option<usize>,
@@ -26,6 +38,65 @@ pub struct Order<'a>(pub(crate) &'a OrderByExpr);
 pub struct Offset<'a>(pub(crate) &'a SqlOffset);
 pub struct Limit<'a>(pub(crate) &'a SqlExpr);
 pub struct Value(pub(crate) SqlValue);
+pub struct JoinClause<'a>(pub(crate) &'a SqlJoin);
+pub struct FunctionCall(pub(crate) SqlFunction);
+pub struct Type(pub(crate) SqlDataType);
+
+// 有GROUP BY或者SELECT中有聚合函数时，需要一起处理的几个子句
+pub struct GroupBy<'a> {
+    pub(crate) projection: &'a [SelectItem],
+    pub(crate) keys: &'a [SqlExpr],
+    pub(crate) having: Option<&'a SqlExpr>,
+    pub(crate) orders: &'a [OrderByExpr],
+}
+
+// FROM和JOIN中的一个数据源
+#[derive(Debug, PartialEq)]
+pub struct Table<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) alias: Option<&'a str>,
+    // SQL里指定的数据格式，没有指定时根据数据判断
+    pub(crate) format: Option<Format>,
+}
+
+impl<'a> Table<'a> {
+    // 在列名前面用来区分数据源的名字，没有别名时使用数据源本身
+    pub(crate) fn qualifier(&self) -> &'a str {
+        self.alias.unwrap_or(self.name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    Inner,
+    Left,
+    Right,
+    Full,
+}
+
+// JOIN子句，on是相等的(左边的列, 右边的列)，这时候还不知道每个数据源有哪些列，
+// 列属于哪个数据源等加载完数据再确定
+#[derive(Debug, PartialEq)]
+pub struct Join<'a> {
+    pub(crate) table: Table<'a>,
+    pub(crate) kind: JoinKind,
+    pub(crate) on: Vec<(String, String)>,
+}
+
+// 分组聚合：按keys分组，每组计算aggs（都带有名字），having是聚合之后的过滤条件
+#[derive(Debug, PartialEq)]
+pub struct Aggregation {
+    pub(crate) keys: Vec<Expr>,
+    pub(crate) aggs: Vec<Expr>,
+    pub(crate) having: Option<Expr>,
+}
+
+// GroupBy转换的结果，selection和order_by只引用聚合之后的列
+pub struct Grouped {
+    pub(crate) aggregation: Aggregation,
+    pub(crate) selection: Vec<Expr>,
+    pub(crate) order_by: Vec<(String, bool)>,
+}
 
 /// 把sqlParser解析出来的Statement转换成需要的结构
 impl<'a> TryFrom<&'a Statement> for Sql<'a> {
@@ -42,30 +113,73 @@ impl<'a> TryFrom<&'a Statement> for Sql<'a> {
                     from: table_with_joins,
                     selection: where_clause,
                     projection,
-                    group_by: _,
+                    group_by,
+                    having,
                     ..
                 } = match &q.body {
                     SetExpr::Select(statement) => statement.as_ref(),
                     _ => return Err(anyhow!("We only support Select Query at the moment")),
                 };
 
-                let source = Source(table_with_joins).try_into()?;
+                let (source, joins) = Source(table_with_joins).try_into()?;
 
                 let condition = match where_clause {
                     Some(expr) => Some(Expression(Box::new(expr.to_owned())).try_into()?),
                     None => None,
                 };
 
-                let mut selection = Vec::with_capacity(8);
-                for p in projection {
-                    let expr = Projection(p).try_into()?;
-                    selection.push(expr);
-                }
+                let grouping = !group_by.is_empty()
+                    || having.is_some()
+                    || projection.iter().any(|p| match p {
+                        SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                            has_aggregate(expr)
+                        }
+                        _ => false,
+                    });
 
-                let mut order_by = Vec::new();
-                for expr in orders {
-                    order_by.push(Order(expr).try_into()?);
-                }
+                let (aggregation, selection, computed, order_by) = if grouping {
+                    let group = GroupBy {
+                        projection,
+                        keys: group_by,
+                        having: having.as_ref(),
+                        orders,
+                    };
+                    let grouped: Grouped = group.try_into()?;
+                    (
+                        Some(grouped.aggregation),
+                        grouped.selection,
+                        Vec::new(),
+                        grouped.order_by,
+                    )
+                } else {
+                    let mut selection = Vec::with_capacity(8);
+                    let mut computed = Vec::new();
+                    // SELECT中计算出来的列的(sql文本, 列名)
+                    let mut named = Vec::new();
+                    for p in projection {
+                        let expr = match Projection(p).try_into()? {
+                            // 计算出来的列先加到数据里，这样ORDER BY里可以使用
+                            Expr::Alias(expr, name) => {
+                                let name = unique_name(&name, &named);
+                                if let SelectItem::UnnamedExpr(e)
+                                | SelectItem::ExprWithAlias { expr: e, .. } = p
+                                {
+                                    named.push((e.to_string(), name.clone()));
+                                }
+                                computed.push((*expr).alias(&name));
+                                col(&name)
+                            }
+                            expr => expr,
+                        };
+                        selection.push(expr);
+                    }
+
+                    let mut order_by = Vec::new();
+                    for (i, o) in orders.iter().enumerate() {
+                        order_by.push(order_column(i, o, &named, &mut computed)?);
+                    }
+                    (None, selection, computed, order_by)
+                };
 
                 let offset = offset.map(|v| Offset(v).into());
                 let limit = limit.map(|v| Limit(v).into());
@@ -74,6 +188,9 @@ impl<'a> TryFrom<&'a Statement> for Sql<'a> {
                     selection,
                     condition,
                     source,
+                    joins,
+                    aggregation,
+                    computed,
                     order_by,
                     offset,
                     limit,
@@ -90,6 +207,19 @@ impl TryFrom<Expression> for Expr {
 
     fn try_from(expr: Expression) -> Result<Self, Self::Error> {
         match *expr.0 {
+            SqlExpr::BinaryOp {
+                left,
+                op: SqlBinaryOperator::Like,
+                right,
+            } => function::like(Expression(left).try_into()?, Expression(right).try_into()?),
+            SqlExpr::BinaryOp {
+                left,
+                op: SqlBinaryOperator::NotLike,
+                right,
+            } => Ok(Self::Not(Box::new(function::like(
+                Expression(left).try_into()?,
+                Expression(right).try_into()?,
+            )?))),
             SqlExpr::BinaryOp {left, op, right} => Ok(Expr::BinaryExpr {
                 left: Box::new(Expression(left).try_into()?),
                 op: Operation(op).try_into()?,
@@ -99,12 +229,166 @@ impl TryFrom<Expression> for Expr {
             SqlExpr::IsNull(expr) => Ok(Self::IsNull(Box::new(Expression(expr).try_into()?))),
             SqlExpr::IsNotNull(expr) => Ok(Self::IsNotNull(Box::new(Expression(expr).try_into()?))),
             SqlExpr::Identifier(id) => Ok(Self::Column(Arc::new(id.value))),
+            SqlExpr::CompoundIdentifier(ids) => Ok(Self::Column(Arc::new(qualified_name(&ids)))),
             SqlExpr::Value(v) => Ok(Self::Literal(Value(v).try_into()?)),
+            SqlExpr::Nested(expr) => Expression(expr).try_into(),
+            SqlExpr::Cast { expr, data_type } => {
+                let expr: Expr = Expression(expr).try_into()?;
+                Ok(expr.cast(Type(data_type).try_into()?))
+            }
+            SqlExpr::UnaryOp { op, expr } => {
+                let expr: Expr = Expression(expr).try_into()?;
+                match (op, expr) {
+                    (UnaryOperator::Plus, expr) => Ok(expr),
+                    (UnaryOperator::Minus, Expr::Literal(LiteralValue::Float64(v))) => Ok(lit(-v)),
+                    (UnaryOperator::Minus, expr) => Ok(lit(0) - expr),
+                    (UnaryOperator::Not, expr) => Ok(Self::Not(Box::new(expr))),
+                    (op, _) => Err(anyhow!("Operator {} is not supported", op)),
+                }
+            }
+            SqlExpr::Function(f) => FunctionCall(f).try_into(),
+            // 从最后一个分支开始，逐个嵌套成when(..).then(..).otherwise(..)
+            SqlExpr::Case {
+                operand,
+                conditions,
+                results,
+                else_result,
+            } => {
+                let operand: Option<Expr> = match operand {
+                    Some(expr) => Some(Expression(expr).try_into()?),
+                    None => None,
+                };
+                let mut acc = match else_result {
+                    Some(expr) => Expression(expr).try_into()?,
+                    None => Self::Literal(LiteralValue::Null),
+                };
+                for (condition, result) in conditions.into_iter().zip(results).rev() {
+                    let condition: Expr = Expression(Box::new(condition)).try_into()?;
+                    let condition = match &operand {
+                        Some(operand) => operand.clone().eq(condition),
+                        None => condition,
+                    };
+                    let result: Expr = Expression(Box::new(result)).try_into()?;
+                    acc = when(condition).then(result).otherwise(acc);
+                }
+                Ok(acc)
+            }
             v => Err(anyhow!("expr {:#?} is not supported", v)),
         }
     }
 }
 
+// 把SqlParser的函数调用转换成DataFrame的Expr，聚合函数之外的函数在function里注册
+impl TryFrom<FunctionCall> for Expr {
+    type Error = anyhow::Error;
+
+    fn try_from(f: FunctionCall) -> Result<Self, Self::Error> {
+        let name = f.0.name.to_string().to_lowercase();
+        if !is_aggregate(&name) {
+            if f.0.distinct {
+                return Err(anyhow!("{} is not supported", f.0));
+            }
+            let mut args = Vec::with_capacity(f.0.args.len());
+            for arg in f.0.args {
+                match arg {
+                    FunctionArg::Unnamed(expr) => args.push(Expression(Box::new(expr)).try_into()?),
+                    arg => return Err(anyhow!("named argument {} is not supported", arg)),
+                }
+            }
+            return function::call(&name, args);
+        }
+
+        let arg: Expr = match f.0.args.as_slice() {
+            [FunctionArg::Unnamed(SqlExpr::Wildcard)] if name == "count" && !f.0.distinct => {
+                return Ok(col(ALL_ROWS).count())
+            }
+            [FunctionArg::Unnamed(expr)] => Expression(Box::new(expr.clone())).try_into()?,
+            _ => return Err(anyhow!("function {} needs exactly one argument", f.0.name)),
+        };
+
+        match (name.as_str(), f.0.distinct) {
+            // COUNT(x)只统计不是null的值
+            ("count", false) => Ok(arg.is_not_null().cast(DataType::UInt32).sum()),
+            ("count", true) => Ok(arg.n_unique()),
+            ("sum", false) => Ok(arg.sum()),
+            ("avg", false) => Ok(arg.mean()),
+            ("min", false) => Ok(arg.min()),
+            ("max", false) => Ok(arg.max()),
+            ("median", false) => Ok(arg.median()),
+            _ => Err(anyhow!("{} is not supported", f.0)),
+        }
+    }
+}
+
+// 把分组相关的子句转换成Aggregation
+// SELECT中除了分组的列，都要放到agg里计算，并且用SELECT中的名字（没有别名时用表达式本身）命名，
+// ORDER BY中出现的聚合如果在SELECT里出现过就使用对应的列，否则增加一个隐藏的列
+impl<'a> TryFrom<GroupBy<'a>> for Grouped {
+    type Error = anyhow::Error;
+
+    fn try_from(group: GroupBy<'a>) -> Result<Self, Self::Error> {
+        let mut keys: Vec<Expr> = Vec::with_capacity(group.keys.len());
+        for expr in group.keys {
+            keys.push(Expression(Box::new(expr.clone())).try_into()?);
+        }
+
+        let mut aggs = Vec::new();
+        let mut selection = Vec::with_capacity(group.projection.len());
+        // SELECT中聚合表达式的(sql文本, 列名)
+        let mut named = Vec::new();
+        for p in group.projection {
+            let (expr, alias) = match p {
+                SelectItem::UnnamedExpr(expr) => (expr, None),
+                SelectItem::ExprWithAlias { expr, alias } => (expr, Some(alias.value.clone())),
+                item => {
+                    return Err(anyhow!(
+                        "projection {} is not supported with GROUP BY",
+                        item
+                    ))
+                }
+            };
+            let converted: Expr = Expression(Box::new(expr.clone())).try_into()?;
+
+            if !has_aggregate(expr) {
+                if !keys.contains(&converted) {
+                    return Err(anyhow!(
+                        "{} must appear in GROUP BY or be used in an aggregate function",
+                        expr
+                    ));
+                }
+                match alias {
+                    Some(alias) => {
+                        aggs.push(converted.first().alias(&alias));
+                        selection.push(col(&alias));
+                    }
+                    None => selection.push(converted),
+                }
+                continue;
+            }
+
+            let name = alias.unwrap_or_else(|| expr.to_string());
+            aggs.push(converted.alias(&name));
+            selection.push(col(&name));
+            named.push((expr.to_string(), name));
+        }
+
+        let having = match group.having {
+            Some(expr) => Some(Expression(Box::new(expr.clone())).try_into()?),
+            None => None,
+        };
+
+        let mut order_by = Vec::with_capacity(group.orders.len());
+        for (i, o) in group.orders.iter().enumerate() {
+            order_by.push(order_column(i, o, &named, &mut aggs)?);
+        }
+
+        Ok(Grouped {
+            aggregation: Aggregation { keys, aggs, having },
+            selection,
+            order_by,
+        })
+    }
+}
 
 // 把SqlParser的BinaryOperator转换成DataFrame的Operator
 impl TryFrom<Operation> for Operator {
@@ -137,13 +421,17 @@ impl<'a> TryFrom<Projection<'a>> for Expr {
     fn try_from(p: Projection<'a>) -> Result<Self, Self::Error> {
         match p.0 {
             SelectItem::UnnamedExpr(SqlExpr::Identifier(id)) => Ok(col(&id.to_string())),
-            SelectItem::ExprWithAlias {
-                expr: SqlExpr::Identifier(id),
-                alias,
-            } => Ok(Expr::Alias(
-                Box::new(Expr::Column(Arc::new(id.to_string()))),
-                Arc::new(alias.to_string()),
-            )),
+            SelectItem::UnnamedExpr(SqlExpr::CompoundIdentifier(ids)) => {
+                Ok(col(&qualified_name(ids)))
+            }
+            // 没有别名时，用表达式本身作为列名，比如upper(name)、a + 1
+            SelectItem::UnnamedExpr(expr) => {
+                let name = expr.to_string();
+                Ok(Expr::try_from(Expression(Box::new(expr.clone())))?.alias(&name))
+            }
+            SelectItem::ExprWithAlias { expr, alias } => {
+                Ok(Expr::try_from(Expression(Box::new(expr.clone())))?.alias(&alias.value))
+            }
             SelectItem::QualifiedWildcard(v) => Ok(col(&v.to_string())),
             SelectItem::Wildcard => Ok(col("*")),
             item => Err(anyhow!("projection {} not supported", item)),
@@ -151,37 +439,210 @@ impl<'a> TryFrom<Projection<'a>> for Expr {
     }
 }
 
-impl<'a> TryFrom<Source<'a>> for &'a str {
+// 把FROM子句转换成第一个数据源和之后JOIN的数据源
+impl<'a> TryFrom<Source<'a>> for (Table<'a>, Vec<Join<'a>>) {
     type Error = anyhow::Error;
 
     fn try_from(source: Source<'a>) -> Result<Self, Self::Error> {
         if source.0.len() != 1 {
-            return Err(anyhow!("We only support single data source at the moment"));
+            return Err(anyhow!(
+                "We only support single data source in FROM, use JOIN ... ON to combine sources"
+            ));
         }
 
         let table = &source.0[0];
-        if !table.joins.is_empty() {
-            return Err(anyhow!("We do not support joint data source at the moment"));
+        let mut joins = Vec::with_capacity(table.joins.len());
+        for join in &table.joins {
+            joins.push(JoinClause(join).try_into()?);
         }
 
-        match &table.relation {
-            TableFactor::Table {name, ..} => Ok(&name.0.first().unwrap().value),
-            _ => Err(anyhow!("We only suport table")),
+        Ok((table_of(&table.relation)?, joins))
+    }
+}
+
+// 把SqlParser的Join转换成我们的Join，目前只支持列相等的连接条件
+impl<'a> TryFrom<JoinClause<'a>> for Join<'a> {
+    type Error = anyhow::Error;
+
+    fn try_from(join: JoinClause<'a>) -> Result<Self, Self::Error> {
+        let table = table_of(&join.0.relation)?;
+        let (kind, constraint) = match &join.0.join_operator {
+            JoinOperator::Inner(c) => (JoinKind::Inner, c),
+            JoinOperator::LeftOuter(c) => (JoinKind::Left, c),
+            JoinOperator::RightOuter(c) => (JoinKind::Right, c),
+            JoinOperator::FullOuter(c) => (JoinKind::Full, c),
+            v => return Err(anyhow!("join {:?} is not supported", v)),
+        };
+
+        let mut on = Vec::new();
+        match constraint {
+            JoinConstraint::On(expr) => equalities(expr, &mut on)?,
+            // USING (a)的左边可能是之前任何一个数据源的列，右边一定是这个数据源的列
+            JoinConstraint::Using(ids) => {
+                for id in ids {
+                    on.push((
+                        id.value.clone(),
+                        format!("{}.{}", table.qualifier(), id.value),
+                    ));
+                }
+            }
+            _ => return Err(anyhow!("join needs ON or USING condition")),
         }
+
+        Ok(Join { table, kind, on })
     }
 }
 
+// 数据源可以直接写url，也可以用read_json('url')这样的写法指定格式
+fn table_of(relation: &TableFactor) -> Result<Table> {
+    match relation {
+        TableFactor::Table {
+            name, alias, args, ..
+        } => {
+            let alias = alias.as_ref().map(|v| v.name.value.as_str());
+            if args.is_empty() {
+                return Ok(Table {
+                    name: &name.0.first().unwrap().value,
+                    alias,
+                    format: None,
+                });
+            }
+
+            let format = Format::from_function(&name.to_string())
+                .ok_or_else(|| anyhow!("table function {} is not supported", name))?;
+            match args.as_slice() {
+                [FunctionArg::Unnamed(SqlExpr::Value(SqlValue::SingleQuotedString(v)))] => {
+                    Ok(Table {
+                        name: v,
+                        alias,
+                        format: Some(format),
+                    })
+                }
+                _ => Err(anyhow!("{} needs a quoted data source", name)),
+            }
+        }
+        _ => Err(anyhow!("We only suport table")),
+    }
+}
+
+// 把 a.x = b.x AND a.y = b.y 拆成[(a.x, b.x), (a.y, b.y)]
+fn equalities(expr: &SqlExpr, on: &mut Vec<(String, String)>) -> Result<()> {
+    match expr {
+        SqlExpr::Nested(expr) => equalities(expr, on),
+        SqlExpr::BinaryOp {
+            left,
+            op: SqlBinaryOperator::And,
+            right,
+        } => {
+            equalities(left, on)?;
+            equalities(right, on)
+        }
+        SqlExpr::BinaryOp {
+            left,
+            op: SqlBinaryOperator::Eq,
+            right,
+        } => match (column_name(left), column_name(right)) {
+            (Some(l), Some(r)) => {
+                on.push((l, r));
+                Ok(())
+            }
+            _ => Err(anyhow!("join condition {} must compare two columns", expr)),
+        },
+        expr => Err(anyhow!(
+            "join condition {} is not supported, only column equality is allowed",
+            expr
+        )),
+    }
+}
+
+// 列名，a.x 这样带限定名的列名保持原样
+fn column_name(expr: &SqlExpr) -> Option<String> {
+    match expr {
+        SqlExpr::Identifier(id) => Some(id.value.clone()),
+        SqlExpr::CompoundIdentifier(ids) => Some(qualified_name(ids)),
+        _ => None,
+    }
+}
+
+// ORDER BY的一项：列名直接使用；和SELECT中计算的某一列相同时使用那一列；
+// 否则计算成一个隐藏的列加到extra里
+fn order_column(
+    i: usize,
+    o: &OrderByExpr,
+    named: &[(String, String)],
+    extra: &mut Vec<Expr>,
+) -> Result<(String, bool)> {
+    if column_name(&o.expr).is_some() {
+        return Order(o).try_into();
+    }
+    let text = o.expr.to_string();
+    let name = match named.iter().find(|(expr, _)| expr == &text) {
+        Some((_, name)) => name.clone(),
+        None => {
+            let name = format!("__order_{}", i);
+            let expr: Expr = Expression(Box::new(o.expr.clone())).try_into()?;
+            extra.push(expr.alias(&name));
+            name
+        }
+    };
+    Ok((name, !o.asc.unwrap_or(true)))
+}
+
+// 列名重复时加上序号，比如SELECT 1, 1
+fn unique_name(name: &str, named: &[(String, String)]) -> String {
+    let used = |v: &str| named.iter().any(|(_, n)| n == v);
+    let mut unique = name.to_string();
+    let mut i = 1;
+    while used(&unique) {
+        unique = format!("{}_{}", name, i);
+        i += 1;
+    }
+    unique
+}
+
+fn is_aggregate(name: &str) -> bool {
+    ["count", "sum", "avg", "min", "max", "median"].contains(&name.to_lowercase().as_str())
+}
+
+// 表达式里是否有聚合函数
+fn has_aggregate(expr: &SqlExpr) -> bool {
+    match expr {
+        SqlExpr::Function(f) => {
+            is_aggregate(&f.name.to_string())
+                || f.args.iter().any(|arg| match arg {
+                    FunctionArg::Named { arg, .. } | FunctionArg::Unnamed(arg) => {
+                        has_aggregate(arg)
+                    }
+                })
+        }
+        SqlExpr::BinaryOp { left, right, .. } => has_aggregate(left) || has_aggregate(right),
+        SqlExpr::UnaryOp { expr, .. }
+        | SqlExpr::Nested(expr)
+        | SqlExpr::Cast { expr, .. }
+        | SqlExpr::IsNull(expr)
+        | SqlExpr::IsNotNull(expr) => has_aggregate(expr),
+        _ => false,
+    }
+}
+
+fn qualified_name(ids: &[Ident]) -> String {
+    ids.iter()
+        .map(|id| id.value.as_str())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
 /// 把SqlParser的order by expr转换成(列名,排序方法)
 impl<'a> TryFrom<Order<'a>> for (String, bool) {
     type Error = anyhow::Error;
 
     fn try_from(o: Order) -> Result<Self, Self::Error> {
-        let name = match &o.0.expr {
-            SqlExpr::Identifier(id) => id.to_string(),
-            expr => {
+        let name = match column_name(&o.0.expr) {
+            Some(name) => name,
+            None => {
                 return Err(anyhow!(
                     "We only support identifier for order by, got {}",
-                    expr
+                    o.0.expr
                 ))
             }
         };
@@ -213,6 +674,25 @@ impl<'a> From<Limit<'a>> for usize {
     }
 }
 
+/// 把CAST中SqlParser的DataType转换成DataFrame的DataType
+impl TryFrom<Type> for DataType {
+    type Error = anyhow::Error;
+
+    fn try_from(t: Type) -> Result<Self, Self::Error> {
+        match t.0 {
+            SqlDataType::SmallInt | SqlDataType::Int => Ok(DataType::Int32),
+            SqlDataType::BigInt => Ok(DataType::Int64),
+            SqlDataType::Real | SqlDataType::Float(_) => Ok(DataType::Float32),
+            SqlDataType::Double | SqlDataType::Decimal(..) => Ok(DataType::Float64),
+            SqlDataType::Boolean => Ok(DataType::Boolean),
+            SqlDataType::Char(_) | SqlDataType::Varchar(_) | SqlDataType::Text => {
+                Ok(DataType::Utf8)
+            }
+            v => Err(anyhow!("Type {} is not supported", v)),
+        }
+    }
+}
+
 /// 把SqlParser的value转换成Dataframe 支持的LiteralValue
 impl TryFrom<Value> for LiteralValue {
     type Error = anyhow::Error;
@@ -220,6 +700,7 @@ impl TryFrom<Value> for LiteralValue {
         match v.0 {
             SqlValue::Number(v, _) => Ok(LiteralValue::Float64(v.parse().unwrap())),
             SqlValue::Boolean(v) => Ok(LiteralValue::Boolean(v)),
+            SqlValue::SingleQuotedString(v) => Ok(LiteralValue::Utf8(v)),
             SqlValue::Null => Ok(LiteralValue::Null),
             v=> Err(anyhow!("Value {} is not supported", v)),
         }
@@ -242,10 +723,190 @@ mod tests {
 
         let statement = &Parser::parse_sql(&TyrDialect::default(), sql.as_ref()).unwrap()[0];
         let sql: Sql = statement.try_into().unwrap();
-        assert_eq!(sql.source, url);
+        assert_eq!(sql.source.name, url);
+        assert_eq!(sql.source.alias, None);
+        assert!(sql.joins.is_empty());
         assert_eq!(sql.limit, Some(5));
         assert_eq!(sql.offset, Some(10));
         assert_eq!(sql.order_by, vec![("c".into(), true)]);
         assert_eq!(sql.selection, vec![col("a"), col("b"), col("c")]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parse_join_work() {
+        let sql = "select a.location, total_cases, b.population \
+            from file:///data/cases.csv a \
+            join file:///data/population.csv b on a.location = b.location \
+            left join file:///data/gdp.csv using (location)";
+
+        let statement = &Parser::parse_sql(&TyrDialect::default(), sql).unwrap()[0];
+        let sql: Sql = statement.try_into().unwrap();
+        assert_eq!(sql.source.qualifier(), "a");
+        assert_eq!(
+            sql.joins,
+            vec![
+                Join {
+                    table: Table {
+                        name: "file:///data/population.csv",
+                        alias: Some("b"),
+                        format: None,
+                    },
+                    kind: JoinKind::Inner,
+                    on: vec![("a.location".into(), "b.location".into())],
+                },
+                Join {
+                    table: Table {
+                        name: "file:///data/gdp.csv",
+                        alias: None,
+                        format: None,
+                    },
+                    kind: JoinKind::Left,
+                    on: vec![("location".into(), "file:///data/gdp.csv.location".into())],
+                },
+            ]
+        );
+        assert_eq!(sql.selection[0], col("a.location"));
+    }
+
+    #[test]
+    fn unsupported_join_should_fail() {
+        let parse = |sql: &str| {
+            let statement = &Parser::parse_sql(&TyrDialect::default(), sql).unwrap()[0];
+            Sql::try_from(statement).is_err()
+        };
+        assert!(parse("select * from a, b"));
+        assert!(parse("select * from a join b on a.x > b.x"));
+        assert!(parse("select * from a cross join b"));
+    }
+
+    #[test]
+    fn parse_group_by_work() {
+        let sql = "select location, sum(new_cases) total, count(*) from data \
+            group by location having max(new_cases) > 10 \
+            order by sum(new_cases) desc, avg(new_cases), location";
+
+        let statement = &Parser::parse_sql(&TyrDialect::default(), sql).unwrap()[0];
+        let sql: Sql = statement.try_into().unwrap();
+        assert_eq!(
+            sql.aggregation,
+            Some(Aggregation {
+                keys: vec![col("location")],
+                aggs: vec![
+                    col("new_cases").sum().alias("total"),
+                    col(ALL_ROWS).count().alias("count(*)"),
+                    col("new_cases").mean().alias("__order_1"),
+                ],
+                having: Some(col("new_cases").max().gt(lit(10f64))),
+            })
+        );
+        assert_eq!(
+            sql.selection,
+            vec![col("location"), col("total"), col("count(*)")]
+        );
+        assert_eq!(
+            sql.order_by,
+            vec![
+                ("total".into(), true),
+                ("__order_1".into(), false),
+                ("location".into(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn ungrouped_column_should_fail() {
+        let parse = |sql: &str| {
+            let statement = &Parser::parse_sql(&TyrDialect::default(), sql).unwrap()[0];
+            Sql::try_from(statement).is_err()
+        };
+        assert!(parse(
+            "select location, new_cases from data group by location"
+        ));
+        assert!(parse("select *, count(*) from data"));
+        assert!(parse("select sum(distinct new_cases) from data"));
+        assert!(!parse(
+            "select count(distinct location), max(new_cases) from data"
+        ));
+    }
+
+    #[test]
+    fn parse_computed_projection_work() {
+        let sql = "select a * 2 + 1, b total, cast(c as int), -d, -1, 'x' label, 1 \
+            from t order by total desc, a * 2 + 1, b + c";
+
+        let statement = &Parser::parse_sql(&TyrDialect::default(), sql).unwrap()[0];
+        let sql: Sql = statement.try_into().unwrap();
+        assert_eq!(
+            sql.computed,
+            vec![
+                (col("a") * lit(2f64) + lit(1f64)).alias("a * 2 + 1"),
+                col("b").alias("total"),
+                col("c").cast(DataType::Int32).alias("CAST(c AS INT)"),
+                (lit(0) - col("d")).alias("neg"),
+                lit(-1f64).alias("m"),
+                lit("x").alias("label"),
+                lit(1f64).alias("1"),
+                (col("b") + col("c")).alias("__order_2"),
+            ]
+        );
+        assert_eq!(
+            sql.selection,
+            [
+                "a * 2 + 1",
+                "total",
+                "CAST(c AS INT)",
+                "neg",
+                "m",
+                "label",
+                "1"
+            ]
+            .into_iter()
+            .map(col)
+            .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            sql.order_by,
+            vec![
+                ("total".into(), true),
+                ("a * 2 + 1".into(), false),
+                ("__order_2".into(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicated_names_should_be_numbered() {
+        let sql = "select 1, 1, a + 1 as x, a - 1 as x from t";
+        let statement = &Parser::parse_sql(&TyrDialect::default(), sql).unwrap()[0];
+        let sql: Sql = statement.try_into().unwrap();
+        assert_eq!(
+            sql.selection,
+            vec![col("1"), col("1_1"), col("x"), col("x_1")]
+        );
+    }
+
+    #[test]
+    fn parse_table_function_work() {
+        let sql = "select * from read_json('https://abc.xyz/api?a=1') a \
+            join read_parquet('file:///data/b.parquet') using (id)";
+
+        let statement = &Parser::parse_sql(&TyrDialect::default(), sql).unwrap()[0];
+        let sql: Sql = statement.try_into().unwrap();
+        assert_eq!(
+            sql.source,
+            Table {
+                name: "https://abc.xyz/api?a=1",
+                alias: Some("a"),
+                format: Some(Format::Json),
+            }
+        );
+        assert_eq!(sql.joins[0].table.format, Some(Format::Parquet));
+
+        let parse = |sql: &str| {
+            let statement = &Parser::parse_sql(&TyrDialect::default(), sql).unwrap()[0];
+            Sql::try_from(statement).is_err()
+        };
+        assert!(parse("select * from read_xml('file:///data/a.xml')"));
+        assert!(parse("select * from read_csv(a)"));
+    }
+}
@@ -1,16 +1,25 @@
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use reqwest::header::CONTENT_TYPE;
 use tokio::fs;
 
 // Rust的async trait还没有稳定，可以用async_trait库
 #[async_trait]
 pub trait Fetch {
     type Error;
-    async fn fetch(&self) -> Result<String, Self::Error>;
+    async fn fetch(&self) -> Result<Content, Self::Error>;
+}
+
+// 获取到的数据，source和content_type用来判断数据的格式
+#[derive(Debug)]
+pub struct Content {
+    pub(crate) source: String,
+    pub(crate) data: Vec<u8>,
+    pub(crate) content_type: Option<String>,
 }
 
 /// 从文件源或者http源中获取数据，组成data frame
-pub async fn retrieve_data(source: impl AsRef<str>) -> Result<String> {
+pub async fn retrieve_data(source: impl AsRef<str>) -> Result<Content> {
     let name = source.as_ref();
     match &name[..4] {
         "http" => UrlFetcher(name).fetch().await,
@@ -26,8 +35,18 @@ struct FileFetcher<'a>(pub(crate) &'a str);
 impl<'a> Fetch for UrlFetcher<'a> {
     type Error = anyhow::Error;
 
-    async fn fetch(&self) -> Result<String, Self::Error> {
-        Ok(reqwest::get(self.0).await?.text().await?)
+    async fn fetch(&self) -> Result<Content, Self::Error> {
+        let resp = reqwest::get(self.0).await?;
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        Ok(Content {
+            source: self.0.to_string(),
+            data: resp.bytes().await?.to_vec(),
+            content_type,
+        })
     }
 }
 
@@ -35,7 +54,11 @@ impl<'a> Fetch for UrlFetcher<'a> {
 impl<'a> Fetch for FileFetcher<'a> {
     type Error = anyhow::Error;
 
-    async fn fetch(&self) -> Result<String, Self::Error> {
-        Ok(fs::read_to_string(&self.0[7..]).await?)
+    async fn fetch(&self) -> Result<Content, Self::Error> {
+        Ok(Content {
+            source: self.0.to_string(),
+            data: fs::read(&self.0[7..]).await?,
+            content_type: None,
+        })
     }
 }
\ No newline at end of file
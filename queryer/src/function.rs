@@ -0,0 +1,300 @@
+// 标量函数：把SQL里的函数调用转换成DataFrame的Expr
+// 内置了常用的字符串、数学和条件函数，也可以用register_function注册自己的函数
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use polars::prelude::*;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+/// 标量函数，参数是已经转换好的Expr，返回计算结果的Expr
+pub type ScalarFunction = Arc<dyn Fn(Vec<Expr>) -> Result<Expr> + Send + Sync>;
+
+lazy_static! {
+    static ref FUNCTIONS: RwLock<HashMap<String, ScalarFunction>> = RwLock::new(builtins());
+}
+
+/// 注册函数，名字不区分大小写，和已有的函数同名时替换掉原来的函数
+pub fn register_function<F>(name: &str, f: F)
+where
+    F: Fn(Vec<Expr>) -> Result<Expr> + Send + Sync + 'static,
+{
+    FUNCTIONS
+        .write()
+        .unwrap()
+        .insert(name.to_lowercase(), Arc::new(f));
+}
+
+// 查找并调用函数
+pub(crate) fn call(name: &str, args: Vec<Expr>) -> Result<Expr> {
+    let f = FUNCTIONS
+        .read()
+        .unwrap()
+        .get(&name.to_lowercase())
+        .cloned()
+        .ok_or_else(|| anyhow!("function {} is not supported", name))?;
+    f(args)
+}
+
+// LIKE，pattern里%匹配任意多个字符，_匹配一个字符
+pub(crate) fn like(expr: Expr, pattern: Expr) -> Result<Expr> {
+    let pattern: Vec<char> = match pattern {
+        Expr::Literal(LiteralValue::Utf8(v)) => v.chars().collect(),
+        v => return Err(anyhow!("pattern of LIKE should be a string, got {:?}", v)),
+    };
+    Ok(expr.map(
+        move |s: Series| {
+            let ca = s.cast_with_dtype(&DataType::Utf8)?;
+            let out: BooleanChunked = ca
+                .utf8()?
+                .into_iter()
+                .map(|v| v.map(|v| matches(&v.chars().collect::<Vec<_>>(), &pattern)))
+                .collect();
+            Ok(named(out.into_series(), s.name()))
+        },
+        Some(DataType::Boolean),
+    ))
+}
+
+fn builtins() -> HashMap<String, ScalarFunction> {
+    let mut functions: HashMap<String, ScalarFunction> = HashMap::new();
+    let mut add = |name: &str, f: fn(Vec<Expr>) -> Result<Expr>| {
+        functions.insert(name.into(), Arc::new(f));
+    };
+
+    // 字符串
+    add("upper", |args| {
+        Ok(map_str(unary("upper", args)?, |v| v.to_uppercase()))
+    });
+    add("lower", |args| {
+        Ok(map_str(unary("lower", args)?, |v| v.to_lowercase()))
+    });
+    add("trim", |args| {
+        Ok(map_str(unary("trim", args)?, |v| v.trim().into()))
+    });
+    add("length", |args| {
+        let count = |v: &str| v.chars().count() as u32;
+        Ok(unary("length", args)?.map(
+            move |s: Series| {
+                let ca = s.cast_with_dtype(&DataType::Utf8)?;
+                let out: UInt32Chunked = ca.utf8()?.into_iter().map(|v| v.map(count)).collect();
+                Ok(named(out.into_series(), s.name()))
+            },
+            Some(DataType::UInt32),
+        ))
+    });
+    add("substr", substr);
+    add("concat", concat);
+    add("like", |args| match <[Expr; 2]>::try_from(args) {
+        Ok([expr, pattern]) => like(expr, pattern),
+        Err(_) => Err(anyhow!("like needs 2 arguments")),
+    });
+
+    // 数学
+    add("abs", |args| {
+        let expr = unary("abs", args)?;
+        Ok(when(expr.clone().lt(lit(0)))
+            .then(lit(0) - expr.clone())
+            .otherwise(expr))
+    });
+    add("floor", |args| {
+        Ok(map_f64(unary("floor", args)?, f64::floor))
+    });
+    add("ceil", |args| Ok(map_f64(unary("ceil", args)?, f64::ceil)));
+    add("round", round);
+
+    // 条件
+    add("coalesce", coalesce);
+    add("nullif", |args| match <[Expr; 2]>::try_from(args) {
+        Ok([expr, value]) => Ok(when(expr.clone().eq(value))
+            .then(Expr::Literal(LiteralValue::Null))
+            .otherwise(expr)),
+        Err(_) => Err(anyhow!("nullif needs 2 arguments")),
+    });
+
+    functions
+}
+
+// substr(s, start[, length])，start从1开始，start和length需要是数字
+fn substr(args: Vec<Expr>) -> Result<Expr> {
+    let (expr, start, length) = match args.as_slice() {
+        [expr, start] => (expr, integer(start)?, None),
+        [expr, start, length] => (expr, integer(start)?, Some(integer(length)?)),
+        _ => return Err(anyhow!("substr needs 2 or 3 arguments")),
+    };
+    let skip = (start.max(1) - 1) as usize;
+    let take = length.map(|v| v.max(0) as usize).unwrap_or(usize::MAX);
+    Ok(expr.clone().map(
+        move |s: Series| {
+            let ca = s.cast_with_dtype(&DataType::Utf8)?;
+            let out: Utf8Chunked = ca
+                .utf8()?
+                .into_iter()
+                .map(|v| v.map(|v| v.chars().skip(skip).take(take).collect::<String>()))
+                .collect();
+            Ok(named(out.into_series(), s.name()))
+        },
+        Some(DataType::Utf8),
+    ))
+}
+
+// 把所有参数转换成字符串连接起来，null当作空字符串
+fn concat(args: Vec<Expr>) -> Result<Expr> {
+    let mut args = args.into_iter();
+    let first = args
+        .next()
+        .ok_or_else(|| anyhow!("concat needs at least 1 argument"))?;
+    // 字面量只有一个值，需要和其他列对齐
+    fn value(ca: &Utf8Chunked, i: usize) -> Option<&str> {
+        match ca.len() {
+            1 => ca.get(0),
+            _ => ca.get(i),
+        }
+    }
+    Ok(fold_exprs(
+        first,
+        |a: Series, b: Series| {
+            let (a, b) = (
+                a.cast_with_dtype(&DataType::Utf8)?,
+                b.cast_with_dtype(&DataType::Utf8)?,
+            );
+            let (x, y) = (a.utf8()?, b.utf8()?);
+            let out: Utf8Chunked = (0..x.len().max(y.len()))
+                .map(|i| {
+                    let (l, r) = (value(x, i).unwrap_or(""), value(y, i).unwrap_or(""));
+                    Some(format!("{}{}", l, r))
+                })
+                .collect();
+            Ok(named(out.into_series(), a.name()))
+        },
+        args.collect(),
+    ))
+}
+
+// round(x[, digits])
+fn round(args: Vec<Expr>) -> Result<Expr> {
+    let (expr, digits) = match args.as_slice() {
+        [expr] => (expr, 0),
+        [expr, digits] => (expr, integer(digits)?),
+        _ => return Err(anyhow!("round needs 1 or 2 arguments")),
+    };
+    let scale = 10f64.powi(digits as i32);
+    Ok(expr.clone().map(
+        move |s: Series| {
+            let ca = s.cast_with_dtype(&DataType::Float64)?;
+            let out: Float64Chunked = ca
+                .f64()?
+                .into_iter()
+                .map(|v| v.map(|v| (v * scale).round() / scale))
+                .collect();
+            Ok(named(out.into_series(), s.name()))
+        },
+        Some(DataType::Float64),
+    ))
+}
+
+// 返回第一个不是null的参数
+fn coalesce(mut args: Vec<Expr>) -> Result<Expr> {
+    let last = args
+        .pop()
+        .ok_or_else(|| anyhow!("coalesce needs at least 1 argument"))?;
+    Ok(args.into_iter().rev().fold(last, |acc, expr| {
+        when(expr.clone().is_not_null()).then(expr).otherwise(acc)
+    }))
+}
+
+fn unary(name: &str, args: Vec<Expr>) -> Result<Expr> {
+    match <[Expr; 1]>::try_from(args) {
+        Ok([expr]) => Ok(expr),
+        Err(_) => Err(anyhow!("{} needs 1 argument", name)),
+    }
+}
+
+// 函数参数里的数字，比如substr(name, 1, 3)里的1和3
+fn integer(expr: &Expr) -> Result<i64> {
+    match expr {
+        Expr::Literal(LiteralValue::Float64(v)) if v.fract() == 0.0 => Ok(*v as i64),
+        Expr::Literal(LiteralValue::Int64(v)) => Ok(*v),
+        Expr::Literal(LiteralValue::Int32(v)) => Ok(*v as i64),
+        v => Err(anyhow!("expect an integer, got {:?}", v)),
+    }
+}
+
+fn map_str(expr: Expr, f: fn(&str) -> String) -> Expr {
+    expr.map(
+        move |s: Series| {
+            let ca = s.cast_with_dtype(&DataType::Utf8)?;
+            let out: Utf8Chunked = ca.utf8()?.into_iter().map(|v| v.map(f)).collect();
+            Ok(named(out.into_series(), s.name()))
+        },
+        Some(DataType::Utf8),
+    )
+}
+
+fn map_f64(expr: Expr, f: fn(f64) -> f64) -> Expr {
+    expr.map(
+        move |s: Series| {
+            let ca = s.cast_with_dtype(&DataType::Float64)?;
+            let out: Float64Chunked = ca.f64()?.into_iter().map(|v| v.map(f)).collect();
+            Ok(named(out.into_series(), s.name()))
+        },
+        Some(DataType::Float64),
+    )
+}
+
+fn named(mut s: Series, name: &str) -> Series {
+    s.rename(name);
+    s
+}
+
+fn matches(value: &[char], pattern: &[char]) -> bool {
+    match pattern.split_first() {
+        None => value.is_empty(),
+        // %可以匹配0个或者多个字符
+        Some(('%', rest)) => (0..=value.len()).any(|i| matches(&value[i..], rest)),
+        Some((p, rest)) => match value.split_first() {
+            Some((v, value)) if *p == '_' || p == v => matches(value, rest),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn like_str(value: &str, pattern: &str) -> bool {
+        let (value, pattern): (Vec<_>, Vec<_>) =
+            (value.chars().collect(), pattern.chars().collect());
+        matches(&value, &pattern)
+    }
+
+    #[test]
+    fn like_pattern_should_match() {
+        assert!(like_str("India", "I%"));
+        assert!(like_str("India", "%dia"));
+        assert!(like_str("India", "_nd_a"));
+        assert!(like_str("India", "%"));
+        assert!(like_str("", "%"));
+        assert!(!like_str("India", "_dia"));
+        assert!(!like_str("China", "I%"));
+        assert!(!like_str("India", "Ind"));
+    }
+
+    #[test]
+    fn functions_should_check_arguments() {
+        assert!(call("UPPER", vec![col("a")]).is_ok());
+        assert!(call("upper", vec![col("a"), col("b")]).is_err());
+        assert!(call("substr", vec![col("a"), col("b")]).is_err());
+        assert!(call("like", vec![col("a"), col("b")]).is_err());
+        assert!(call("no_such_function", vec![]).is_err());
+        assert_eq!(call("coalesce", vec![col("a")]).unwrap(), col("a"));
+    }
+
+    #[test]
+    fn functions_could_be_registered() {
+        register_function("Twice", |args| Ok(unary("twice", args)? * lit(2)));
+        assert_eq!(call("twice", vec![col("a")]).unwrap(), col("a") * lit(2));
+    }
+}
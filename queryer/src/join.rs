@@ -0,0 +1,170 @@
+// 把FROM和JOIN里的多个数据源连接成一个LazyFrame
+// 为了让 a.x 这样的列名可以直接使用，连接之前每个数据源的列都重命名成 限定名.列名，
+// 连接之后只在一个数据源里出现的列再加上不带限定名的版本
+use crate::{
+    convert::{Join, JoinKind, Table},
+    detect_content, retrieve_data,
+};
+use anyhow::{anyhow, Result};
+use polars::prelude::*;
+use std::collections::HashMap;
+
+// (限定名, 列名)
+type QualifiedColumn = (String, String);
+
+// 连接之后的数据，columns是SELECT *需要返回的列
+pub(crate) struct Joined {
+    pub(crate) frame: LazyFrame,
+    columns: Vec<Expr>,
+}
+
+impl Joined {
+    // 把SELECT *换成所有数据源的列
+    pub(crate) fn expand(&self, selection: Vec<Expr>) -> Vec<Expr> {
+        selection
+            .into_iter()
+            .flat_map(|expr| match expr {
+                Expr::Wildcard => self.columns.clone(),
+                expr => vec![expr],
+            })
+            .collect()
+    }
+}
+
+// 按顺序获取每个数据源，逐个和前面的结果连接
+pub(crate) async fn load(source: Table<'_>, joins: Vec<Join<'_>>) -> Result<Joined> {
+    let (mut frame, mut columns) = fetch(&source, &[]).await?;
+
+    for (i, join) in joins.into_iter().enumerate() {
+        let (mut right, right_columns) = fetch(&join.table, &columns).await?;
+
+        // 把连接条件里的列复制成同名的临时列再连接，这样两边原来的列都能保留下来
+        let mut keys = Vec::with_capacity(join.on.len());
+        for (j, (a, b)) in join.on.iter().enumerate() {
+            let (l, r) = match (resolve(a, &columns)?, resolve(b, &right_columns)?) {
+                (Some(l), Some(r)) => (l, r),
+                _ => match (resolve(b, &columns)?, resolve(a, &right_columns)?) {
+                    (Some(l), Some(r)) => (l, r),
+                    _ => {
+                        return Err(anyhow!(
+                            "join condition {} = {} should compare a column of {} with a previous source",
+                            a,
+                            b,
+                            join.table.qualifier()
+                        ))
+                    }
+                },
+            };
+            let key = format!("__join_{}_{}", i, j);
+            frame = frame.with_column(col(&l).alias(&key));
+            right = right.with_column(col(&r).alias(&key));
+            keys.push(col(&key));
+        }
+
+        frame = match join.kind {
+            JoinKind::Inner => frame.join(right, keys.clone(), keys, JoinType::Inner),
+            JoinKind::Left => frame.join(right, keys.clone(), keys, JoinType::Left),
+            JoinKind::Full => frame.join(right, keys.clone(), keys, JoinType::Outer),
+            // polars没有right join，交换两边之后做left join
+            JoinKind::Right => right.join(frame, keys.clone(), keys, JoinType::Left),
+        };
+        columns.extend(right_columns);
+    }
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for (_, name) in &columns {
+        *counts.entry(name).or_default() += 1;
+    }
+    let unique = |name: &str| counts[name] == 1;
+
+    let aliases: Vec<Expr> = columns
+        .iter()
+        .filter(|(_, name)| unique(name))
+        .map(|c| col(&qualified(c)).alias(&c.1))
+        .collect();
+    if !aliases.is_empty() {
+        frame = frame.with_columns(aliases);
+    }
+
+    // SELECT *时，只在一个数据源里出现的列不带限定名
+    let columns = columns
+        .iter()
+        .map(|c| match unique(&c.1) {
+            true => col(&c.1),
+            false => col(&qualified(c)),
+        })
+        .collect();
+
+    Ok(Joined { frame, columns })
+}
+
+// 获取数据源，把列名加上限定名
+async fn fetch(
+    table: &Table<'_>,
+    existing: &[QualifiedColumn],
+) -> Result<(LazyFrame, Vec<QualifiedColumn>)> {
+    let qualifier = table.qualifier();
+    if existing.iter().any(|(q, _)| q == qualifier) {
+        return Err(anyhow!(
+            "source {} is used more than once, please give it an alias",
+            qualifier
+        ));
+    }
+
+    let content = retrieve_data(table.name).await?;
+    let mut df = detect_content(content, table.format).load()?.0;
+    let columns: Vec<QualifiedColumn> = df
+        .get_column_names()
+        .iter()
+        .map(|name| (qualifier.to_string(), name.to_string()))
+        .collect();
+    let names: Vec<String> = columns.iter().map(qualified).collect();
+    df.set_column_names(&names)?;
+
+    Ok((df.lazy(), columns))
+}
+
+// 在一个数据源的列里查找name，name可以带限定名，也可以只有列名
+fn resolve(name: &str, columns: &[QualifiedColumn]) -> Result<Option<String>> {
+    if let Some(c) = columns.iter().find(|c| qualified(c) == name) {
+        return Ok(Some(qualified(c)));
+    }
+    let mut found = columns.iter().filter(|(_, n)| n == name);
+    match (found.next(), found.next()) {
+        (Some(c), None) => Ok(Some(qualified(c))),
+        (Some(_), Some(_)) => Err(anyhow!("column {} is ambiguous", name)),
+        _ => Ok(None),
+    }
+}
+
+fn qualified((qualifier, name): &QualifiedColumn) -> String {
+    format!("{}.{}", qualifier, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns() -> Vec<QualifiedColumn> {
+        vec![
+            ("a".into(), "location".into()),
+            ("a".into(), "total_cases".into()),
+            ("b".into(), "location".into()),
+        ]
+    }
+
+    #[test]
+    fn resolve_should_find_qualified_and_unique_names() {
+        let columns = columns();
+        assert_eq!(
+            resolve("b.location", &columns).unwrap(),
+            Some("b.location".into())
+        );
+        assert_eq!(
+            resolve("total_cases", &columns).unwrap(),
+            Some("a.total_cases".into())
+        );
+        assert_eq!(resolve("population", &columns).unwrap(), None);
+        assert!(resolve("location", &columns).is_err());
+    }
+}
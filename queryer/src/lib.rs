@@ -8,12 +8,15 @@ mod convert;
 mod dialect;
 mod loader;
 mod fetcher;
-use convert::Sql;
+mod join;
+mod function;
+use convert::{Aggregation, Sql, ALL_ROWS, HAVING};
 use loader::detect_content;
 use fetcher::retrieve_data;
 
 pub use dialect::example_sql;
 pub use dialect::TyrDialect;
+pub use function::{register_function, ScalarFunction};
 
 #[derive(Debug)]
 pub struct DataSet(DataFrame);
@@ -58,24 +61,64 @@ pub async fn query<T: AsRef<str>>(sql: T) -> Result<DataSet> {
     // 关注点分离，是我们控制软件复杂度的法宝
     let Sql {
         source,
+        joins,
+        aggregation,
+        computed,
         condition,
-        selection,
+        mut selection,
         offset,
         limit,
         order_by,
     } = sql.try_into()?;
 
-    info!("retrieving data from source: {}", source);
+    let frame = if joins.is_empty() && source.alias.is_none() {
+        info!("retrieving data from source: {}", source.name);
 
-    // 从source读入一个DataSet
-    // detect_content， 怎么detect不重要，重要的是能根据内容返回DataSet
-    let ds = detect_content(retrieve_data(source).await?).load()?;
+        // 从source读入一个DataSet
+        // detect_content， 怎么detect不重要，重要的是能根据内容返回DataSet
+        let ds = detect_content(retrieve_data(source.name).await?, source.format).load()?;
+        ds.0.lazy()
+    } else {
+        info!("joining {} data sources", joins.len() + 1);
+
+        // 有别名或者JOIN时，列名需要加上限定名，SELECT *也要展开成所有数据源的列
+        let joined = join::load(source, joins).await?;
+        selection = joined.expand(selection);
+        joined.frame
+    };
 
     let mut filtered = match condition {
-        Some(expr) => ds.0.lazy().filter(expr),
-        None => ds.0.lazy(),
+        Some(expr) => frame.filter(expr),
+        None => frame,
     };
 
+    if let Some(Aggregation {
+        mut keys,
+        mut aggs,
+        having,
+    }) = aggregation
+    {
+        // 没有GROUP BY时所有的行作为一组
+        filtered = filtered.with_column(lit(1).alias(ALL_ROWS));
+        if keys.is_empty() {
+            keys.push(col(ALL_ROWS));
+        }
+        let has_having = having.is_some();
+        if let Some(expr) = having {
+            aggs.push(expr.alias(HAVING));
+        }
+
+        filtered = filtered.groupby(keys).agg(aggs);
+        if has_having {
+            filtered = filtered.filter(col(HAVING));
+        }
+    }
+
+    // SELECT中计算出来的列先加到数据里，ORDER BY才能使用它们
+    if !computed.is_empty() {
+        filtered = filtered.with_columns(computed);
+    }
+
     filtered = order_by
         .into_iter()
         .fold(filtered, |acc, (col, desc)| acc.sort(&col, desc));
@@ -89,9 +132,281 @@ pub async fn query<T: AsRef<str>>(sql: T) -> Result<DataSet> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    fn fixture(name: &str) -> String {
+        format!("file://{}/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn i64_values(df: &DataFrame, name: &str) -> Vec<Option<i64>> {
+        df.column(name)
+            .unwrap()
+            .i64()
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn join_should_work() {
+        let (cases, population) = (fixture("cases.csv"), fixture("population.csv"));
+
+        let sql = format!(
+            "SELECT c.location, total_cases, population FROM {} c \
+            JOIN {} p ON c.location = p.location ORDER BY total_cases",
+            cases, population
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(
+            df.get_column_names(),
+            ["c.location", "total_cases", "population"]
+        );
+        assert_eq!(i64_values(&df, "population"), [Some(1400), Some(1380)]);
+
+        let sql = format!(
+            "SELECT population FROM {} c LEFT JOIN {} p USING (location) ORDER BY total_cases",
+            cases, population
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(
+            i64_values(&df, "population"),
+            [None, Some(1400), Some(1380)]
+        );
+
+        let sql = format!(
+            "SELECT p.location, total_cases FROM {} c RIGHT JOIN {} p \
+            ON c.location = p.location ORDER BY population",
+            cases, population
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(i64_values(&df, "total_cases"), [None, Some(300), Some(100)]);
+
+        // 两边都有的列需要限定名，其他列可以直接使用
+        let sql = format!(
+            "SELECT * FROM {} c FULL JOIN {} p ON c.location = p.location",
+            cases, population
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(
+            df.get_column_names(),
+            ["c.location", "total_cases", "p.location", "population"]
+        );
+        assert_eq!(df.height(), 4);
+    }
+
+    #[tokio::test]
+    async fn alias_should_work_in_single_source() {
+        let sql = format!(
+            "SELECT c.location FROM {} c WHERE c.total_cases > 60",
+            fixture("cases.csv")
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(df.height(), 2);
+    }
+
+    fn f64_values(df: &DataFrame, name: &str) -> Vec<Option<f64>> {
+        let s = df.column(name).unwrap().cast_with_dtype(&DataType::Float64);
+        s.unwrap().f64().unwrap().into_iter().collect()
+    }
+
+    fn str_values<'a>(df: &'a DataFrame, name: &str) -> Vec<Option<&'a str>> {
+        df.column(name)
+            .unwrap()
+            .utf8()
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn group_by_should_work() {
+        let sql = format!(
+            "SELECT location, SUM(new_cases) AS total, COUNT(*), COUNT(new_cases) days, \
+            AVG(new_cases), MIN(new_cases), MAX(new_cases), MEDIAN(new_cases) \
+            FROM {} GROUP BY location ORDER BY location",
+            fixture("daily.csv")
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(
+            str_values(&df, "location"),
+            [Some("China"), Some("India"), Some("Japan")]
+        );
+        let values = |name| f64_values(&df, name);
+        assert_eq!(values("total"), [Some(40.), Some(120.), Some(5.)]);
+        assert_eq!(values("COUNT(*)"), [Some(2.), Some(3.), Some(1.)]);
+        assert_eq!(values("days"), [Some(2.), Some(2.), Some(1.)]);
+        assert_eq!(values("AVG(new_cases)"), [Some(20.), Some(60.), Some(5.)]);
+        assert_eq!(values("MIN(new_cases)"), [Some(10.), Some(50.), Some(5.)]);
+        assert_eq!(values("MAX(new_cases)"), [Some(30.), Some(70.), Some(5.)]);
+        assert_eq!(
+            values("MEDIAN(new_cases)"),
+            [Some(20.), Some(60.), Some(5.)]
+        );
+    }
+
+    #[tokio::test]
+    async fn having_and_order_by_aggregate_should_work() {
+        let sql = format!(
+            "SELECT location FROM {} GROUP BY location \
+            HAVING SUM(new_cases) > 10 ORDER BY MAX(new_cases) DESC",
+            fixture("daily.csv")
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(df.get_column_names(), ["location"]);
+        assert_eq!(str_values(&df, "location"), [Some("India"), Some("China")]);
+
+        // 没有GROUP BY时整个数据作为一组
+        let sql = format!(
+            "SELECT COUNT(*) AS days, COUNT(DISTINCT location) AS locations FROM {}",
+            fixture("daily.csv")
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(f64_values(&df, "days"), [Some(6.)]);
+        assert_eq!(f64_values(&df, "locations"), [Some(3.)]);
+    }
+
+    #[tokio::test]
+    async fn string_functions_should_work() {
+        let sql = format!(
+            "SELECT upper(location) AS loc, lower(location), length(location) AS len, \
+            substr(location, 2, 3) AS sub, concat(location, '-', date) AS key, \
+            trim(concat('  ', location)) AS trimmed \
+            FROM {} WHERE location LIKE 'I%' AND new_cases IS NOT NULL",
+            fixture("daily.csv")
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(
+            df.get_column_names(),
+            ["loc", "lower(location)", "len", "sub", "key", "trimmed"]
+        );
+        assert_eq!(str_values(&df, "loc"), [Some("INDIA"), Some("INDIA")]);
+        assert_eq!(str_values(&df, "lower(location)")[0], Some("india"));
+        assert_eq!(f64_values(&df, "len"), [Some(5.), Some(5.)]);
+        assert_eq!(str_values(&df, "sub")[0], Some("ndi"));
+        assert_eq!(
+            str_values(&df, "key"),
+            [Some("India-2021-01-01"), Some("India-2021-01-02")]
+        );
+        assert_eq!(str_values(&df, "trimmed")[0], Some("India"));
+
+        let sql = format!(
+            "SELECT location FROM {} WHERE like(location, '_hina') OR location NOT LIKE '%a'",
+            fixture("daily.csv")
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(df.height(), 3);
+    }
+
+    #[tokio::test]
+    async fn math_and_conditional_functions_should_work() {
+        let sql = format!(
+            "SELECT round(new_cases / 3, 1) AS r, floor(new_cases / 3) AS f, \
+            ceil(new_cases / 3) AS c, abs(0 - new_cases) AS a, coalesce(new_cases, 0) AS n, \
+            nullif(new_cases, 70) AS z, \
+            CASE WHEN new_cases > 60 THEN 'high' WHEN new_cases > 8 THEN 'mid' ELSE 'low' END \
+            AS level FROM {} WHERE location = 'India'",
+            fixture("daily.csv")
+        );
+        let df = query(sql).await.unwrap();
+        let values = |name| f64_values(&df, name);
+        assert_eq!(values("r"), [Some(16.7), Some(23.3), None]);
+        assert_eq!(values("f"), [Some(16.), Some(23.), None]);
+        assert_eq!(values("c"), [Some(17.), Some(24.), None]);
+        assert_eq!(values("a"), [Some(50.), Some(70.), None]);
+        assert_eq!(values("n"), [Some(50.), Some(70.), Some(0.)]);
+        assert_eq!(values("z"), [Some(50.), None, None]);
+        assert_eq!(
+            str_values(&df, "level"),
+            [Some("mid"), Some("high"), Some("low")]
+        );
+    }
+
+    #[tokio::test]
+    async fn registered_function_should_work() {
+        register_function("plus_one", |args| match args.as_slice() {
+            [expr] => Ok(expr.clone() + lit(1)),
+            _ => Err(anyhow!("plus_one needs 1 argument")),
+        });
+        let sql = format!(
+            "SELECT plus_one(new_cases) AS n FROM {} WHERE location = 'China' AND plus_one(new_cases) > 20",
+            fixture("daily.csv")
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(f64_values(&df, "n"), [Some(31.)]);
+    }
+
+    #[tokio::test]
+    async fn computed_projection_should_work() {
+        let sql = format!(
+            "SELECT location, new_cases * 100 / 50 AS rate, new_cases + 1, 'daily' AS kind, \
+            CAST(new_cases AS DOUBLE) FROM {} WHERE location = 'China' ORDER BY rate DESC",
+            fixture("daily.csv")
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(
+            df.get_column_names(),
+            [
+                "location",
+                "rate",
+                "new_cases + 1",
+                "kind",
+                "CAST(new_cases AS DOUBLE)"
+            ]
+        );
+        assert_eq!(f64_values(&df, "rate"), [Some(60.), Some(20.)]);
+        assert_eq!(f64_values(&df, "new_cases + 1"), [Some(31.), Some(11.)]);
+        assert_eq!(str_values(&df, "kind"), [Some("daily"), Some("daily")]);
+        assert_eq!(
+            f64_values(&df, "CAST(new_cases AS DOUBLE)"),
+            [Some(30.), Some(10.)]
+        );
+    }
+
+    #[tokio::test]
+    async fn json_sources_should_work() {
+        let sql = format!(
+            "SELECT location, total_cases FROM {} WHERE total_cases > 60 ORDER BY total_cases",
+            fixture("cases.json")
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(str_values(&df, "location"), [Some("China"), Some("India")]);
+
+        // 不同格式的数据源可以连接
+        let sql = format!(
+            "SELECT c.location, population FROM {} c \
+            JOIN {} p ON c.location = p.location ORDER BY population",
+            fixture("cases.json"),
+            fixture("population.csv")
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(
+            str_values(&df, "c.location"),
+            [Some("India"), Some("China")]
+        );
+
+        // 在SQL里指定格式
+        let sql = format!(
+            "SELECT location, total_cases FROM read_ndjson('{}') \
+            WHERE location = 'Japan'",
+            fixture("cases.ndjson")
+        );
+        let df = query(sql).await.unwrap();
+        assert_eq!(i64_values(&df, "total_cases"), [Some(50)]);
+    }
+
+    #[tokio::test]
+    async fn ambiguous_column_should_fail() {
+        let sql = format!(
+            "SELECT location FROM {} c JOIN {} p USING (location)",
+            fixture("cases.csv"),
+            fixture("population.csv")
+        );
+        assert!(query(sql).await.is_err());
+    }
 }
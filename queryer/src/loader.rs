@@ -1,8 +1,11 @@
-use crate::DataSet;
+use crate::{fetcher::Content, DataSet};
 use anyhow::Result;
 use polars::prelude::*;
 use std::io::Cursor;
 
+// parquet文件以PAR1开头
+const PARQUET_MAGIC: &[u8] = b"PAR1";
+
 pub trait Load {
     type Error;
     fn load(self) -> Result<DataSet, Self::Error>;
@@ -12,22 +15,116 @@ pub trait Load {
 #[non_exhaustive]
 pub enum Loader {
     Csv(CsvLoader),
+    Json(JsonLoader),
+    Ndjson(NdjsonLoader),
+    Parquet(ParquetLoader),
+}
+
+// 数据格式，可以在SQL里用read_csv('...')这样的写法指定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Json,
+    Ndjson,
+    Parquet,
 }
 
 #[derive(Default, Debug)]
-pub struct CsvLoader(pub(crate) String);
+pub struct CsvLoader(pub(crate) Vec<u8>);
+
+// JSON数组，每个元素是一行
+#[derive(Default, Debug)]
+pub struct JsonLoader(pub(crate) Vec<u8>);
+
+// 每行一个JSON对象
+#[derive(Default, Debug)]
+pub struct NdjsonLoader(pub(crate) Vec<u8>);
+
+#[derive(Default, Debug)]
+pub struct ParquetLoader(pub(crate) Vec<u8>);
 
 impl Loader {
     pub fn load(self) -> Result<DataSet> {
         match self {
             Loader::Csv(csv) => csv.load(),
+            Loader::Json(json) => json.load(),
+            Loader::Ndjson(ndjson) => ndjson.load(),
+            Loader::Parquet(parquet) => parquet.load(),
+        }
+    }
+}
+
+impl Format {
+    // SQL里指定格式的函数名
+    pub(crate) fn from_function(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "read_csv" => Some(Format::Csv),
+            "read_json" => Some(Format::Json),
+            "read_ndjson" => Some(Format::Ndjson),
+            "read_parquet" => Some(Format::Parquet),
+            _ => None,
+        }
+    }
+
+    // 只认明确表示格式的Content-Type，text/plain、application/octet-stream这些不能说明什么
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next()?.trim().to_lowercase();
+        match mime.as_str() {
+            "text/csv" | "application/csv" => Some(Format::Csv),
+            "application/json" | "text/json" => Some(Format::Json),
+            "application/x-ndjson"
+            | "application/ndjson"
+            | "application/jsonl"
+            | "application/x-jsonlines" => Some(Format::Ndjson),
+            "application/vnd.apache.parquet" | "application/x-parquet" => Some(Format::Parquet),
+            _ => None,
+        }
+    }
+
+    // url里的?和#之后不是路径的一部分
+    fn from_extension(source: &str) -> Option<Self> {
+        let path = source.split(|c| c == '?' || c == '#').next()?;
+        let (_, ext) = path.rsplit_once('.')?;
+        match ext.to_lowercase().as_str() {
+            "csv" => Some(Format::Csv),
+            "json" => Some(Format::Json),
+            "ndjson" | "jsonl" => Some(Format::Ndjson),
+            "parquet" => Some(Format::Parquet),
+            _ => None,
+        }
+    }
+
+    fn sniff(data: &[u8]) -> Self {
+        if data.starts_with(PARQUET_MAGIC) {
+            return Format::Parquet;
+        }
+        let text = String::from_utf8_lossy(&data[..data.len().min(1024)]);
+        match text.trim_start().chars().next() {
+            Some('[') => Format::Json,
+            Some('{') => Format::Ndjson,
+            _ => Format::Csv,
         }
     }
 }
 
-pub fn detect_content(data: String) -> Loader {
-    // TODO: 内容检测
-    Loader::Csv(CsvLoader(data))
+// 按SQL里指定的格式、Content-Type、扩展名、数据内容的顺序决定用哪种Loader
+pub fn detect_content(content: Content, format: Option<Format>) -> Loader {
+    let format = format
+        .or_else(|| {
+            content
+                .content_type
+                .as_deref()
+                .and_then(Format::from_content_type)
+        })
+        .or_else(|| Format::from_extension(&content.source))
+        .unwrap_or_else(|| Format::sniff(&content.data));
+
+    match format {
+        Format::Csv => Loader::Csv(CsvLoader(content.data)),
+        Format::Json => Loader::Json(JsonLoader(content.data)),
+        Format::Ndjson => Loader::Ndjson(NdjsonLoader(content.data)),
+        Format::Parquet => Loader::Parquet(ParquetLoader(content.data)),
+    }
 }
 
 impl Load for CsvLoader {
@@ -39,4 +136,110 @@ impl Load for CsvLoader {
             .finish()?;
         Ok(DataSet(df))
     }
-}
\ No newline at end of file
+}
+
+impl Load for JsonLoader {
+    type Error = anyhow::Error;
+
+    // JsonReader只能读每行一个对象的格式，先把数组转换成这种格式
+    // 以.json结尾但其实是每行一个对象的数据也可以直接读取
+    fn load(self) -> Result<DataSet, Self::Error> {
+        if Format::sniff(&self.0) != Format::Json {
+            return NdjsonLoader(self.0).load();
+        }
+
+        let rows: Vec<serde_json::Value> = serde_json::from_slice(&self.0)?;
+        let mut data = Vec::with_capacity(self.0.len());
+        for row in rows {
+            serde_json::to_writer(&mut data, &row)?;
+            data.push(b'\n');
+        }
+        NdjsonLoader(data).load()
+    }
+}
+
+impl Load for NdjsonLoader {
+    type Error = anyhow::Error;
+
+    fn load(self) -> Result<DataSet, Self::Error> {
+        let df = JsonReader::new(Cursor::new(self.0))
+            .infer_schema(Some(16))
+            .finish()?;
+        Ok(DataSet(df))
+    }
+}
+
+impl Load for ParquetLoader {
+    type Error = anyhow::Error;
+
+    fn load(self) -> Result<DataSet, Self::Error> {
+        let df = ParquetReader::new(Cursor::new(self.0)).finish()?;
+        Ok(DataSet(df))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content(source: &str, content_type: Option<&str>, data: &[u8]) -> Content {
+        Content {
+            source: source.into(),
+            data: data.to_vec(),
+            content_type: content_type.map(|v| v.into()),
+        }
+    }
+
+    fn detect(source: &str, content_type: Option<&str>, data: &[u8]) -> Format {
+        match detect_content(content(source, content_type, data), None) {
+            Loader::Csv(_) => Format::Csv,
+            Loader::Json(_) => Format::Json,
+            Loader::Ndjson(_) => Format::Ndjson,
+            Loader::Parquet(_) => Format::Parquet,
+        }
+    }
+
+    #[test]
+    fn format_should_be_detected() {
+        // Content-Type优先于扩展名
+        let url = "https://abc.xyz/data.csv?token=1";
+        assert_eq!(detect(url, None, b"[]"), Format::Csv);
+        let json = Some("application/json; charset=utf-8");
+        assert_eq!(detect(url, json, b"a,b"), Format::Json);
+        assert_eq!(detect(url, Some("text/plain"), b"[]"), Format::Csv);
+
+        let file = "file:///data/rows.jsonl";
+        assert_eq!(detect(file, None, b"a,b"), Format::Ndjson);
+        assert_eq!(detect("file:///data/t.Parquet", None, b""), Format::Parquet);
+
+        // 没有扩展名时检查内容
+        let url = "https://abc.xyz/api/data";
+        assert_eq!(detect(url, None, b"PAR1\x15\x04"), Format::Parquet);
+        assert_eq!(detect(url, None, b"  \n[{\"a\": 1}]"), Format::Json);
+        assert_eq!(detect(url, None, b"{\"a\": 1}\n{\"a\": 2}"), Format::Ndjson);
+        assert_eq!(detect(url, None, b"a,b\n1,2"), Format::Csv);
+    }
+
+    #[test]
+    fn explicit_format_should_win() {
+        let data = content("https://abc.xyz/data.csv", Some("text/csv"), b"{}");
+        assert!(matches!(
+            detect_content(data, Some(Format::Ndjson)),
+            Loader::Ndjson(_)
+        ));
+        assert_eq!(Format::from_function("READ_PARQUET"), Some(Format::Parquet));
+        assert_eq!(Format::from_function("read_xml"), None);
+    }
+
+    #[test]
+    fn json_array_should_be_loaded() {
+        let data = br#"[{"location": "China", "total_cases": 100}, {"location": "India", "total_cases": 300}]"#;
+        let ds = JsonLoader(data.to_vec()).load().unwrap();
+        assert_eq!(ds.shape(), (2, 2));
+
+        let data =
+            b"{\"location\": \"China\"}\n{\"location\": \"India\"}\n{\"location\": \"Japan\"}\n";
+        let ds = JsonLoader(data.to_vec()).load().unwrap();
+        assert_eq!(ds.shape(), (3, 1));
+    }
+}
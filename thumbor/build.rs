@@ -1,6 +1,6 @@
 fn main() {
-    prost_build::Config::new()
+    tonic_build::configure()
         .out_dir("src/pb")
-        .compile_protos(&["abi.proto"], &["."])
+        .compile(&["abi.proto"], &["."])
         .unwrap();
-}
\ No newline at end of file
+}
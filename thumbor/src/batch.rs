@@ -0,0 +1,293 @@
+// 不启动服务，直接用ImageSpec处理本地文件
+// 处理过程和server完全一样：优化spec -> 解码（SVG按spec栅格化） -> 加载overlay -> apply -> 按spec里的Output编码，
+// 所以同样的spec和源图片得到的结果和服务返回的逐字节相同
+use crate::{
+    engine::{optimize, probe_dimensions, Engine, Photon},
+    pb::*,
+};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use image::ImageOutputFormat;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+// 一个待处理的文件
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+// 加载spec里overlay引用的图片，http(s)的url通过网络获取，其他的当作本地路径
+// 批量处理时只加载一次，所有文件共用
+pub async fn load_overlays(spec: &ImageSpec) -> Result<HashMap<String, Bytes>> {
+    let mut sources = HashMap::new();
+    for overlay in spec.overlays() {
+        if sources.contains_key(&overlay.url) {
+            continue;
+        }
+        let data = if overlay.url.starts_with("http://") || overlay.url.starts_with("https://") {
+            reqwest::get(&overlay.url).await?.error_for_status()?.bytes().await?
+        } else {
+            Bytes::from(fs::read(&overlay.url)?)
+        };
+        sources.insert(overlay.url.clone(), data);
+    }
+    Ok(sources)
+}
+
+// 和server里的处理一样，返回编码后的图片和使用的格式
+pub fn render(
+    data: Bytes,
+    spec: &ImageSpec,
+    sources: &HashMap<String, Bytes>,
+) -> Result<(Vec<u8>, ImageOutputFormat)> {
+    let (width, height) = probe_dimensions(&data)?;
+    let specs = optimize(&spec.specs, width, height)?;
+    let (mut engine, specs) = Photon::load(&data, &specs)?;
+    for (url, data) in sources {
+        engine.add_source(url, data.clone())?;
+    }
+    engine.apply(&specs);
+    let format = engine.format();
+    Ok((engine.generate(format.clone()), format))
+}
+
+// spec输出格式对应的文件扩展名
+pub fn extension(spec: &ImageSpec) -> &'static str {
+    match output_format(spec) {
+        Some(output::Format::Png) => "png",
+        _ => "jpg",
+    }
+}
+
+// 单个文件时根据输出文件的扩展名确定格式：
+// spec里没有Output时按扩展名补上，有的话两者必须一致
+pub fn spec_for_path(mut spec: ImageSpec, path: &Path) -> Result<ImageSpec> {
+    let ext = path
+        .extension()
+        .and_then(|v| v.to_str())
+        .map(|v| v.to_ascii_lowercase());
+    let format = match ext.as_deref() {
+        Some("jpg") | Some("jpeg") => output::Format::Jpeg,
+        Some("png") => output::Format::Png,
+        _ => {
+            return Err(anyhow!(
+                "output {} should be .jpg, .jpeg or .png",
+                path.display()
+            ))
+        }
+    };
+
+    let has_output = spec
+        .specs
+        .iter()
+        .any(|s| matches!(s.data, Some(spec::Data::Output(_))));
+    if !has_output {
+        if format == output::Format::Png {
+            spec.specs.push(Spec::new_png(output::PngCompression::Default, 0));
+        }
+        return Ok(spec);
+    }
+
+    match output_format(&spec) {
+        Some(v) if v == format => Ok(spec),
+        _ => Err(anyhow!(
+            "output {} doesn't match the output format in spec",
+            path.display()
+        )),
+    }
+}
+
+// input是一个文件时输出到output（output是已经存在的目录时输出到目录里）；
+// 是目录或者glob时，output是目录，每个输入文件输出为 output/<文件名>.<spec对应的扩展名>
+pub fn plan(input: &str, output: &Path, spec: &ImageSpec) -> Result<Vec<Job>> {
+    let ext = extension(spec);
+    let target = |path: &Path| -> Result<PathBuf> {
+        let stem = path
+            .file_stem()
+            .ok_or_else(|| anyhow!("input {} has no file name", path.display()))?;
+        Ok(output.join(stem).with_extension(ext))
+    };
+
+    let is_glob = input.contains(|c| matches!(c, '*' | '?' | '['));
+    if !is_glob && Path::new(input).is_file() {
+        let output = match output.is_dir() {
+            true => target(Path::new(input))?,
+            false => output.into(),
+        };
+        return Ok(vec![Job {
+            input: input.into(),
+            output,
+        }]);
+    }
+
+    let pattern = match is_glob {
+        true => input.to_string(),
+        false if Path::new(input).is_dir() => format!("{}/*", input.trim_end_matches('/')),
+        false => return Err(anyhow!("input {} doesn't exist", input)),
+    };
+
+    let mut jobs: Vec<Job> = Vec::new();
+    for path in glob::glob(&pattern)? {
+        let path = path?;
+        if !path.is_file() {
+            continue;
+        }
+        let target = target(&path)?;
+        // a.jpg和a.png会输出到同一个文件
+        if let Some(job) = jobs.iter().find(|job| job.output == target) {
+            return Err(anyhow!(
+                "{} and {} would both be written to {}",
+                job.input.display(),
+                path.display(),
+                target.display()
+            ));
+        }
+        jobs.push(Job {
+            input: path,
+            output: target,
+        });
+    }
+    if jobs.is_empty() {
+        return Err(anyhow!("no input files match {}", input));
+    }
+    Ok(jobs)
+}
+
+// 用workers个线程并行处理，返回的结果和jobs一一对应，成功时是输出的字节数
+pub fn run(
+    jobs: &[Job],
+    spec: &ImageSpec,
+    sources: &HashMap<String, Bytes>,
+    workers: usize,
+) -> Vec<Result<usize>> {
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<Result<usize>>>> =
+        jobs.iter().map(|_| Mutex::new(None)).collect();
+
+    thread::scope(|s| {
+        for _ in 0..workers.clamp(1, jobs.len().max(1)) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= jobs.len() {
+                    break;
+                }
+                let result = process_job(&jobs[i], spec, sources);
+                *results[i].lock().unwrap() = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_iter()
+        .map(|v| v.into_inner().unwrap().unwrap())
+        .collect()
+}
+
+fn process_job(job: &Job, spec: &ImageSpec, sources: &HashMap<String, Bytes>) -> Result<usize> {
+    let data = fs::read(&job.input)?;
+    let (image, _) = render(data.into(), spec, sources)?;
+    if let Some(parent) = job.output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&job.output, &image)?;
+    Ok(image.len())
+}
+
+// 最后一个Output决定输出格式
+fn output_format(spec: &ImageSpec) -> Option<output::Format> {
+    spec.specs.iter().rev().find_map(|s| match s.data {
+        Some(spec::Data::Output(ref v)) => output::Format::from_i32(v.format),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRADIENT: &[u8] = include_bytes!("../fixtures/samples/gradient.png");
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "thumbor-batch-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn render_should_match_engine() {
+        let spec = ImageSpec::parse("resize(64, 48, catmull_rom) | fliph | png(best)").unwrap();
+        let (data, format) =
+            render(Bytes::from_static(GRADIENT), &spec, &HashMap::new()).unwrap();
+        assert_eq!(format, ImageOutputFormat::Png);
+
+        let mut engine = Photon::try_from(Bytes::from_static(GRADIENT)).unwrap();
+        engine.apply(&spec.specs);
+        assert_eq!(data, engine.generate(ImageOutputFormat::Png));
+    }
+
+    #[test]
+    fn output_extension_should_match_spec() {
+        let spec = ImageSpec::parse("fliph").unwrap();
+        let png = spec_for_path(spec.clone(), Path::new("out.PNG")).unwrap();
+        assert_eq!(extension(&png), "png");
+        assert_eq!(spec_for_path(spec.clone(), Path::new("out.jpg")).unwrap(), spec);
+        assert!(spec_for_path(spec, Path::new("out.webp")).is_err());
+
+        let spec = ImageSpec::parse("fliph | png").unwrap();
+        assert!(spec_for_path(spec.clone(), Path::new("out.png")).is_ok());
+        assert!(spec_for_path(spec, Path::new("out.jpeg")).is_err());
+    }
+
+    #[test]
+    fn directory_should_be_processed_in_parallel() {
+        let input = temp_dir("in");
+        let output = temp_dir("out");
+        for name in ["a.png", "b.png", "c.png", "notes.txt"] {
+            let data: &[u8] = match name.ends_with(".png") {
+                true => GRADIENT,
+                false => b"not an image",
+            };
+            fs::write(input.join(name), data).unwrap();
+        }
+
+        let spec = ImageSpec::parse("resize(32, 24, nearest) | png").unwrap();
+        let pattern = format!("{}/*.png", input.display());
+        let jobs = plan(&pattern, &output, &spec).unwrap();
+        assert_eq!(jobs.len(), 3);
+        assert_eq!(jobs[0].output, output.join("a.png"));
+
+        let results = run(&jobs, &spec, &HashMap::new(), 2);
+        assert!(results.iter().all(|r| r.is_ok()));
+        let data = fs::read(output.join("b.png")).unwrap();
+        let (expected, _) =
+            render(Bytes::from_static(GRADIENT), &spec, &HashMap::new()).unwrap();
+        assert_eq!(data, expected);
+
+        // 目录模式会包含无法解码的文件，单独报错，不影响其他文件
+        let jobs = plan(input.to_str().unwrap(), &output, &spec).unwrap();
+        let results = run(&jobs, &spec, &HashMap::new(), 4);
+        assert_eq!(results.iter().filter(|r| r.is_err()).count(), 1);
+
+        assert!(plan(&format!("{}/*.gif", input.display()), &output, &spec).is_err());
+
+        // 单个文件，输出是已经存在的目录
+        let single = input.join("a.png");
+        let jobs = plan(single.to_str().unwrap(), &output, &spec).unwrap();
+        assert_eq!(jobs[0].output, output.join("a.png"));
+    }
+}
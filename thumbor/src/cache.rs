@@ -0,0 +1,303 @@
+// 两级缓存：raw保存获取到的源图片，processed保存处理后的结果
+// 两级都按url记录，源图片在上游更新后可以通过admin api按url或者url前缀清除
+use bytes::Bytes;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// 统计里最多列出这么多个命中次数最多的key
+const TOP_KEYS: usize = 10;
+
+// 处理后的图片和它的content-type
+#[derive(Debug, Clone, PartialEq)]
+pub struct Processed {
+    pub data: Bytes,
+    pub content_type: &'static str,
+}
+
+// 清除哪些缓存
+#[derive(Debug, Clone, PartialEq)]
+pub enum Purge {
+    Url(String),
+    Prefix(String),
+    All,
+}
+
+impl Purge {
+    fn matches(&self, url: &str) -> bool {
+        match self {
+            Purge::Url(v) => url == v,
+            Purge::Prefix(v) => url.starts_with(v.as_str()),
+            Purge::All => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct KeyStats {
+    pub key: String,
+    pub hits: u64,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct TierStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_ratio: f64,
+    pub top_keys: Vec<KeyStats>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct CacheStats {
+    pub raw: TierStats,
+    pub processed: TierStats,
+}
+
+// 每一级清除的条目数
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct PurgeResult {
+    pub raw: usize,
+    pub processed: usize,
+}
+
+struct Entry<T> {
+    // 源图片的url，按url清除时使用
+    url: String,
+    value: T,
+    size: usize,
+    hits: u64,
+}
+
+struct Tier<T> {
+    entries: LruCache<String, Entry<T>>,
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl<T: Clone> Tier<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: LruCache::new(capacity),
+            bytes: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<T> {
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.hits += 1;
+                self.hits += 1;
+                Some(entry.value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn put(&mut self, key: String, url: String, value: T, size: usize) {
+        // 满了的时候先手动淘汰最旧的一项，这样才能更新bytes
+        if !self.entries.contains(&key) && self.entries.len() == self.entries.cap() {
+            if let Some((_, old)) = self.entries.pop_lru() {
+                self.bytes -= old.size;
+            }
+        }
+        let entry = Entry {
+            url,
+            value,
+            size,
+            hits: 0,
+        };
+        if let Some(old) = self.entries.put(key, entry) {
+            self.bytes -= old.size;
+        }
+        self.bytes += size;
+    }
+
+    fn purge(&mut self, purge: &Purge) -> usize {
+        let keys: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| purge.matches(&entry.url))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys.iter() {
+            if let Some(old) = self.entries.pop(key) {
+                self.bytes -= old.size;
+            }
+        }
+        keys.len()
+    }
+
+    fn stats(&self) -> TierStats {
+        let mut top_keys: Vec<KeyStats> = self
+            .entries
+            .iter()
+            .map(|(key, entry)| KeyStats {
+                key: key.clone(),
+                hits: entry.hits,
+                bytes: entry.size,
+            })
+            .collect();
+        top_keys.sort_by(|a, b| b.hits.cmp(&a.hits).then_with(|| a.key.cmp(&b.key)));
+        top_keys.truncate(TOP_KEYS);
+
+        let total = self.hits + self.misses;
+        TierStats {
+            entries: self.entries.len(),
+            bytes: self.bytes,
+            hits: self.hits,
+            misses: self.misses,
+            hit_ratio: match total {
+                0 => 0.0,
+                n => self.hits as f64 / n as f64,
+            },
+            top_keys,
+        }
+    }
+}
+
+struct Inner {
+    raw: Tier<Bytes>,
+    processed: Tier<Processed>,
+}
+
+#[derive(Clone)]
+pub struct Cache(Arc<Mutex<Inner>>);
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new(1024, 1024)
+    }
+}
+
+impl Cache {
+    pub fn new(raw_capacity: usize, processed_capacity: usize) -> Self {
+        Self(Arc::new(Mutex::new(Inner {
+            raw: Tier::new(raw_capacity),
+            processed: Tier::new(processed_capacity),
+        })))
+    }
+
+    // 只检查是否存在，不影响LRU顺序和统计
+    pub async fn contains_raw(&self, url: &str) -> bool {
+        self.0.lock().await.raw.entries.contains(url)
+    }
+
+    pub async fn get_raw(&self, url: &str) -> Option<Bytes> {
+        self.0.lock().await.raw.get(url)
+    }
+
+    pub async fn put_raw(&self, url: &str, data: Bytes) {
+        let size = data.len();
+        self.0
+            .lock()
+            .await
+            .raw
+            .put(url.to_string(), url.to_string(), data, size);
+    }
+
+    // spec是编码后的ImageSpec
+    pub async fn get_processed(&self, spec: &str, url: &str) -> Option<Processed> {
+        self.0.lock().await.processed.get(&processed_key(spec, url))
+    }
+
+    pub async fn put_processed(&self, spec: &str, url: &str, processed: Processed) {
+        let size = processed.data.len();
+        self.0.lock().await.processed.put(
+            processed_key(spec, url),
+            url.to_string(),
+            processed,
+            size,
+        );
+    }
+
+    // 同时清除两级缓存
+    pub async fn purge(&self, purge: &Purge) -> PurgeResult {
+        let mut inner = self.0.lock().await;
+        PurgeResult {
+            raw: inner.raw.purge(purge),
+            processed: inner.processed.purge(purge),
+        }
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        let inner = self.0.lock().await;
+        CacheStats {
+            raw: inner.raw.stats(),
+            processed: inner.processed.stats(),
+        }
+    }
+}
+
+fn processed_key(spec: &str, url: &str) -> String {
+    format!("{} {}", spec, url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn processed(data: &'static [u8]) -> Processed {
+        Processed {
+            data: Bytes::from_static(data),
+            content_type: "image/jpeg",
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_should_track_hits_and_bytes() {
+        let cache = Cache::new(2, 2);
+        cache.put_raw("http://a/1.jpg", Bytes::from_static(b"12345")).await;
+        cache.put_raw("http://a/2.jpg", Bytes::from_static(b"123")).await;
+
+        assert!(cache.get_raw("http://a/1.jpg").await.is_some());
+        assert!(cache.get_raw("http://a/1.jpg").await.is_some());
+        assert!(cache.get_raw("http://a/3.jpg").await.is_none());
+        // contains不计入统计
+        assert!(cache.contains_raw("http://a/2.jpg").await);
+
+        let stats = cache.stats().await.raw;
+        assert_eq!((stats.entries, stats.bytes), (2, 8));
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert!((stats.hit_ratio - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(stats.top_keys[0].key, "http://a/1.jpg");
+        assert_eq!(stats.top_keys[0].hits, 2);
+
+        // 淘汰和覆盖之后bytes仍然正确
+        cache.put_raw("http://a/3.jpg", Bytes::from_static(b"1")).await;
+        cache.put_raw("http://a/3.jpg", Bytes::from_static(b"12")).await;
+        let stats = cache.stats().await.raw;
+        assert_eq!((stats.entries, stats.bytes), (2, 7));
+    }
+
+    #[tokio::test]
+    async fn purge_should_apply_to_both_tiers() {
+        let cache = Cache::default();
+        for url in ["http://a/1.jpg", "http://a/2.jpg", "http://b/1.jpg"] {
+            cache.put_raw(url, Bytes::from_static(b"raw")).await;
+            cache.put_processed("spec", url, processed(b"out")).await;
+        }
+        cache.put_processed("other", "http://a/1.jpg", processed(b"out")).await;
+
+        let result = cache.purge(&Purge::Url("http://a/1.jpg".into())).await;
+        assert_eq!(result, PurgeResult { raw: 1, processed: 2 });
+        assert!(cache.get_processed("spec", "http://a/2.jpg").await.is_some());
+
+        let result = cache.purge(&Purge::Prefix("http://a/".into())).await;
+        assert_eq!(result, PurgeResult { raw: 1, processed: 1 });
+
+        let result = cache.purge(&Purge::All).await;
+        assert_eq!(result, PurgeResult { raw: 1, processed: 1 });
+        let stats = cache.stats().await;
+        assert_eq!((stats.raw.bytes, stats.processed.bytes), (0, 0));
+    }
+}
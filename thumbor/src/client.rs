@@ -0,0 +1,301 @@
+use crate::{
+    pb::*,
+    server::{preset_key, PlaceholderBody, PlaceholderKind},
+};
+use anyhow::Result;
+use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use hmac::{Hmac, Mac, NewMac};
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// 用链式调用构建ImageSpec，spec按调用顺序执行
+#[derive(Debug, Default, Clone)]
+pub struct ImageSpecBuilder {
+    specs: Vec<Spec>,
+}
+
+impl ImageSpecBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn resize(self, width: u32, height: u32, filter: resize::SampleFilter) -> Self {
+        self.spec(Spec::new_resize(width, height, filter))
+    }
+
+    // 在线性空间中缩放，明暗交界处不会偏暗
+    pub fn resize_linear(self, width: u32, height: u32, filter: resize::SampleFilter) -> Self {
+        self.spec(Spec::new_resize(width, height, filter).with_linear())
+    }
+
+    pub fn seam_carve(self, width: u32, height: u32) -> Self {
+        self.spec(Spec::new_resize_seam_carve(width, height))
+    }
+
+    // 等比缩放并裁剪到width x height，裁剪时保留焦点区域
+    pub fn cover(
+        self,
+        width: u32,
+        height: u32,
+        filter: resize::SampleFilter,
+        focal: Focal,
+    ) -> Self {
+        self.spec(Spec::new_resize_cover(width, height, filter, focal))
+    }
+
+    pub fn crop(self, x1: u32, y1: u32, x2: u32, y2: u32) -> Self {
+        self.spec(Spec::new_crop(x1, y1, x2, y2))
+    }
+
+    // 截取width x height大小的区域，位置由焦点决定
+    pub fn crop_focal(self, width: u32, height: u32, focal: Focal) -> Self {
+        self.spec(Spec::new_crop_focal(width, height, focal))
+    }
+
+    pub fn fliph(self) -> Self {
+        self.spec(Spec::new_fliph())
+    }
+
+    pub fn flipv(self) -> Self {
+        self.spec(Spec::new_flipv())
+    }
+
+    pub fn contrast(self, contrast: f32) -> Self {
+        self.spec(Spec::new_contrast(contrast))
+    }
+
+    pub fn filter(self, filter: filter::Filter) -> Self {
+        self.spec(Spec::new_filter(filter))
+    }
+
+    pub fn watermark(self, x: u32, y: u32) -> Self {
+        self.spec(Spec::new_watermark(x, y))
+    }
+
+    // 去掉四周颜色一致的边框，保留padding像素的边距
+    pub fn trim(self, tolerance: u32, padding: u32) -> Self {
+        self.spec(Spec::new_trim(tolerance, padding))
+    }
+
+    // 输出JPEG时透明区域合成的背景色，color是0xRRGGBB
+    pub fn background(self, color: u32) -> Self {
+        self.spec(Spec::new_background(color))
+    }
+
+    // 输出JPEG，quality为0时使用默认的85
+    pub fn jpeg(
+        self,
+        quality: u32,
+        progressive: bool,
+        subsampling: output::ChromaSubsampling,
+    ) -> Self {
+        self.spec(Spec::new_jpeg(quality, progressive, subsampling))
+    }
+
+    // 输出PNG，palette不为0时量化成调色板图片
+    pub fn png(self, compression: output::PngCompression, palette: u32) -> Self {
+        self.spec(Spec::new_png(compression, palette))
+    }
+
+    // 设置输出大小的上限，需要紧跟在jpeg/png之后调用
+    pub fn max_bytes(mut self, max_bytes: u32) -> Self {
+        if let Some(spec) = self.specs.pop() {
+            self.specs.push(spec.with_max_bytes(max_bytes));
+        }
+        self
+    }
+
+    // 把url指向的图片叠加到(x, y)，scale为0时不缩放，opacity为0时完全不透明
+    pub fn overlay(
+        self,
+        url: impl Into<String>,
+        x: u32,
+        y: u32,
+        scale: f32,
+        opacity: f32,
+        blend: overlay::BlendMode,
+    ) -> Self {
+        self.spec(Spec::new_overlay(url, x, y, scale, opacity, blend))
+    }
+
+    // 添加任意一个spec
+    pub fn spec(mut self, spec: Spec) -> Self {
+        self.specs.push(spec);
+        self
+    }
+
+    pub fn build(self) -> ImageSpec {
+        ImageSpec::new(self.specs)
+    }
+}
+
+// 用HMAC-SHA256对(spec, 源图片url)签名，防止别人随意构造url消耗服务器资源
+#[derive(Clone)]
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+impl UrlSigner {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self { key: key.into() }
+    }
+
+    pub fn sign(&self, spec: &str, url: &str) -> String {
+        let tag = self.mac(spec, url).finalize().into_bytes();
+        encode_config(tag, URL_SAFE_NO_PAD)
+    }
+
+    pub fn verify(&self, spec: &str, url: &str, sig: &str) -> bool {
+        match decode_config(sig, URL_SAFE_NO_PAD) {
+            Ok(tag) => self.mac(spec, url).verify(&tag).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn mac(&self, spec: &str, url: &str) -> HmacSha256 {
+        // HMAC可以接受任意长度的key，这里不会失败
+        let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+        mac.update(spec.as_bytes());
+        mac.update(b"/");
+        mac.update(url.as_bytes());
+        mac
+    }
+}
+
+// thumbor服务的客户端，负责拼接（签名）url并解析返回结果
+#[derive(Clone)]
+pub struct Client {
+    base: String,
+    signer: Option<UrlSigner>,
+    http: reqwest::Client,
+}
+
+impl Client {
+    // base是服务的地址，比如 http://localhost:3000
+    pub fn new(base: impl Into<String>) -> Self {
+        Self {
+            base: base.into().trim_end_matches('/').to_string(),
+            signer: None,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn with_signer(mut self, signer: UrlSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    pub fn image_url(&self, spec: &ImageSpec, url: &str) -> String {
+        self.build_url("image", spec, url)
+    }
+
+    pub fn placeholder_url(&self, kind: PlaceholderKind, spec: &ImageSpec, url: &str) -> String {
+        self.build_url(&format!("placeholder/{}", kind.as_str()), spec, url)
+    }
+
+    // 使用服务端配置的preset，extra里的spec会在preset之后执行
+    pub fn preset_url(&self, name: &str, extra: Option<&ImageSpec>, url: &str) -> String {
+        let extra: Option<String> = extra.map(|spec| spec.into());
+        let key = preset_key(name, extra.as_deref());
+        let path = match &extra {
+            Some(spec) => format!("preset/{}/{}", name, spec),
+            None => format!("preset/{}", name),
+        };
+        self.sign_url(
+            format!("{}/{}/{}", self.base, path, encode_url(url)),
+            &key,
+            url,
+        )
+    }
+
+    pub async fn image(&self, spec: &ImageSpec, url: &str) -> Result<Bytes> {
+        let resp = self.http.get(self.image_url(spec, url)).send().await?;
+        Ok(resp.error_for_status()?.bytes().await?)
+    }
+
+    pub async fn placeholder(
+        &self,
+        kind: PlaceholderKind,
+        spec: &ImageSpec,
+        url: &str,
+    ) -> Result<PlaceholderBody> {
+        let resp = self
+            .http
+            .get(self.placeholder_url(kind, spec, url))
+            .send()
+            .await?;
+        Ok(resp.error_for_status()?.json().await?)
+    }
+
+    pub async fn preset(&self, name: &str, extra: Option<&ImageSpec>, url: &str) -> Result<Bytes> {
+        let resp = self
+            .http
+            .get(self.preset_url(name, extra, url))
+            .send()
+            .await?;
+        Ok(resp.error_for_status()?.bytes().await?)
+    }
+
+    fn build_url(&self, prefix: &str, spec: &ImageSpec, url: &str) -> String {
+        let s: String = spec.into();
+        let result = format!("{}/{}/{}/{}", self.base, prefix, s, encode_url(url));
+        self.sign_url(result, &s, url)
+    }
+
+    // 配置了signer时在url后面加上签名
+    fn sign_url(&self, mut result: String, key: &str, url: &str) -> String {
+        if let Some(signer) = &self.signer {
+            result.push_str("?sig=");
+            result.push_str(&signer.sign(key, url));
+        }
+        result
+    }
+}
+
+fn encode_url(url: &str) -> String {
+    percent_encode(url.as_bytes(), NON_ALPHANUMERIC).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_should_keep_spec_order() {
+        let spec = ImageSpecBuilder::new()
+            .resize(100, 100, resize::SampleFilter::Nearest)
+            .fliph()
+            .build();
+        assert_eq!(
+            spec,
+            ImageSpec::new(vec![
+                Spec::new_resize(100, 100, resize::SampleFilter::Nearest),
+                Spec::new_fliph(),
+            ])
+        );
+    }
+
+    #[test]
+    fn signed_url_could_be_verified() {
+        let signer = UrlSigner::new("secret");
+        let sig = signer.sign("spec", "https://example.com/a.jpg");
+        assert!(signer.verify("spec", "https://example.com/a.jpg", &sig));
+        assert!(!signer.verify("spec", "https://example.com/b.jpg", &sig));
+        assert!(!UrlSigner::new("other").verify("spec", "https://example.com/a.jpg", &sig));
+    }
+
+    #[test]
+    fn client_should_build_signed_url() {
+        let client = Client::new("http://localhost:3000/").with_signer(UrlSigner::new("secret"));
+        let spec = ImageSpecBuilder::new().flipv().build();
+        let url = client.image_url(&spec, "https://example.com/a.jpg");
+        let s: String = (&spec).into();
+        assert!(url.starts_with(&format!(
+            "http://localhost:3000/image/{}/https%3A%2F%2Fexample%2Ecom%2Fa%2Ejpg?sig=",
+            s
+        )));
+    }
+}
@@ -0,0 +1,123 @@
+// 把一张RGBA图片按照混合模式叠加到另一张上
+use crate::pb::overlay::BlendMode;
+
+// base和top都是RGBA的原始像素，top的左上角放在base的(x, y)
+// 超出base的部分会被忽略，base的alpha保持不变
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn blend_onto(
+    base: &mut [u8],
+    base_width: u32,
+    base_height: u32,
+    top: &[u8],
+    top_width: u32,
+    top_height: u32,
+    (x, y): (u32, u32),
+    opacity: f32,
+    mode: BlendMode,
+) {
+    let opacity = opacity.clamp(0.0, 1.0);
+    let cols = top_width.min(base_width.saturating_sub(x));
+    let rows = top_height.min(base_height.saturating_sub(y));
+
+    for row in 0..rows {
+        for col in 0..cols {
+            let t = ((row * top_width + col) * 4) as usize;
+            let b = (((y + row) * base_width + x + col) * 4) as usize;
+            let alpha = top[t + 3] as f32 / 255.0 * opacity;
+            if alpha == 0.0 {
+                continue;
+            }
+            for c in 0..3 {
+                let a = base[b + c] as f32 / 255.0;
+                let v = blend_channel(a, top[t + c] as f32 / 255.0, mode);
+                base[b + c] = ((a + (v - a) * alpha) * 255.0).round() as u8;
+            }
+        }
+    }
+}
+
+fn blend_channel(a: f32, b: f32, mode: BlendMode) -> f32 {
+    match mode {
+        BlendMode::Normal => b,
+        BlendMode::Multiply => a * b,
+        BlendMode::Screen => 1.0 - (1.0 - a) * (1.0 - b),
+        BlendMode::Overlay if a < 0.5 => 2.0 * a * b,
+        BlendMode::Overlay => 1.0 - 2.0 * (1.0 - a) * (1.0 - b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(width: u32, height: u32, rgba: [u8; 4]) -> Vec<u8> {
+        rgba.iter()
+            .copied()
+            .cycle()
+            .take((width * height * 4) as usize)
+            .collect()
+    }
+
+    fn blend_pixel(base: [u8; 4], top: [u8; 4], opacity: f32, mode: BlendMode) -> [u8; 4] {
+        let mut pixels = base.to_vec();
+        blend_onto(&mut pixels, 1, 1, &top, 1, 1, (0, 0), opacity, mode);
+        [pixels[0], pixels[1], pixels[2], pixels[3]]
+    }
+
+    #[test]
+    fn blend_modes_should_work() {
+        let base = [200, 100, 0, 255];
+        let top = [100, 255, 255, 255];
+        assert_eq!(blend_pixel(base, top, 1.0, BlendMode::Normal), top);
+        assert_eq!(
+            blend_pixel(base, top, 1.0, BlendMode::Multiply),
+            [78, 100, 0, 255]
+        );
+        assert_eq!(
+            blend_pixel(base, top, 1.0, BlendMode::Screen),
+            [222, 255, 255, 255]
+        );
+        assert_eq!(
+            blend_pixel(base, top, 1.0, BlendMode::Overlay),
+            [188, 200, 0, 255]
+        );
+        // 半透明时按不透明度插值
+        assert_eq!(
+            blend_pixel(base, top, 0.25, BlendMode::Normal),
+            [175, 139, 64, 255]
+        );
+        // 完全透明的像素不改变base
+        assert_eq!(
+            blend_pixel(base, [0, 0, 0, 0], 1.0, BlendMode::Normal),
+            base
+        );
+    }
+
+    #[test]
+    fn overlay_outside_base_should_be_clipped() {
+        let mut base = solid(4, 4, [0, 0, 0, 255]);
+        let top = solid(3, 3, [255, 255, 255, 255]);
+        blend_onto(&mut base, 4, 4, &top, 3, 3, (2, 2), 1.0, BlendMode::Normal);
+
+        let white: Vec<usize> = base
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|(_, p)| p[0] == 255)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(white, vec![10, 11, 14, 15]);
+
+        // 完全在base之外
+        blend_onto(
+            &mut base,
+            4,
+            4,
+            &top,
+            3,
+            3,
+            (10, 10),
+            1.0,
+            BlendMode::Normal,
+        );
+    }
+}
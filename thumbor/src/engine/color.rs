@@ -0,0 +1,183 @@
+// 色彩空间和alpha相关的处理：解码、线性空间缩放、输出不透明格式前的alpha合成
+use super::{format, svg, InputFormat};
+use crate::pb::Spec;
+use anyhow::Result;
+use image::{imageops::FilterType, DynamicImage, ImageBuffer, Rgba};
+use lazy_static::lazy_static;
+use photon_rs::{transform::SamplingFilter, PhotonImage};
+
+lazy_static! {
+    // sRGB的8位值到线性空间16位值的查找表
+    static ref SRGB_TO_LINEAR: Vec<u16> = (0..=255u8)
+        .map(|v| (srgb_to_linear(v as f32 / 255.0) * 65535.0).round() as u16)
+        .collect();
+}
+
+// 解码成8位RGBA
+// 灰度、带alpha的灰度和16位的图片都会正确扩展/缩放到RGBA8，而不是直接使用原始字节
+pub(crate) fn decode(data: &[u8]) -> Result<PhotonImage> {
+    Ok(to_photon(format::decode(data)?))
+}
+
+// 和decode相同，但SVG按spec决定的尺寸栅格化，返回接下来需要apply的spec
+pub(crate) fn load(data: &[u8], specs: &[Spec]) -> Result<(PhotonImage, Vec<Spec>)> {
+    let (img, specs) = match InputFormat::sniff(data) {
+        Some(InputFormat::Svg) => svg::load(data, specs)?,
+        _ => (format::decode(data)?, specs.to_vec()),
+    };
+    Ok((to_photon(img), specs))
+}
+
+fn to_photon(img: DynamicImage) -> PhotonImage {
+    let img = img.to_rgba8();
+    let (width, height) = img.dimensions();
+    PhotonImage::new(img.into_raw(), width, height)
+}
+
+// 在线性空间中缩放：先把sRGB转成线性值并乘上alpha，缩放后再转换回来
+// 直接在sRGB上插值会让明暗交界处偏暗，透明像素的颜色也会渗到边缘
+pub(crate) fn resize_linear(
+    img: &PhotonImage,
+    width: u32,
+    height: u32,
+    filter: SamplingFilter,
+) -> PhotonImage {
+    let (w, h) = (img.get_width(), img.get_height());
+    let linear: Vec<u16> = img
+        .get_raw_pixels()
+        .chunks_exact(4)
+        .flat_map(|p| {
+            let a = p[3] as u32;
+            let premultiply = |v: u8| ((SRGB_TO_LINEAR[v as usize] as u32 * a + 127) / 255) as u16;
+            [
+                premultiply(p[0]),
+                premultiply(p[1]),
+                premultiply(p[2]),
+                a as u16 * 257,
+            ]
+        })
+        .collect();
+    let buf: ImageBuffer<Rgba<u16>, Vec<u16>> = ImageBuffer::from_raw(w, h, linear).unwrap();
+    let resized = image::imageops::resize(&buf, width, height, filter_type(filter));
+
+    let pixels: Vec<u8> = resized
+        .pixels()
+        .flat_map(|p| {
+            let a = p[3] as f32 / 65535.0;
+            let unpremultiply = |v: u16| match a > 0.0 {
+                true => linear_to_srgb8((v as f32 / 65535.0 / a).min(1.0)),
+                false => 0,
+            };
+            [
+                unpremultiply(p[0]),
+                unpremultiply(p[1]),
+                unpremultiply(p[2]),
+                (a * 255.0).round() as u8,
+            ]
+        })
+        .collect();
+    PhotonImage::new(pixels, width, height)
+}
+
+// 把RGBA按alpha合成到背景色上，得到不透明的RGB，用于JPEG这类不支持alpha的格式
+pub(crate) fn flatten(pixels: &[u8], background: [u8; 3]) -> Vec<u8> {
+    pixels
+        .chunks_exact(4)
+        .flat_map(|p| {
+            let a = p[3] as u32;
+            let mix = |c: u8, bg: u8| ((c as u32 * a + bg as u32 * (255 - a) + 127) / 255) as u8;
+            [
+                mix(p[0], background[0]),
+                mix(p[1], background[1]),
+                mix(p[2], background[2]),
+            ]
+        })
+        .collect()
+}
+
+fn filter_type(filter: SamplingFilter) -> FilterType {
+    match filter {
+        SamplingFilter::Nearest => FilterType::Nearest,
+        SamplingFilter::Triangle => FilterType::Triangle,
+        SamplingFilter::CatmullRom => FilterType::CatmullRom,
+        SamplingFilter::Gaussian => FilterType::Gaussian,
+        SamplingFilter::Lanczos3 => FilterType::Lanczos3,
+    }
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb8(v: f32) -> u8 {
+    let s = if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (s * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat};
+
+    fn encode_png(img: DynamicImage) -> Vec<u8> {
+        let mut buf = Vec::new();
+        img.write_to(&mut buf, ImageOutputFormat::Png).unwrap();
+        buf
+    }
+
+    #[test]
+    fn grayscale_and_16bit_inputs_should_be_decoded() {
+        let gray = image::GrayImage::from_pixel(3, 2, image::Luma([100]));
+        let img = decode(&encode_png(DynamicImage::ImageLuma8(gray))).unwrap();
+        assert_eq!(img.get_raw_pixels(), [100, 100, 100, 255].repeat(6));
+
+        let gray_alpha = image::ImageBuffer::from_pixel(2, 2, image::LumaA([0xffffu16, 0x8080]));
+        let img = decode(&encode_png(DynamicImage::ImageLumaA16(gray_alpha))).unwrap();
+        assert_eq!(img.get_raw_pixels(), [255, 255, 255, 128].repeat(4));
+
+        let rgb = image::ImageBuffer::from_pixel(2, 1, image::Rgb([0xffffu16, 0x8080, 0]));
+        let img = decode(&encode_png(DynamicImage::ImageRgb16(rgb))).unwrap();
+        assert_eq!(img.get_raw_pixels(), [255, 128, 0, 255].repeat(2));
+    }
+
+    #[test]
+    fn linear_resize_should_keep_brightness() {
+        // 黑白相间的竖条缩小成一个像素，线性空间的平均值是sRGB的188，而不是128
+        let pixels: Vec<u8> = (0..4)
+            .flat_map(|x| match x % 2 {
+                0 => [0, 0, 0, 255],
+                _ => [255, 255, 255, 255],
+            })
+            .collect();
+        let img = PhotonImage::new(pixels, 4, 1);
+        let small = resize_linear(&img, 1, 1, SamplingFilter::Triangle);
+        let p = small.get_raw_pixels();
+        assert!((186..=190).contains(&p[0]), "{:?}", p);
+        assert_eq!(p[3], 255);
+
+        // 完全透明像素的颜色不会渗到结果里
+        let pixels = [[255, 0, 0, 255], [0, 255, 0, 0]].concat();
+        let img = PhotonImage::new(pixels, 2, 1);
+        let small = resize_linear(&img, 1, 1, SamplingFilter::Triangle);
+        let p = small.get_raw_pixels();
+        assert_eq!((p[0], p[1]), (255, 0));
+        assert!((120..=135).contains(&p[3]), "{:?}", p);
+    }
+
+    #[test]
+    fn flatten_should_blend_with_background() {
+        let pixels = [[255, 0, 0, 255], [255, 0, 0, 0], [0, 0, 0, 128]].concat();
+        assert_eq!(
+            flatten(&pixels, [255, 255, 255]),
+            [[255, 0, 0], [255, 255, 255], [127, 127, 127]].concat()
+        );
+    }
+}
@@ -0,0 +1,290 @@
+// 输出编码：JPEG的渐进式和色度抽样，PNG的压缩级别和调色板量化，以及按大小上限自动调整
+use crate::pb::*;
+use anyhow::Result;
+use color_quant::NeuQuant;
+use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+use std::convert::TryFrom;
+
+// 没有指定质量时使用的JPEG质量
+pub(crate) const DEFAULT_QUALITY: u8 = 85;
+
+const MIN_COLORS: u32 = 2;
+const MAX_COLORS: u32 = 256;
+
+// pixels是RGB
+// 设置了max_bytes并且超出时，在1..quality之间二分查找满足大小的最高质量，都不满足时使用质量1
+pub(crate) fn encode_jpeg(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    quality: u8,
+    output: &Output,
+) -> Result<Vec<u8>> {
+    let data = jpeg(pixels, width, height, quality, output)?;
+    if fits(&data, output) {
+        return Ok(data);
+    }
+
+    let (mut lo, mut hi) = (1, quality as i32 - 1);
+    let mut best = None;
+    while lo <= hi {
+        let mid = (lo + hi) / 2;
+        let data = jpeg(pixels, width, height, mid as u8, output)?;
+        if fits(&data, output) {
+            best = Some(data);
+            lo = mid + 1;
+        } else {
+            hi = mid - 1;
+        }
+    }
+
+    match best {
+        Some(data) => Ok(data),
+        None => jpeg(pixels, width, height, 1, output),
+    }
+}
+
+// pixels是RGBA
+// 设置了max_bytes并且超出时，每次把调色板的颜色减半，直到满足大小或者只剩2种颜色
+pub(crate) fn encode_png(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    output: &Output,
+) -> Result<Vec<u8>> {
+    let compression = match output::PngCompression::from_i32(output.compression) {
+        Some(output::PngCompression::Fast) => png::Compression::Fast,
+        Some(output::PngCompression::Best) => png::Compression::Best,
+        _ => png::Compression::Default,
+    };
+
+    let mut colors = match output.palette {
+        0 => 0,
+        v => v.clamp(MIN_COLORS, MAX_COLORS),
+    };
+    let mut data = png(pixels, width, height, colors, compression)?;
+    while !fits(&data, output) && colors != MIN_COLORS {
+        colors = match colors {
+            0 => MAX_COLORS,
+            v => v / 2,
+        };
+        data = png(pixels, width, height, colors, compression)?;
+    }
+    Ok(data)
+}
+
+fn fits(data: &[u8], output: &Output) -> bool {
+    output.max_bytes == 0 || data.len() <= output.max_bytes as usize
+}
+
+fn jpeg(pixels: &[u8], width: u32, height: u32, quality: u8, output: &Output) -> Result<Vec<u8>> {
+    let sampling = match output::ChromaSubsampling::from_i32(output.subsampling) {
+        Some(output::ChromaSubsampling::Chroma422) => SamplingFactor::R_4_2_2,
+        Some(output::ChromaSubsampling::Chroma444) => SamplingFactor::R_4_4_4,
+        _ => SamplingFactor::R_4_2_0,
+    };
+
+    let mut buffer = Vec::with_capacity(32768);
+    let mut encoder = Encoder::new(&mut buffer, quality.clamp(1, 100));
+    encoder.set_progressive(output.progressive);
+    encoder.set_sampling_factor(sampling);
+    encoder.encode(
+        pixels,
+        u16::try_from(width)?,
+        u16::try_from(height)?,
+        ColorType::Rgb,
+    )?;
+    Ok(buffer)
+}
+
+// colors为0时输出RGBA，否则用NeuQuant量化成带透明度的调色板图片
+fn png(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    colors: u32,
+    compression: png::Compression,
+) -> Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(32768);
+    {
+        let mut encoder = png::Encoder::new(&mut buffer, width, height);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(compression);
+
+        let indices = match colors {
+            0 => {
+                encoder.set_color(png::ColorType::RGBA);
+                None
+            }
+            colors => {
+                let quant = NeuQuant::new(10, colors as usize, pixels);
+                let map = quant.color_map_rgba();
+                encoder.set_color(png::ColorType::Indexed);
+                encoder.set_palette(
+                    map.chunks_exact(4)
+                        .flat_map(|c| [c[0], c[1], c[2]])
+                        .collect(),
+                );
+                encoder.set_trns(map.chunks_exact(4).map(|c| c[3]).collect());
+                Some(
+                    pixels
+                        .chunks_exact(4)
+                        .map(|p| quant.index_of(p) as u8)
+                        .collect::<Vec<_>>(),
+                )
+            }
+        };
+
+        // writer在离开作用域时写入结尾的IEND
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(indices.as_deref().unwrap_or(pixels))?;
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 带噪声的渐变，压缩后的大小随质量/颜色数明显变化
+    fn noisy_rgba(width: u32, height: u32) -> Vec<u8> {
+        let mut seed = 7u32;
+        (0..width * height)
+            .flat_map(|i| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let noise = (seed >> 24) as u8 / 4;
+                let (x, y) = ((i % width) as u8, (i / width) as u8);
+                [
+                    x.wrapping_mul(3).wrapping_add(noise),
+                    y.wrapping_mul(3).wrapping_add(noise),
+                    noise.wrapping_mul(4),
+                    255,
+                ]
+            })
+            .collect()
+    }
+
+    fn rgb(rgba: &[u8]) -> Vec<u8> {
+        rgba.chunks_exact(4).flat_map(|p| [p[0], p[1], p[2]]).collect()
+    }
+
+    fn jpeg_output(progressive: bool, subsampling: output::ChromaSubsampling) -> Output {
+        Output {
+            progressive,
+            subsampling: subsampling as i32,
+            ..Default::default()
+        }
+    }
+
+    // 找到SOF标记，返回(是否渐进式, 亮度分量的采样因子)
+    fn jpeg_frame(data: &[u8]) -> (bool, u8) {
+        let i = data
+            .windows(2)
+            .position(|w| w == [0xff, 0xc0] || w == [0xff, 0xc2])
+            .unwrap();
+        // 标记(2) 长度(2) 精度(1) 高(2) 宽(2) 分量数(1) 第一个分量的id(1)
+        (data[i + 1] == 0xc2, data[i + 11])
+    }
+
+    #[test]
+    fn jpeg_options_should_be_encoded() {
+        let pixels = rgb(&noisy_rgba(64, 64));
+        let baseline = jpeg_output(false, output::ChromaSubsampling::Chroma420);
+        let progressive = jpeg_output(true, output::ChromaSubsampling::Chroma420);
+        let full = jpeg_output(false, output::ChromaSubsampling::Chroma444);
+
+        for (output, frame) in [
+            (baseline, (false, 0x22)),
+            (progressive, (true, 0x22)),
+            (
+                jpeg_output(false, output::ChromaSubsampling::Chroma422),
+                (false, 0x21),
+            ),
+            (full.clone(), (false, 0x11)),
+        ] {
+            let data = encode_jpeg(&pixels, 64, 64, 85, &output).unwrap();
+            assert_eq!(jpeg_frame(&data), frame);
+            let img = image::load_from_memory(&data).unwrap();
+            assert_eq!((img.width(), img.height()), (64, 64));
+        }
+
+        // 不做色度抽样时保留更多信息，文件更大
+        let subsampled = jpeg_output(false, output::ChromaSubsampling::Chroma420);
+        let small = encode_jpeg(&pixels, 64, 64, 85, &subsampled).unwrap();
+        let large = encode_jpeg(&pixels, 64, 64, 85, &full).unwrap();
+        assert!(large.len() > small.len());
+    }
+
+    #[test]
+    fn jpeg_should_fit_max_bytes() {
+        let pixels = rgb(&noisy_rgba(64, 64));
+        let unlimited = encode_jpeg(&pixels, 64, 64, 95, &Output::default()).unwrap();
+
+        let max_bytes = unlimited.len() as u32 / 2;
+        let output = Output {
+            max_bytes,
+            ..Default::default()
+        };
+        let data = encode_jpeg(&pixels, 64, 64, 95, &output).unwrap();
+        assert!(data.len() <= max_bytes as usize);
+        // 不是直接用最低质量
+        let lowest = encode_jpeg(&pixels, 64, 64, 1, &Output::default()).unwrap();
+        assert!(data.len() > lowest.len());
+        assert!(image::load_from_memory(&data).is_ok());
+
+        // 无法满足时尽量输出最小的结果
+        let output = Output {
+            max_bytes: 10,
+            ..Default::default()
+        };
+        assert_eq!(encode_jpeg(&pixels, 64, 64, 95, &output).unwrap(), lowest);
+    }
+
+    #[test]
+    fn png_options_should_be_encoded() {
+        let pixels = noisy_rgba(64, 64);
+        let encode = |compression: output::PngCompression, palette| {
+            let output = Output {
+                format: output::Format::Png as i32,
+                compression: compression as i32,
+                palette,
+                ..Default::default()
+            };
+            encode_png(&pixels, 64, 64, &output).unwrap()
+        };
+
+        let fast = encode(output::PngCompression::Fast, 0);
+        let best = encode(output::PngCompression::Best, 0);
+        assert!(best.len() <= fast.len());
+        // 无损压缩，像素不变
+        for data in [&fast, &best] {
+            let img = image::load_from_memory(data).unwrap().to_rgba8();
+            assert_eq!(img.into_raw(), pixels);
+        }
+
+        let indexed = encode(output::PngCompression::Default, 16);
+        assert!(indexed.len() < best.len());
+        let img = image::load_from_memory(&indexed).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (64, 64));
+        let mut colors: Vec<_> = img.pixels().map(|p| p.0).collect();
+        colors.sort_unstable();
+        colors.dedup();
+        assert!(colors.len() <= 16);
+    }
+
+    #[test]
+    fn png_should_fit_max_bytes() {
+        let pixels = noisy_rgba(64, 64);
+        let rgba = encode_png(&pixels, 64, 64, &Output::default()).unwrap();
+
+        let max_bytes = rgba.len() as u32 / 3;
+        let output = Output {
+            format: output::Format::Png as i32,
+            max_bytes,
+            ..Default::default()
+        };
+        let data = encode_png(&pixels, 64, 64, &output).unwrap();
+        assert!(data.len() <= max_bytes as usize);
+        assert!(image::load_from_memory(&data).is_ok());
+    }
+}
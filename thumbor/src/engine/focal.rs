@@ -0,0 +1,111 @@
+// 焦点相关的计算：crop和cover resize时决定截取窗口的位置
+use crate::pb::*;
+use photon_rs::PhotonImage;
+
+// 计算焦点在图片中的位置，返回相对于宽高的比例(0-1)
+pub(crate) fn focal_point(img: &PhotonImage, focal: &Focal) -> (f32, f32) {
+    match focal::Mode::from_i32(focal.mode) {
+        Some(focal::Mode::Point) => (focal.x.clamp(0.0, 1.0), focal.y.clamp(0.0, 1.0)),
+        Some(focal::Mode::Auto) => {
+            auto_focal(&img.get_raw_pixels(), img.get_width(), img.get_height())
+        }
+        _ => (0.5, 0.5),
+    }
+}
+
+// 把win_w x win_h的窗口以焦点为中心放到图片里，超出边界时往回挪
+// 窗口比图片大时缩小到图片大小，返回(x1, y1, x2, y2)
+pub(crate) fn place_window(
+    width: u32,
+    height: u32,
+    win_w: u32,
+    win_h: u32,
+    (fx, fy): (f32, f32),
+) -> (u32, u32, u32, u32) {
+    let place = |size: u32, win: u32, f: f32| {
+        let win = win.min(size);
+        let center = (f * size as f32).round() as i64;
+        let start = (center - win as i64 / 2).clamp(0, (size - win) as i64) as u32;
+        (start, start + win)
+    };
+    let (x1, x2) = place(width, win_w, fx);
+    let (y1, y2) = place(height, win_h, fy);
+    (x1, y1, x2, y2)
+}
+
+// 自动焦点：用亮度梯度的平方（边缘能量）加权求重心
+// 主体通常比背景有更多细节和边缘，天空、墙面这样平滑的区域能量很低
+fn auto_focal(pixels: &[u8], width: u32, height: u32) -> (f32, f32) {
+    // 大图按步长采样，最多计算约128x128个点
+    let step = (width.max(height) / 128).max(1);
+    let luma = |x: u32, y: u32| {
+        let i = ((y * width + x) * 4) as usize;
+        0.299 * pixels[i] as f64 + 0.587 * pixels[i + 1] as f64 + 0.114 * pixels[i + 2] as f64
+    };
+
+    let (mut sx, mut sy, mut total) = (0.0, 0.0, 0.0);
+    for y in (step..height.saturating_sub(step)).step_by(step as usize) {
+        for x in (step..width.saturating_sub(step)).step_by(step as usize) {
+            let gx = luma(x + step, y) - luma(x - step, y);
+            let gy = luma(x, y + step) - luma(x, y - step);
+            let energy = gx * gx + gy * gy;
+            sx += energy * x as f64;
+            sy += energy * y as f64;
+            total += energy;
+        }
+    }
+
+    if total == 0.0 {
+        return (0.5, 0.5);
+    }
+    (
+        (sx / total / width as f64) as f32,
+        (sy / total / height as f64) as f32,
+    )
+}
+
+// 灰色背景上，在(x1, y1)-(x2, y2)画一块黑白棋盘格作为"主体"
+#[cfg(test)]
+pub(crate) fn subject_image(width: u32, height: u32, rect: (u32, u32, u32, u32)) -> PhotonImage {
+    let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let inside = x >= rect.0 && x < rect.2 && y >= rect.1 && y < rect.3;
+            let v = match inside {
+                true if (x / 4 + y / 4) % 2 == 0 => 255,
+                true => 0,
+                false => 128,
+            };
+            pixels.extend_from_slice(&[v, v, v, 255]);
+        }
+    }
+    PhotonImage::new(pixels, width, height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_focal_should_find_the_subject() {
+        let img = subject_image(200, 100, (150, 30, 190, 70));
+        let (fx, fy) = focal_point(&img, &Focal::auto());
+        assert!((0.75..0.95).contains(&fx), "fx = {}", fx);
+        assert!((0.3..0.7).contains(&fy), "fy = {}", fy);
+
+        // 没有任何边缘时退回到中心
+        let flat = subject_image(50, 50, (0, 0, 0, 0));
+        assert_eq!(focal_point(&flat, &Focal::auto()), (0.5, 0.5));
+    }
+
+    #[test]
+    fn window_should_stay_inside_image() {
+        assert_eq!(place_window(100, 100, 40, 40, (0.5, 0.5)), (30, 30, 70, 70));
+        assert_eq!(place_window(100, 100, 40, 40, (0.0, 1.0)), (0, 60, 40, 100));
+        assert_eq!(place_window(100, 50, 200, 40, (0.9, 0.9)), (0, 10, 100, 50));
+        assert_eq!(
+            focal_point(&subject_image(4, 4, (0, 0, 0, 0)), &Focal::point(2.0, -1.0)),
+            (1.0, 0.0)
+        );
+    }
+}
@@ -0,0 +1,204 @@
+// 按文件头的magic bytes识别输入格式，再交给对应的解码器
+// 不依赖url的扩展名或者源站返回的content-type，它们经常是错的
+use super::svg;
+use anyhow::{anyhow, Result};
+use image::{io::Reader, DynamicImage, ImageBuffer, ImageFormat};
+use std::io::Cursor;
+
+// 支持的输入格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+    Tiff,
+    Bmp,
+    Ico,
+    Avif,
+    Svg,
+}
+
+impl InputFormat {
+    // 无法识别时返回None
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        let format = match data {
+            [0xff, 0xd8, 0xff, ..] => InputFormat::Jpeg,
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => InputFormat::Png,
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => InputFormat::Gif,
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => InputFormat::WebP,
+            [b'I', b'I', 0x2a, 0x00, ..] | [b'M', b'M', 0x00, 0x2a, ..] => InputFormat::Tiff,
+            [b'B', b'M', ..] => InputFormat::Bmp,
+            [0x00, 0x00, 0x01, 0x00, ..] => InputFormat::Ico,
+            _ if is_avif(data) => InputFormat::Avif,
+            _ if svg::is_svg(data) => InputFormat::Svg,
+            _ => return None,
+        };
+        Some(format)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InputFormat::Jpeg => "jpeg",
+            InputFormat::Png => "png",
+            InputFormat::Gif => "gif",
+            InputFormat::WebP => "webp",
+            InputFormat::Tiff => "tiff",
+            InputFormat::Bmp => "bmp",
+            InputFormat::Ico => "ico",
+            InputFormat::Avif => "avif",
+            InputFormat::Svg => "svg",
+        }
+    }
+
+    // AVIF需要dav1d，编译时没有打开avif feature就不支持
+    pub fn is_supported(&self) -> bool {
+        match self {
+            InputFormat::Avif => cfg!(feature = "avif"),
+            _ => true,
+        }
+    }
+
+    // SVG由resvg栅格化，在decode和dimensions里单独处理
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            InputFormat::Jpeg => ImageFormat::Jpeg,
+            InputFormat::Png => ImageFormat::Png,
+            InputFormat::Gif => ImageFormat::Gif,
+            InputFormat::WebP => ImageFormat::WebP,
+            InputFormat::Tiff => ImageFormat::Tiff,
+            InputFormat::Bmp => ImageFormat::Bmp,
+            InputFormat::Ico => ImageFormat::Ico,
+            InputFormat::Avif => ImageFormat::Avif,
+            InputFormat::Svg => unreachable!("svg is not decoded by image"),
+        }
+    }
+}
+
+// 识别格式并检查是否支持
+pub fn input_format(data: &[u8]) -> Result<InputFormat> {
+    match InputFormat::sniff(data) {
+        Some(format) if format.is_supported() => Ok(format),
+        Some(format) => Err(anyhow!("{} input is not enabled in this build", format.as_str())),
+        None => Err(anyhow!("input is not a supported image format")),
+    }
+}
+
+// 完整解码，GIF只取第一帧，SVG按自身的尺寸栅格化
+pub fn decode(data: &[u8]) -> Result<DynamicImage> {
+    match input_format(data)? {
+        InputFormat::Svg => svg::decode(data),
+        // image只能解码有损的WebP，无损、带alpha和动画的WebP使用image-webp
+        InputFormat::WebP => decode_webp(data),
+        format => Ok(image::load_from_memory_with_format(
+            data,
+            format.image_format(),
+        )?),
+    }
+}
+
+// 只读取图片头获取宽高
+pub fn dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    match input_format(data)? {
+        InputFormat::WebP => Ok(image_webp::WebPDecoder::new(Cursor::new(data))?.dimensions()),
+        InputFormat::Svg => svg::dimensions(data),
+        format => {
+            let reader = Reader::with_format(Cursor::new(data), format.image_format());
+            Ok(reader.into_dimensions()?)
+        }
+    }
+}
+
+fn decode_webp(data: &[u8]) -> Result<DynamicImage> {
+    let mut decoder = image_webp::WebPDecoder::new(Cursor::new(data))?;
+    let (width, height) = decoder.dimensions();
+    let size = decoder
+        .output_buffer_size()
+        .ok_or_else(|| anyhow!("webp {}x{} is too large", width, height))?;
+    let mut buf = vec![0; size];
+    decoder.read_image(&mut buf)?;
+
+    let img = match decoder.has_alpha() {
+        true => ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba8),
+        false => ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb8),
+    };
+    img.ok_or_else(|| anyhow!("webp buffer doesn't match {}x{}", width, height))
+}
+
+// AVIF是ISOBMFF容器，第一个box是ftyp，主品牌或者兼容品牌里有avif/avis
+fn is_avif(data: &[u8]) -> bool {
+    if data.len() < 16 || &data[4..8] != b"ftyp" {
+        return false;
+    }
+    let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let end = size.clamp(16, data.len());
+    // 8..12是主品牌，12..16是版本号，之后是兼容品牌
+    std::iter::once(&data[8..12])
+        .chain(data[16..end].chunks_exact(4))
+        .any(|brand| brand == b"avif" || brand == b"avis")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    // CPython的imghdr测试数据，同一个16x16的图标保存为不同格式；ico内嵌的是png版本
+    // svg是手写的16x16左红右蓝的图标
+    const FIXTURES: [(&[u8], InputFormat); 8] = [
+        (include_bytes!("../../fixtures/formats/logo.jpg"), InputFormat::Jpeg),
+        (include_bytes!("../../fixtures/formats/logo.png"), InputFormat::Png),
+        (include_bytes!("../../fixtures/formats/logo.gif"), InputFormat::Gif),
+        (include_bytes!("../../fixtures/formats/logo.webp"), InputFormat::WebP),
+        (include_bytes!("../../fixtures/formats/logo.tiff"), InputFormat::Tiff),
+        (include_bytes!("../../fixtures/formats/logo.bmp"), InputFormat::Bmp),
+        (include_bytes!("../../fixtures/formats/logo.ico"), InputFormat::Ico),
+        (include_bytes!("../../fixtures/formats/logo.svg"), InputFormat::Svg),
+    ];
+
+    #[test]
+    fn every_fixture_should_be_sniffed_and_decoded() {
+        for (data, format) in FIXTURES {
+            assert_eq!(InputFormat::sniff(data), Some(format));
+            assert_eq!(dimensions(data).unwrap(), (16, 16), "{:?}", format);
+            let img = decode(data).unwrap();
+            assert_eq!(img.dimensions(), (16, 16), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn unknown_inputs_should_be_rejected() {
+        assert_eq!(InputFormat::sniff(b"<!DOCTYPE html><html><svg/></html>"), None);
+        assert_eq!(InputFormat::sniff(b"RIFF\0\0\0\0WAVEfmt "), None);
+        assert_eq!(InputFormat::sniff(b""), None);
+        assert!(decode(b"not an image").is_err());
+        assert!(dimensions(b"not an image").is_err());
+
+        // HEIC和AVIF用同样的容器，但品牌不同
+        let heic = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic";
+        assert_eq!(InputFormat::sniff(heic), None);
+        let avif = b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf";
+        assert_eq!(InputFormat::sniff(avif), Some(InputFormat::Avif));
+        let brand = b"\0\0\0\x18ftypmif1\0\0\0\0mif1avif";
+        assert_eq!(InputFormat::sniff(brand), Some(InputFormat::Avif));
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn avif_should_be_decoded() {
+        use image::{codecs::avif::AvifEncoder, ColorType};
+
+        // 没有现成的AVIF样本，用image的编码器生成一张
+        let pixels: Vec<u8> = (0..16 * 16)
+            .flat_map(|i| [(i % 16 * 16) as u8, (i / 16 * 16) as u8, 128])
+            .collect();
+        let mut data = Vec::new();
+        AvifEncoder::new(&mut data)
+            .write_image(&pixels, 16, 16, ColorType::Rgb8)
+            .unwrap();
+
+        assert_eq!(InputFormat::sniff(&data), Some(InputFormat::Avif));
+        assert_eq!(dimensions(&data).unwrap(), (16, 16));
+        assert_eq!(decode(&data).unwrap().dimensions(), (16, 16));
+    }
+}
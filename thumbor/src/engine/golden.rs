@@ -0,0 +1,281 @@
+// golden image回归测试：对fixtures/samples下的每张图片应用每个case的spec，
+// 和fixtures/golden下保存的结果做感知差异比较
+// fixtures/specs下是以前版本发布的url里的spec字符串，解码后要和对应的case渲染结果相同
+//
+// 修改了engine的行为之后，用下面的命令重新生成golden：
+//   THUMBOR_UPDATE_GOLDEN=1 cargo test golden
+use super::{Engine, Photon};
+use crate::pb::*;
+use bytes::Bytes;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+use std::{
+    convert::{TryFrom, TryInto},
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+const SAMPLES: &[&str] = &["gradient.png", "checker.png"];
+
+// 两张图片平均的感知差异不能超过这个值（0-255）
+const MEAN_THRESHOLD: f64 = 1.0;
+// 差异明显（超过PIXEL_THRESHOLD）的像素不能超过这个比例
+const PIXEL_THRESHOLD: f64 = 16.0;
+const OUTLIER_RATIO: f64 = 0.005;
+
+fn cases() -> Vec<(&'static str, Vec<Spec>)> {
+    vec![
+        (
+            "resize",
+            vec![Spec::new_resize(64, 48, resize::SampleFilter::CatmullRom)],
+        ),
+        (
+            "resize_nearest",
+            vec![Spec::new_resize(40, 40, resize::SampleFilter::Nearest)],
+        ),
+        (
+            "resize_linear",
+            vec![Spec::new_resize(40, 30, resize::SampleFilter::Lanczos3).with_linear()],
+        ),
+        ("seam_carve", vec![Spec::new_resize_seam_carve(80, 60)]),
+        ("crop", vec![Spec::new_crop(8, 8, 72, 56)]),
+        (
+            "crop_focal_auto",
+            vec![Spec::new_crop_focal(48, 48, Focal::auto())],
+        ),
+        (
+            "cover_auto",
+            vec![Spec::new_resize_cover(
+                48,
+                48,
+                resize::SampleFilter::CatmullRom,
+                Focal::auto(),
+            )],
+        ),
+        (
+            "cover_point",
+            vec![Spec::new_resize_cover(
+                60,
+                30,
+                resize::SampleFilter::Triangle,
+                Focal::point(0.2, 0.8),
+            )],
+        ),
+        ("fliph", vec![Spec::new_fliph()]),
+        ("flipv", vec![Spec::new_flipv()]),
+        ("contrast", vec![Spec::new_contrast(40.0)]),
+        (
+            "filter_oceanic",
+            vec![Spec::new_filter(filter::Filter::Oceanic)],
+        ),
+        (
+            "filter_islands",
+            vec![Spec::new_filter(filter::Filter::Islands)],
+        ),
+        (
+            "filter_marine",
+            vec![Spec::new_filter(filter::Filter::Marine)],
+        ),
+        ("watermark", vec![Spec::new_watermark(4, 4)]),
+        (
+            "thumbnail",
+            vec![
+                Spec::new_crop(8, 8, 88, 88),
+                Spec::new_resize(32, 32, resize::SampleFilter::Lanczos3),
+            ],
+        ),
+        (
+            "resize_watermark_filter",
+            vec![
+                Spec::new_resize(80, 80, resize::SampleFilter::CatmullRom),
+                Spec::new_watermark(8, 8),
+                Spec::new_filter(filter::Filter::Marine),
+            ],
+        ),
+        // overlay的url是fixtures/samples下的文件名
+        (
+            "overlay_multiply",
+            vec![Spec::new_overlay(
+                "checker.png",
+                16,
+                8,
+                0.5,
+                0.0,
+                overlay::BlendMode::Multiply,
+            )],
+        ),
+        (
+            "overlay_screen_opacity",
+            vec![Spec::new_overlay(
+                "gradient.png",
+                0,
+                0,
+                0.0,
+                0.6,
+                overlay::BlendMode::Screen,
+            )],
+        ),
+        (
+            "flip_both_contrast",
+            vec![
+                Spec::new_fliph(),
+                Spec::new_flipv(),
+                Spec::new_contrast(-20.0),
+            ],
+        ),
+    ]
+}
+
+fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+}
+
+fn update_mode() -> bool {
+    matches!(env::var("THUMBOR_UPDATE_GOLDEN").as_deref(), Ok("1"))
+}
+
+fn render(sample: &[u8], specs: &[Spec]) -> Vec<u8> {
+    let mut engine = Photon::try_from(Bytes::copy_from_slice(sample)).unwrap();
+    let overlays = ImageSpec::new(specs.to_vec());
+    for overlay in overlays.overlays() {
+        let data = fs::read(fixtures().join("samples").join(&overlay.url)).unwrap();
+        engine.add_source(&overlay.url, data.into()).unwrap();
+    }
+    engine.apply(specs);
+    // golden使用无损的png保存，避免jpeg压缩带来的误差
+    engine.generate(ImageOutputFormat::Png)
+}
+
+// 按照人眼对RGB的敏感程度加权，比较两张图片，返回(平均差异, 明显不同的像素比例)
+fn perceptual_diff(a: &DynamicImage, b: &DynamicImage) -> (f64, f64) {
+    let (a, b) = (a.to_rgba8(), b.to_rgba8());
+    let mut total = 0.0;
+    let mut outliers = 0usize;
+    for (pa, pb) in a.pixels().zip(b.pixels()) {
+        let d = |i: usize| (pa[i] as f64 - pb[i] as f64).abs();
+        let diff = 0.299 * d(0) + 0.587 * d(1) + 0.114 * d(2) + d(3) / 4.0;
+        total += diff;
+        if diff > PIXEL_THRESHOLD {
+            outliers += 1;
+        }
+    }
+    let count = (a.width() * a.height()).max(1) as f64;
+    (total / count, outliers as f64 / count)
+}
+
+// 和golden比较，不一致时返回原因
+fn compare(path: &Path, output: &[u8]) -> Option<String> {
+    let expected = image::open(path).unwrap();
+    let actual = image::load_from_memory(output).unwrap();
+    if expected.dimensions() != actual.dimensions() {
+        return Some(format!(
+            "{}: size {:?} != golden {:?}",
+            path.display(),
+            actual.dimensions(),
+            expected.dimensions()
+        ));
+    }
+
+    let (mean, ratio) = perceptual_diff(&expected, &actual);
+    if mean > MEAN_THRESHOLD || ratio > OUTLIER_RATIO {
+        return Some(format!(
+            "{}: mean diff {:.3}, outlier ratio {:.4}",
+            path.display(),
+            mean,
+            ratio
+        ));
+    }
+    None
+}
+
+fn golden_path(sample: &str, name: &str) -> PathBuf {
+    let stem = sample.trim_end_matches(".png");
+    fixtures().join("golden").join(format!("{}__{}.png", stem, name))
+}
+
+#[test]
+fn golden_images_should_match() {
+    let root = fixtures();
+    let mut failures = Vec::new();
+
+    for sample in SAMPLES {
+        let data = fs::read(root.join("samples").join(sample)).unwrap();
+
+        for (name, specs) in cases() {
+            let output = render(&data, &specs);
+            let path = golden_path(sample, name);
+
+            // 更新模式或者golden还不存在时，直接写入新的golden
+            if update_mode() || !path.exists() {
+                fs::write(&path, &output).unwrap();
+                eprintln!("golden written: {}", path.display());
+                continue;
+            }
+            failures.extend(compare(&path, &output));
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "golden images mismatch (set THUMBOR_UPDATE_GOLDEN=1 to regenerate):\n{}",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn published_specs_should_render_like_golden() {
+    let root = fixtures();
+    let cases = cases();
+    let mut failures = Vec::new();
+
+    let mut corpus: Vec<PathBuf> = fs::read_dir(root.join("specs"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    corpus.sort();
+    for file in corpus {
+        let content = fs::read_to_string(&file).unwrap();
+        let lines = content
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+        for line in lines {
+            let (name, encoded) = line.split_once(' ').unwrap();
+            let spec: ImageSpec = encoded.try_into().unwrap_or_else(|e| {
+                panic!("{} {}: {}", file.display(), name, e);
+            });
+            let (_, specs) = cases
+                .iter()
+                .find(|(case, _)| *case == name)
+                .unwrap_or_else(|| panic!("{}: unknown case {}", file.display(), name));
+            // 解码和版本转换之后应该和现在构建的spec完全相同
+            assert_eq!(&spec.specs, specs, "{} {}", file.display(), name);
+
+            for sample in SAMPLES {
+                let path = golden_path(sample, name);
+                if !path.exists() {
+                    continue;
+                }
+                let data = fs::read(root.join("samples").join(sample)).unwrap();
+                let output = render(&data, &spec.specs);
+                failures.extend(compare(&path, &output));
+            }
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "published specs render differently:\n{}",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn perceptual_diff_should_tolerate_small_noise() {
+    let a = DynamicImage::new_rgba8(10, 10);
+    let mut b = a.to_rgba8();
+    b.put_pixel(0, 0, image::Rgba([2, 2, 2, 0]));
+    let (mean, ratio) = perceptual_diff(&a, &DynamicImage::ImageRgba8(b));
+    assert!(mean < MEAN_THRESHOLD);
+    assert_eq!(ratio, 0.0);
+}
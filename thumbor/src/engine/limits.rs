@@ -0,0 +1,187 @@
+use super::format;
+use crate::pb::*;
+use anyhow::{anyhow, Result};
+
+const MEGAPIXEL: f64 = 1_000_000.0;
+
+// 输入和输出图片的像素上限
+// 几KB的PNG可能声明50000x50000的尺寸，完整解码会分配几个G的内存，
+// 所以在解码之前先根据图片头检查尺寸
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_input_pixels: u64,
+    pub max_output_pixels: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::from_megapixels(50.0, 25.0)
+    }
+}
+
+impl Limits {
+    pub fn from_megapixels(input: f64, output: f64) -> Self {
+        Self {
+            max_input_pixels: (input * MEGAPIXEL) as u64,
+            max_output_pixels: (output * MEGAPIXEL) as u64,
+        }
+    }
+
+    // 检查源图片的尺寸
+    pub fn check_input(&self, width: u32, height: u32) -> Result<()> {
+        let pixels = width as u64 * height as u64;
+        if pixels > self.max_input_pixels {
+            return Err(anyhow!(
+                "input image {}x{} exceeds the limit of {} pixels",
+                width,
+                height,
+                self.max_input_pixels
+            ));
+        }
+        Ok(())
+    }
+
+    // 按顺序推算每个spec处理后的尺寸，保证没有spec会超出输出限制
+    pub fn check_specs(&self, width: u32, height: u32, specs: &[Spec]) -> Result<()> {
+        let (mut width, mut height) = (width, height);
+        for spec in specs.iter() {
+            match spec.data {
+                Some(spec::Data::Resize(ref v)) => {
+                    if v.width == 0 || v.height == 0 {
+                        return Err(anyhow!("resize to {}x{} is invalid", v.width, v.height));
+                    }
+                    let pixels = v.width as u64 * v.height as u64;
+                    if pixels > self.max_output_pixels {
+                        return Err(anyhow!(
+                            "resize to {}x{} exceeds the limit of {} pixels",
+                            v.width,
+                            v.height,
+                            self.max_output_pixels
+                        ));
+                    }
+                    width = v.width;
+                    height = v.height;
+                }
+                // 有焦点的crop只用到截取的大小，超出图片时会缩小到图片大小
+                Some(spec::Data::Crop(ref v)) if v.focal.is_some() => {
+                    if v.x1 >= v.x2 || v.y1 >= v.y2 {
+                        return Err(anyhow!(
+                            "crop ({}, {}, {}, {}) is empty",
+                            v.x1,
+                            v.y1,
+                            v.x2,
+                            v.y2
+                        ));
+                    }
+                    width = width.min(v.x2 - v.x1);
+                    height = height.min(v.y2 - v.y1);
+                }
+                Some(spec::Data::Crop(ref v)) => {
+                    if v.x1 >= v.x2 || v.y1 >= v.y2 || v.x2 > width || v.y2 > height {
+                        return Err(anyhow!(
+                            "crop ({}, {}, {}, {}) is out of the {}x{} image",
+                            v.x1,
+                            v.y1,
+                            v.x2,
+                            v.y2,
+                            width,
+                            height
+                        ));
+                    }
+                    width = v.x2 - v.x1;
+                    height = v.y2 - v.y1;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // 检查overlay图片：源图片按输入限制，缩放后的大小按输出限制
+    pub fn check_overlay(&self, width: u32, height: u32, overlay: &Overlay) -> Result<()> {
+        self.check_input(width, height)?;
+        if overlay.scale < 0.0 {
+            return Err(anyhow!("overlay scale {} is invalid", overlay.scale));
+        }
+        let scale = match overlay.scale {
+            v if v > 0.0 => v as f64,
+            _ => 1.0,
+        };
+        let pixels = width as f64 * scale * height as f64 * scale;
+        if pixels > self.max_output_pixels as f64 {
+            return Err(anyhow!(
+                "overlay {}x{} scaled by {} exceeds the limit of {} pixels",
+                width,
+                height,
+                overlay.scale,
+                self.max_output_pixels
+            ));
+        }
+        Ok(())
+    }
+}
+
+// 只读取图片头获取宽高，不做完整解码
+pub fn probe_dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    format::dimensions(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat};
+
+    #[test]
+    fn probe_dimensions_should_read_header() {
+        let img = DynamicImage::new_rgb8(40, 30);
+        let mut buf = Vec::new();
+        img.write_to(&mut buf, ImageOutputFormat::Png).unwrap();
+        assert_eq!(probe_dimensions(&buf).unwrap(), (40, 30));
+        assert!(probe_dimensions(b"not an image").is_err());
+    }
+
+    #[test]
+    fn check_input_should_reject_huge_images() {
+        let limits = Limits::default();
+        assert!(limits.check_input(4000, 3000).is_ok());
+        assert!(limits.check_input(50000, 50000).is_err());
+    }
+
+    #[test]
+    fn check_specs_should_follow_the_chain() {
+        let limits = Limits::from_megapixels(50.0, 1.0);
+        let resize = Spec::new_resize(800, 600, resize::SampleFilter::CatmullRom);
+        let huge = Spec::new_resize(100000, 100, resize::SampleFilter::CatmullRom);
+        let crop = Spec::new_crop(0, 0, 1000, 500);
+
+        assert!(limits.check_specs(2000, 2000, &[resize.clone()]).is_ok());
+        assert!(limits.check_specs(2000, 2000, &[huge]).is_err());
+        assert!(limits.check_specs(2000, 2000, &[crop.clone()]).is_ok());
+        // resize之后图片只有800x600，再crop到1000x500就越界了
+        assert!(limits
+            .check_specs(2000, 2000, &[resize.clone(), crop])
+            .is_err());
+        // 有焦点的crop不会越界
+        let focal_crop = Spec::new_crop_focal(1000, 500, Focal::auto());
+        assert!(limits
+            .check_specs(2000, 2000, &[resize, focal_crop])
+            .is_ok());
+        let cover =
+            Spec::new_resize_cover(2000, 2000, resize::SampleFilter::Nearest, Focal::auto());
+        assert!(limits.check_specs(2000, 2000, &[cover]).is_err());
+    }
+
+    #[test]
+    fn check_overlay_should_limit_scaled_size() {
+        let limits = Limits::from_megapixels(50.0, 1.0);
+        let overlay = |scale| Overlay {
+            url: "badge.png".into(),
+            scale,
+            ..Default::default()
+        };
+        assert!(limits.check_overlay(500, 500, &overlay(0.0)).is_ok());
+        assert!(limits.check_overlay(500, 500, &overlay(2.0)).is_ok());
+        assert!(limits.check_overlay(500, 500, &overlay(3.0)).is_err());
+        assert!(limits.check_overlay(500, 500, &overlay(-1.0)).is_err());
+    }
+}
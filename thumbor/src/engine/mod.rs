@@ -1,13 +1,33 @@
 use crate::pb::Spec;
+use anyhow::Result;
+use bytes::Bytes;
 use image::ImageOutputFormat;
 
+mod blend;
+mod color;
+mod encoder;
+mod focal;
+mod format;
+#[cfg(test)]
+mod golden;
+mod limits;
+mod optimize;
 mod photon;
+mod svg;
+mod trim;
+pub use format::InputFormat;
+pub use limits::{probe_dimensions, Limits};
+pub use optimize::{optimize, validate, MAX_SPECS};
 pub use photon::Photon;
 
 // Engine trait： 未来可以添加更多的engin，主流只需要替换engine
 pub trait Engine {
     // 对engine按照specs进行一系列有序的处理
     fn apply(&mut self, specs: &[Spec]);
+    // 加载spec里引用的其他图片（比如Overlay的url），需要在apply之前调用
+    fn add_source(&mut self, url: &str, data: Bytes) -> Result<()>;
+    // 根据spec里最后一个Output决定输出格式，没有Output时是质量85的JPEG
+    fn format(&self) -> ImageOutputFormat;
     // 从engine中生成目标图片，注意这里用的self,而百self的引用
     fn generate(self, format: ImageOutputFormat) -> Vec<u8>;
 }
@@ -16,4 +36,14 @@ pub trait Engine {
 pub trait SpecTransform<T> {
     // 对图片使用op做transform
     fn transform(&mut self, op: T);
-}
\ No newline at end of file
+}
+
+// Placeholder: 根据engine当前的图片生成低质量占位信息，供前端在图片加载时使用
+pub trait Placeholder {
+    // 生成BlurHash字符串，components_x/components_y取值为1..=9
+    fn blurhash(&self, components_x: u32, components_y: u32) -> Result<String>;
+    // 生成一张很小的jpeg，以base64 data uri的形式返回
+    fn lqip(&self, width: u32) -> String;
+    // 返回图片中最主要的n种颜色，格式为#rrggbb，按占比从高到低排列
+    fn palette(&self, n: usize) -> Vec<String>;
+}
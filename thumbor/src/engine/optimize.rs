@@ -0,0 +1,611 @@
+// 执行之前对spec链做检查和规范化：
+// 检查每个spec的参数，拒绝空的或者过长的链，避免无效的spec在apply时被静默忽略或者panic；
+// 在不改变结果的前提下合并spec、调整顺序，减少需要处理的像素
+use crate::pb::*;
+use anyhow::{anyhow, Result};
+
+// 一个ImageSpec最多包含的spec数量
+pub const MAX_SPECS: usize = 32;
+
+type Dims = (u32, u32);
+
+// 只检查spec本身，不需要知道图片尺寸，可以在获取源图片之前调用
+pub fn validate(specs: &[Spec]) -> Result<()> {
+    if specs.is_empty() {
+        return Err(anyhow!("spec chain is empty"));
+    }
+    if specs.len() > MAX_SPECS {
+        return Err(anyhow!(
+            "spec chain has {} specs, at most {} are allowed",
+            specs.len(),
+            MAX_SPECS
+        ));
+    }
+    for (i, spec) in specs.iter().enumerate() {
+        validate_spec(spec).map_err(|e| anyhow!("spec #{} ({}): {}", i, spec.kind(), e))?;
+    }
+    Ok(())
+}
+
+// 检查spec链，返回一个等价但处理起来更快的spec链，width和height是源图片的尺寸
+// 除了连续的resize，其他规则得到的图片和原来的逐像素相同：
+// - 连续的同方向翻转互相抵消
+// - 连续的crop合并成一个
+// - crop移到翻转和整数倍的nearest resize之前，先裁掉不需要的像素
+// - 去掉不改变图片的spec：完整的crop，尺寸不变的nearest resize，未指定的filter
+// - Background和Output只影响编码，每种只保留最后一个并放到最后
+// 连续的普通resize只保留最后一个：输出尺寸相同，少一次重采样画质更好
+pub fn optimize(specs: &[Spec], width: u32, height: u32) -> Result<Vec<Spec>> {
+    validate(specs)?;
+
+    let mut chain = Chain {
+        source: (width, height),
+        specs: Vec::new(),
+    };
+    let mut encoding: Vec<Spec> = Vec::new();
+    for spec in specs.iter() {
+        match spec.data {
+            Some(spec::Data::Background(_)) | Some(spec::Data::Output(_)) => {
+                encoding.retain(|v| v.kind() != spec.kind());
+                encoding.push(spec.clone());
+            }
+            _ => chain.push(spec.clone())?,
+        }
+    }
+
+    let mut specs: Vec<Spec> = chain.specs.into_iter().map(|(spec, _)| spec).collect();
+    specs.extend(encoding);
+    Ok(specs)
+}
+
+fn validate_spec(spec: &Spec) -> Result<()> {
+    match spec.data {
+        None => Err(anyhow!("no operation")),
+        Some(spec::Data::Resize(ref v)) => {
+            if v.width == 0 || v.height == 0 {
+                return Err(anyhow!("size {}x{} is invalid", v.width, v.height));
+            }
+            resize::ResizeType::from_i32(v.rtype)
+                .ok_or_else(|| anyhow!("unknown resize type {}", v.rtype))?;
+            resize::SampleFilter::from_i32(v.filter)
+                .ok_or_else(|| anyhow!("unknown sample filter {}", v.filter))?;
+            v.focal.as_ref().map_or(Ok(()), validate_focal)
+        }
+        Some(spec::Data::Crop(ref v)) => {
+            if v.x1 >= v.x2 || v.y1 >= v.y2 {
+                return Err(anyhow!("({}, {}, {}, {}) is empty", v.x1, v.y1, v.x2, v.y2));
+            }
+            v.focal.as_ref().map_or(Ok(()), validate_focal)
+        }
+        Some(spec::Data::Contrast(ref v)) if !v.contrast.is_finite() => {
+            Err(anyhow!("contrast {} is invalid", v.contrast))
+        }
+        Some(spec::Data::Filter(ref v)) => filter::Filter::from_i32(v.filter)
+            .map(|_| ())
+            .ok_or_else(|| anyhow!("unknown filter {}", v.filter)),
+        Some(spec::Data::Overlay(ref v)) => {
+            if v.url.is_empty() {
+                return Err(anyhow!("url is empty"));
+            }
+            if !v.scale.is_finite() || v.scale < 0.0 {
+                return Err(anyhow!("scale {} is invalid", v.scale));
+            }
+            if !(0.0..=1.0).contains(&v.opacity) {
+                return Err(anyhow!("opacity {} is out of 0-1", v.opacity));
+            }
+            overlay::BlendMode::from_i32(v.blend)
+                .map(|_| ())
+                .ok_or_else(|| anyhow!("unknown blend mode {}", v.blend))
+        }
+        Some(spec::Data::Background(ref v)) if v.color > 0xFF_FFFF => {
+            Err(anyhow!("color {:#x} is not 0xRRGGBB", v.color))
+        }
+        Some(spec::Data::Output(ref v)) => validate_output(v),
+        Some(spec::Data::Trim(ref v)) if v.tolerance > 255 => {
+            Err(anyhow!("tolerance {} is out of 0-255", v.tolerance))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_focal(focal: &Focal) -> Result<()> {
+    focal::Mode::from_i32(focal.mode)
+        .ok_or_else(|| anyhow!("unknown focal mode {}", focal.mode))?;
+    if !(0.0..=1.0).contains(&focal.x) || !(0.0..=1.0).contains(&focal.y) {
+        return Err(anyhow!("focal ({}, {}) is out of 0-1", focal.x, focal.y));
+    }
+    Ok(())
+}
+
+fn validate_output(v: &Output) -> Result<()> {
+    output::Format::from_i32(v.format).ok_or_else(|| anyhow!("unknown format {}", v.format))?;
+    output::ChromaSubsampling::from_i32(v.subsampling)
+        .ok_or_else(|| anyhow!("unknown chroma subsampling {}", v.subsampling))?;
+    output::PngCompression::from_i32(v.compression)
+        .ok_or_else(|| anyhow!("unknown png compression {}", v.compression))?;
+    if v.quality > 100 {
+        return Err(anyhow!("quality {} is out of 1-100", v.quality));
+    }
+    if v.palette == 1 || v.palette > 256 {
+        return Err(anyhow!("palette {} is out of 2-256", v.palette));
+    }
+    Ok(())
+}
+
+// 处理过程中的spec链，每个spec记录它的输入尺寸，None表示无法预先推算（比如seam carve之后）
+struct Chain {
+    source: Dims,
+    specs: Vec<(Spec, Option<Dims>)>,
+}
+
+impl Chain {
+    // 当前链处理完之后的尺寸
+    fn dims(&self) -> Option<Dims> {
+        match self.specs.last() {
+            Some((spec, dims)) => output_dims(spec, *dims),
+            None => Some(self.source),
+        }
+    }
+
+    fn push(&mut self, spec: Spec) -> Result<()> {
+        match spec.data {
+            Some(spec::Data::Fliph(_)) | Some(spec::Data::Flipv(_)) => self.push_flip(spec),
+            Some(spec::Data::Crop(ref v)) if v.focal.is_none() => self.push_crop(v.clone())?,
+            Some(spec::Data::Resize(ref v)) if is_normal(v) => self.push_resize(v.clone()),
+            Some(spec::Data::Filter(ref v)) if v.filter == filter::Filter::Unspecified as i32 => {}
+            _ => self.append(spec),
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, spec: Spec) {
+        let dims = self.dims();
+        self.specs.push((spec, dims));
+    }
+
+    // 不同方向的翻转可以交换顺序，所以结尾连续的翻转里有同方向的翻转时两者抵消
+    fn push_flip(&mut self, spec: Spec) {
+        let run = self
+            .specs
+            .iter()
+            .rev()
+            .take_while(|(v, _)| {
+                matches!(
+                    v.data,
+                    Some(spec::Data::Fliph(_)) | Some(spec::Data::Flipv(_))
+                )
+            })
+            .count();
+        let start = self.specs.len() - run;
+        match self.specs[start..]
+            .iter()
+            .position(|(v, _)| v.kind() == spec.kind())
+        {
+            Some(i) => {
+                self.specs.remove(start + i);
+            }
+            None => self.append(spec),
+        }
+    }
+
+    // crop尽量往前移，和前面的crop合并
+    fn push_crop(&mut self, crop: Crop) -> Result<()> {
+        if let Some((width, height)) = self.dims() {
+            if crop.x2 > width || crop.y2 > height {
+                return Err(anyhow!(
+                    "crop ({}, {}, {}, {}) is out of the {}x{} image",
+                    crop.x1,
+                    crop.y1,
+                    crop.x2,
+                    crop.y2,
+                    width,
+                    height
+                ));
+            }
+            if (crop.x1, crop.y1, crop.x2, crop.y2) == (0, 0, width, height) {
+                return Ok(());
+            }
+        }
+
+        let mut crop = crop;
+        // crop被移到了这些spec之前，按从后往前的顺序保存
+        let mut moved = Vec::new();
+        while let Some((prev, Some(dims))) = self.specs.last() {
+            match hoist(&crop, prev, *dims) {
+                Some(Hoist::Merge(v)) => crop = v,
+                Some(Hoist::Before(v, prev)) => {
+                    crop = v;
+                    moved.push(prev);
+                }
+                None => break,
+            }
+            self.specs.pop();
+        }
+
+        self.append(Spec {
+            data: Some(spec::Data::Crop(crop)),
+        });
+        for spec in moved.into_iter().rev() {
+            self.append(spec);
+        }
+        Ok(())
+    }
+
+    // 连续的普通resize只保留最后一个，尺寸不变的nearest resize直接去掉
+    fn push_resize(&mut self, resize: Resize) {
+        while let Some((prev, _)) = self.specs.last() {
+            let collapse = matches!(prev.data, Some(spec::Data::Resize(ref v)) if is_normal(v));
+            if !collapse {
+                break;
+            }
+            self.specs.pop();
+        }
+
+        if is_nearest(&resize) && self.dims() == Some((resize.width, resize.height)) {
+            return;
+        }
+        self.append(Spec {
+            data: Some(spec::Data::Resize(resize)),
+        });
+    }
+}
+
+enum Hoist {
+    // 和前面的crop合并成一个
+    Merge(Crop),
+    // 新的crop放到前面的spec之前执行，前面的spec可能需要相应调整
+    Before(Crop, Spec),
+}
+
+// crop能否移到prev之前执行，dims是prev的输入尺寸
+fn hoist(crop: &Crop, prev: &Spec, (width, height): Dims) -> Option<Hoist> {
+    match prev.data {
+        Some(spec::Data::Crop(ref v)) if v.focal.is_none() => Some(Hoist::Merge(Crop {
+            x1: v.x1 + crop.x1,
+            y1: v.y1 + crop.y1,
+            x2: v.x1 + crop.x2,
+            y2: v.y1 + crop.y2,
+            focal: None,
+        })),
+        // 翻转之后的[x1, x2)对应翻转之前的[width - x2, width - x1)
+        Some(spec::Data::Fliph(_)) => Some(Hoist::Before(
+            Crop {
+                x1: width - crop.x2,
+                x2: width - crop.x1,
+                ..crop.clone()
+            },
+            prev.clone(),
+        )),
+        Some(spec::Data::Flipv(_)) => Some(Hoist::Before(
+            Crop {
+                y1: height - crop.y2,
+                y2: height - crop.y1,
+                ..crop.clone()
+            },
+            prev.clone(),
+        )),
+        Some(spec::Data::Resize(ref v)) if is_nearest(v) => {
+            let (x1, x2) = scale_back(width, v.width, crop.x1, crop.x2)?;
+            let (y1, y2) = scale_back(height, v.height, crop.y1, crop.y2)?;
+            let resize = Resize {
+                width: crop.x2 - crop.x1,
+                height: crop.y2 - crop.y1,
+                ..v.clone()
+            };
+            Some(Hoist::Before(
+                Crop {
+                    x1,
+                    y1,
+                    x2,
+                    y2,
+                    focal: None,
+                },
+                Spec {
+                    data: Some(spec::Data::Resize(resize)),
+                },
+            ))
+        }
+        _ => None,
+    }
+}
+
+// nearest resize按整数倍缩放时，输出的每个像素只取自输入里固定位置的一个像素：
+// 缩小r倍时输出的第i个像素取自输入的第i * r + r / 2个，放大k倍时取自第i / k个，
+// 所以先crop出对应的区域再resize，和先resize再crop的结果相同
+// 放大时crop的边界需要是k的倍数，否则对应不到完整的输入像素
+fn scale_back(src: u32, dst: u32, a1: u32, a2: u32) -> Option<(u32, u32)> {
+    if src % dst == 0 {
+        let r = src / dst;
+        Some((a1 * r, a2 * r))
+    } else if dst % src == 0 {
+        let k = dst / src;
+        (a1 % k == 0 && a2 % k == 0).then(|| (a1 / k, a2 / k))
+    } else {
+        None
+    }
+}
+
+fn is_normal(v: &Resize) -> bool {
+    v.rtype == resize::ResizeType::Normal as i32
+}
+
+// Undefined在photon里也是Nearest；线性空间的resize会先转换颜色，不做调整
+fn is_nearest(v: &Resize) -> bool {
+    is_normal(v)
+        && !v.linear
+        && matches!(
+            resize::SampleFilter::from_i32(v.filter),
+            Some(resize::SampleFilter::Nearest) | Some(resize::SampleFilter::Undefined)
+        )
+}
+
+fn output_dims(spec: &Spec, dims: Option<Dims>) -> Option<Dims> {
+    match spec.data {
+        Some(spec::Data::Resize(ref v)) => match resize::ResizeType::from_i32(v.rtype) {
+            // seam carve只在缩小时生效，结果尺寸不确定
+            Some(resize::ResizeType::SeamCarve) => None,
+            _ => Some((v.width, v.height)),
+        },
+        // 有焦点的crop超出图片时会缩小到图片大小
+        Some(spec::Data::Crop(ref v)) if v.focal.is_some() => {
+            dims.map(|(w, h)| (w.min(v.x2 - v.x1), h.min(v.y2 - v.y1)))
+        }
+        Some(spec::Data::Crop(ref v)) => Some((v.x2 - v.x1, v.y2 - v.y1)),
+        // trim的结果取决于图片内容
+        Some(spec::Data::Trim(_)) => None,
+        _ => dims,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Engine, Photon};
+    use image::ImageOutputFormat;
+    use photon_rs::PhotonImage;
+
+    const WIDTH: u32 = 120;
+    const HEIGHT: u32 = 80;
+
+    // 每个像素都不一样的测试图片，位置出错时结果一定不同
+    fn source(width: u32, height: u32) -> PhotonImage {
+        let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.extend_from_slice(&[
+                    (x * 2) as u8,
+                    (y * 3) as u8,
+                    ((x * 7 + y * 13) % 256) as u8,
+                    255,
+                ]);
+            }
+        }
+        PhotonImage::new(pixels, width, height)
+    }
+
+    fn render(specs: &[Spec], width: u32, height: u32) -> Vec<u8> {
+        let mut engine = Photon::from(source(width, height));
+        engine.apply(specs);
+        engine.generate(ImageOutputFormat::Png)
+    }
+
+    // 优化后的结果和原来的逐像素相同，返回优化后的spec链
+    fn assert_equivalent(specs: &[Spec], width: u32, height: u32) -> Vec<Spec> {
+        let optimized = optimize(specs, width, height).unwrap();
+        assert_eq!(
+            render(specs, width, height),
+            render(&optimized, width, height),
+            "{:?} => {:?}",
+            specs,
+            optimized
+        );
+        optimized
+    }
+
+    fn nearest(width: u32, height: u32) -> Spec {
+        Spec::new_resize(width, height, resize::SampleFilter::Nearest)
+    }
+
+    #[test]
+    fn invalid_chains_should_be_rejected() {
+        assert!(validate(&[]).is_err());
+        assert!(validate(&vec![Spec::new_fliph(); MAX_SPECS + 1]).is_err());
+        assert!(validate(&vec![Spec::new_fliph(); MAX_SPECS]).is_ok());
+
+        let invalid = vec![
+            Spec { data: None },
+            Spec::new_resize(0, 100, resize::SampleFilter::Nearest),
+            Spec::new_crop(10, 10, 10, 20),
+            Spec::new_crop_focal(100, 100, Focal::point(1.5, 0.5)),
+            Spec::new_contrast(f32::NAN),
+            Spec::new_overlay("", 0, 0, 1.0, 1.0, overlay::BlendMode::Normal),
+            Spec::new_overlay("a.png", 0, 0, -1.0, 1.0, overlay::BlendMode::Normal),
+            Spec::new_background(0x1000000),
+            Spec::new_jpeg(101, false, output::ChromaSubsampling::Chroma420),
+            Spec::new_png(output::PngCompression::Best, 1),
+            Spec::new_trim(256, 0),
+            Spec {
+                data: Some(spec::Data::Filter(Filter { filter: 42 })),
+            },
+        ];
+        for spec in invalid {
+            assert!(validate(&[spec.clone()]).is_err(), "{:?}", spec);
+        }
+
+        // 尺寸已知时检查crop是否越界
+        let specs = vec![nearest(60, 40), Spec::new_crop(0, 0, 61, 10)];
+        assert!(validate(&specs).is_ok());
+        assert!(optimize(&specs, WIDTH, HEIGHT).is_err());
+    }
+
+    #[test]
+    fn flips_should_cancel_out() {
+        let specs = vec![Spec::new_fliph(), Spec::new_flipv(), Spec::new_fliph()];
+        assert_eq!(
+            assert_equivalent(&specs, WIDTH, HEIGHT),
+            vec![Spec::new_flipv()]
+        );
+
+        let specs = vec![Spec::new_flipv(), Spec::new_flipv()];
+        assert!(assert_equivalent(&specs, WIDTH, HEIGHT).is_empty());
+
+        // 中间隔着其他处理时不能抵消
+        let specs = vec![
+            Spec::new_fliph(),
+            Spec::new_contrast(20.0),
+            Spec::new_fliph(),
+        ];
+        assert_eq!(assert_equivalent(&specs, WIDTH, HEIGHT), specs);
+    }
+
+    #[test]
+    fn crop_should_run_before_flip_and_nearest_resize() {
+        // 缩小2倍
+        let specs = vec![
+            nearest(60, 40),
+            Spec::new_fliph(),
+            Spec::new_crop(10, 5, 40, 25),
+        ];
+        let optimized = assert_equivalent(&specs, WIDTH, HEIGHT);
+        assert_eq!(
+            optimized,
+            vec![
+                Spec::new_crop(40, 10, 100, 50),
+                nearest(30, 20),
+                Spec::new_fliph()
+            ]
+        );
+
+        // 缩小3倍，垂直方向翻转
+        let specs = vec![
+            nearest(40, 20),
+            Spec::new_flipv(),
+            Spec::new_crop(1, 2, 38, 19),
+        ];
+        let optimized = assert_equivalent(&specs, WIDTH, 60);
+        assert_eq!(optimized[0], Spec::new_crop(3, 3, 114, 54));
+
+        // 放大3倍，crop边界是3的倍数
+        let specs = vec![nearest(90, 60), Spec::new_crop(3, 6, 30, 27)];
+        let optimized = assert_equivalent(&specs, 30, 20);
+        assert_eq!(
+            optimized,
+            vec![Spec::new_crop(1, 2, 10, 9), nearest(27, 21)]
+        );
+
+        // 放大时边界不是倍数、非整数倍缩放、其他filter都不调整顺序
+        let cases = vec![
+            (vec![nearest(90, 60), Spec::new_crop(1, 6, 30, 27)], 30, 20),
+            (
+                vec![nearest(50, 50), Spec::new_crop(10, 10, 30, 30)],
+                WIDTH,
+                HEIGHT,
+            ),
+            (
+                vec![
+                    Spec::new_resize(60, 40, resize::SampleFilter::CatmullRom),
+                    Spec::new_crop(10, 5, 40, 25),
+                ],
+                WIDTH,
+                HEIGHT,
+            ),
+            (
+                vec![nearest(60, 40).with_linear(), Spec::new_crop(10, 5, 40, 25)],
+                WIDTH,
+                HEIGHT,
+            ),
+        ];
+        for (specs, width, height) in cases {
+            assert_eq!(assert_equivalent(&specs, width, height), specs);
+        }
+    }
+
+    #[test]
+    fn crops_should_be_merged_and_noops_removed() {
+        let specs = vec![
+            Spec::new_crop(10, 10, 110, 70),
+            Spec::new_filter(filter::Filter::Unspecified),
+            Spec::new_crop(5, 5, 50, 40),
+        ];
+        assert_eq!(
+            assert_equivalent(&specs, WIDTH, HEIGHT),
+            vec![Spec::new_crop(15, 15, 60, 50)]
+        );
+
+        let specs = vec![
+            Spec::new_crop(0, 0, WIDTH, HEIGHT),
+            nearest(WIDTH, HEIGHT),
+            Spec::new_contrast(30.0),
+        ];
+        assert_eq!(
+            assert_equivalent(&specs, WIDTH, HEIGHT),
+            vec![Spec::new_contrast(30.0)]
+        );
+
+        // 有焦点的crop位置取决于图片内容，不做调整
+        let specs = vec![
+            Spec::new_fliph(),
+            Spec::new_crop_focal(40, 40, Focal::auto()),
+        ];
+        assert_eq!(assert_equivalent(&specs, WIDTH, HEIGHT), specs);
+
+        // trim之后尺寸未知，crop不能移到trim之前，也不会被当作完整的crop去掉
+        let specs = vec![
+            Spec::new_fliph(),
+            Spec::new_trim(0, 0),
+            Spec::new_crop(0, 0, WIDTH, HEIGHT),
+        ];
+        assert_eq!(assert_equivalent(&specs, WIDTH, HEIGHT), specs);
+    }
+
+    #[test]
+    fn consecutive_resizes_should_keep_the_last() {
+        let last = Spec::new_resize(48, 32, resize::SampleFilter::Triangle);
+        let specs = vec![
+            Spec::new_resize(1000, 1000, resize::SampleFilter::Lanczos3),
+            nearest(10, 10),
+            last.clone(),
+        ];
+        let optimized = optimize(&specs, WIDTH, HEIGHT).unwrap();
+        assert_eq!(optimized, vec![last.clone()]);
+
+        // 输出尺寸不变
+        let a = image::load_from_memory(&render(&specs, WIDTH, HEIGHT)).unwrap();
+        let b = image::load_from_memory(&render(&optimized, WIDTH, HEIGHT)).unwrap();
+        assert_eq!(a.to_rgba8().dimensions(), b.to_rgba8().dimensions());
+
+        // cover和seam carve会裁掉内容，不能合并
+        let cover = Spec::new_resize_cover(60, 60, resize::SampleFilter::Nearest, Focal::center());
+        let specs = vec![cover, last];
+        assert_eq!(optimize(&specs, WIDTH, HEIGHT).unwrap(), specs);
+    }
+
+    #[test]
+    fn encoding_specs_should_keep_the_last() {
+        let specs = vec![
+            Spec::new_background(0xff0000),
+            Spec::new_png(output::PngCompression::Fast, 0),
+            Spec::new_fliph(),
+            Spec::new_background(0x00ff00),
+            Spec::new_fliph(),
+            Spec::new_png(output::PngCompression::Best, 16),
+        ];
+        let optimized = optimize(&specs, WIDTH, HEIGHT).unwrap();
+        assert_eq!(
+            optimized,
+            vec![
+                Spec::new_background(0x00ff00),
+                Spec::new_png(output::PngCompression::Best, 16)
+            ]
+        );
+
+        // 按spec里的Output编码，结果仍然相同
+        let generate = |specs: &[Spec]| {
+            let mut engine = Photon::from(source(WIDTH, HEIGHT));
+            engine.apply(specs);
+            let format = engine.format();
+            engine.generate(format)
+        };
+        assert_eq!(generate(&specs), generate(&optimized));
+    }
+}
@@ -1,38 +1,68 @@
-use super::{Engine, SpecTransform};
+use super::{
+    blend::blend_onto,
+    color::{decode, flatten, load, resize_linear},
+    encoder::{encode_jpeg, encode_png, DEFAULT_QUALITY},
+    focal::{focal_point, place_window},
+    trim::{content_bounds, pad},
+    Engine, Placeholder, SpecTransform,
+};
 use crate::pb::*;
 use anyhow::Result;
 use bytes::Bytes;
 use image::{DynamicImage, ImageBuffer, ImageOutputFormat};
 use lazy_static::lazy_static;
-use photon_rs::{
-    effects, filters, multiple, native::open_image_from_bytes, transform, PhotonImage,
-};
-use std::convert::TryFrom;
+use photon_rs::{effects, filters, multiple, transform, PhotonImage};
+use std::{collections::HashMap, convert::TryFrom};
+use tracing::info_span;
 
 lazy_static!{
     // 预先把水印文件加载为静态变量
     static ref WATERMARK: PhotonImage = {
         // 在编译的时候,include_bytes!宏会直接把文件计入编译后的二进制
         let data = include_bytes!("../../rust-logo.jpeg");
-        let watermark = open_image_from_bytes(data).unwrap();
+        let watermark = decode(data).unwrap();
         transform::resize(&watermark, 64, 64, transform::SamplingFilter::Nearest)
     };
 }
 
-pub struct Photon(PhotonImage);
+// 默认用白色合成JPEG这类不支持透明的格式
+const DEFAULT_BACKGROUND: [u8; 3] = [255, 255, 255];
+
+// 第二个字段保存Overlay用到的其他图片，key是图片的url
+// 第三个字段是输出不透明格式时合成alpha用的背景色
+// 第四个字段是输出的编码参数
+pub struct Photon(PhotonImage, HashMap<String, PhotonImage>, [u8; 3], Output);
+
+impl From<PhotonImage> for Photon {
+    fn from(img: PhotonImage) -> Self {
+        Self(img, HashMap::new(), DEFAULT_BACKGROUND, Output::default())
+    }
+}
 
 // 从Bytes转换成Photon结构
 impl TryFrom<Bytes> for Photon {
     type Error = anyhow::Error;
 
     fn try_from(data: Bytes) -> Result<Self, Self::Error> {
-        Ok(Self(open_image_from_bytes(&data)?))
+        Ok(decode(&data)?.into())
+    }
+}
+
+impl Photon {
+    // 解码源图片，返回engine和接下来需要apply的spec
+    // SVG直接按第一个Resize的目标尺寸栅格化，保持清晰，这个Resize可能会被去掉或者换成crop；
+    // 其他格式和try_from相同，spec不变
+    pub fn load(data: &[u8], specs: &[Spec]) -> Result<(Self, Vec<Spec>)> {
+        let (img, specs) = load(data, specs)?;
+        Ok((img.into(), specs))
     }
 }
 
 impl Engine for Photon {
     fn apply(&mut self, specs: &[Spec]) {
         for spec in specs.iter() {
+            // 每个spec一个span，关闭时可以看到处理耗时
+            let _span = info_span!("spec", kind = spec.kind()).entered();
             match spec.data {
                 Some(spec::Data::Crop(ref v)) => self.transform(v),
                 Some(spec::Data::Contrast(ref v)) => self.transform(v),
@@ -41,19 +71,51 @@ impl Engine for Photon {
                 Some(spec::Data::Flipv(ref v)) => self.transform(v),
                 Some(spec::Data::Resize(ref v)) => self.transform(v),
                 Some(spec::Data::Watermark(ref v)) => self.transform(v),
+                Some(spec::Data::Overlay(ref v)) => self.transform(v),
+                Some(spec::Data::Background(ref v)) => self.transform(v),
+                Some(spec::Data::Output(ref v)) => self.transform(v),
+                Some(spec::Data::Trim(ref v)) => self.transform(v),
                 _ => {}
             }
         }
     }
 
+    fn add_source(&mut self, url: &str, data: Bytes) -> Result<()> {
+        self.1.insert(url.to_owned(), decode(&data)?);
+        Ok(())
+    }
+
+    fn format(&self) -> ImageOutputFormat {
+        match output::Format::from_i32(self.3.format) {
+            Some(output::Format::Png) => ImageOutputFormat::Png,
+            _ => ImageOutputFormat::Jpeg(match self.3.quality {
+                0 => DEFAULT_QUALITY,
+                v => v.min(100) as u8,
+            }),
+        }
+    }
+
     fn generate(self, format: ImageOutputFormat) -> Vec<u8> {
-        image_to_buf(self.0, format)
+        image_to_buf(self.0, format, self.2, &self.3)
     }
 }
 
 impl SpecTransform<&Crop> for Photon {
     fn transform(&mut self, op: &Crop) {
-        let img = transform::crop(&mut self.0, op.x1, op.y1, op.x2, op.y2);
+        let img = match op.focal {
+            // 有焦点时只用坐标算出截取的大小，窗口围绕焦点放置
+            Some(ref focal) => {
+                let (x1, y1, x2, y2) = place_window(
+                    self.0.get_width(),
+                    self.0.get_height(),
+                    op.x2.saturating_sub(op.x1),
+                    op.y2.saturating_sub(op.y1),
+                    focal_point(&self.0, focal),
+                );
+                transform::crop(&mut self.0, x1, y1, x2, y2)
+            }
+            None => transform::crop(&mut self.0, op.x1, op.y1, op.x2, op.y2),
+        };
         self.0 = img;
     }
 }
@@ -89,6 +151,12 @@ impl SpecTransform<&Filter> for Photon {
 impl SpecTransform<&Resize> for Photon {
     fn transform(&mut self, op: &Resize) {
         let img = match resize::ResizeType::from_i32(op.rtype).unwrap() {
+            resize::ResizeType::Normal if op.linear => resize_linear(
+                &self.0,
+                op.width,
+                op.height,
+                resize::SampleFilter::from_i32(op.filter).unwrap().into(),
+            ),
             resize::ResizeType::Normal => transform::resize(
                 &mut self.0,
                 op.width,
@@ -98,26 +166,360 @@ impl SpecTransform<&Resize> for Photon {
             resize::ResizeType::SeamCarve => {
                 transform::seam_carve(&mut self.0, op.width, op.height)
             }
+            resize::ResizeType::Cover => cover(
+                &self.0,
+                op.width,
+                op.height,
+                resize::SampleFilter::from_i32(op.filter).unwrap().into(),
+                op.focal.as_ref(),
+                op.linear,
+            ),
         };
         self.0 = img;
     }
 }
 
+impl SpecTransform<&Trim> for Photon {
+    fn transform(&mut self, op: &Trim) {
+        let (width, height) = (self.0.get_width(), self.0.get_height());
+        let tolerance = op.tolerance.min(255) as u8;
+        // 整张图都是边框颜色时不做处理
+        if let Some(bounds) = content_bounds(&self.0.get_raw_pixels(), width, tolerance) {
+            let (x1, y1, x2, y2) = pad(bounds, op.padding, width, height);
+            self.0 = transform::crop(&mut self.0, x1, y1, x2, y2);
+        }
+    }
+}
+
 impl SpecTransform<&Watermark> for Photon {
     fn transform(&mut self, op: &Watermark) {
         multiple::watermark(&mut self.0, &WATERMARK, op.x, op.y);
     }
 }
 
-fn image_to_buf(img: PhotonImage, format: ImageOutputFormat) -> Vec<u8> {
+impl SpecTransform<&Background> for Photon {
+    fn transform(&mut self, op: &Background) {
+        let [_, r, g, b] = op.color.to_be_bytes();
+        self.2 = [r, g, b];
+    }
+}
+
+// 只记录编码参数，在generate时使用
+impl SpecTransform<&Output> for Photon {
+    fn transform(&mut self, op: &Output) {
+        self.3 = op.clone();
+    }
+}
+
+impl SpecTransform<&Overlay> for Photon {
+    fn transform(&mut self, op: &Overlay) {
+        // 没有通过add_source加载的图片直接忽略
+        let top = match self.1.get(&op.url) {
+            Some(v) => v,
+            None => return,
+        };
+
+        let scaled;
+        let top = if op.scale > 0.0 && op.scale != 1.0 {
+            let width = ((top.get_width() as f32 * op.scale).round() as u32).max(1);
+            let height = ((top.get_height() as f32 * op.scale).round() as u32).max(1);
+            scaled = transform::resize(top, width, height, transform::SamplingFilter::Triangle);
+            &scaled
+        } else {
+            top
+        };
+
+        let opacity = if op.opacity > 0.0 { op.opacity } else { 1.0 };
+        let blend = overlay::BlendMode::from_i32(op.blend).unwrap_or(overlay::BlendMode::Normal);
+
+        let (width, height) = (self.0.get_width(), self.0.get_height());
+        let mut pixels = self.0.get_raw_pixels();
+        blend_onto(
+            &mut pixels,
+            width,
+            height,
+            &top.get_raw_pixels(),
+            top.get_width(),
+            top.get_height(),
+            (op.x, op.y),
+            opacity,
+            blend,
+        );
+        self.0 = PhotonImage::new(pixels, width, height);
+    }
+}
+
+impl Placeholder for Photon {
+    fn blurhash(&self, components_x: u32, components_y: u32) -> Result<String> {
+        // BlurHash只需要很少的像素，先缩小再计算，避免大图耗费过多CPU
+        let small = thumbnail(&self.0, 32);
+        let hash = blurhash::encode(
+            components_x,
+            components_y,
+            small.get_width(),
+            small.get_height(),
+            &small.get_raw_pixels(),
+        )?;
+        Ok(hash)
+    }
+
+    fn lqip(&self, width: u32) -> String {
+        let small = thumbnail(&self.0, width);
+        let data = image_to_buf(
+            small,
+            ImageOutputFormat::Jpeg(40),
+            self.2,
+            &Output::default(),
+        );
+        format!("data:image/jpeg;base64,{}", base64::encode(data))
+    }
+
+    fn palette(&self, n: usize) -> Vec<String> {
+        let small = thumbnail(&self.0, 64);
+        let pixels = small.get_raw_pixels();
+
+        // 每个通道只取高4位，把颜色归到4096个桶里，再对桶内的像素求平均
+        let mut buckets: HashMap<u16, [u64; 4]> = HashMap::new();
+        for p in pixels.chunks_exact(4) {
+            // 跳过基本透明的像素
+            if p[3] < 128 {
+                continue;
+            }
+            let key = ((p[0] as u16 >> 4) << 8) | ((p[1] as u16 >> 4) << 4) | (p[2] as u16 >> 4);
+            let bucket = buckets.entry(key).or_default();
+            bucket[0] += p[0] as u64;
+            bucket[1] += p[1] as u64;
+            bucket[2] += p[2] as u64;
+            bucket[3] += 1;
+        }
+
+        let mut buckets: Vec<_> = buckets.into_values().collect();
+        buckets.sort_by(|a, b| b[3].cmp(&a[3]));
+        buckets
+            .into_iter()
+            .take(n)
+            .map(|[r, g, b, count]| format!("#{:02x}{:02x}{:02x}", r / count, g / count, b / count))
+            .collect()
+    }
+}
+
+// 等比缩放到刚好覆盖width x height，再围绕焦点裁掉多出来的部分
+fn cover(
+    img: &PhotonImage,
+    width: u32,
+    height: u32,
+    filter: transform::SamplingFilter,
+    focal: Option<&Focal>,
+    linear: bool,
+) -> PhotonImage {
+    let (w, h) = (img.get_width() as f32, img.get_height() as f32);
+    let scale = (width as f32 / w).max(height as f32 / h);
+    let scaled_w = ((w * scale).round() as u32).max(width);
+    let scaled_h = ((h * scale).round() as u32).max(height);
+
+    // 焦点用比例表示，在原图上计算，缩放后位置不变
+    let point = match focal {
+        Some(focal) => focal_point(img, focal),
+        None => (0.5, 0.5),
+    };
+
+    let mut scaled = match linear {
+        true => resize_linear(img, scaled_w, scaled_h, filter),
+        false => transform::resize(img, scaled_w, scaled_h, filter),
+    };
+    let (x1, y1, x2, y2) = place_window(scaled_w, scaled_h, width, height, point);
+    transform::crop(&mut scaled, x1, y1, x2, y2)
+}
+
+// 等比缩小图片，宽度不超过max_width
+fn thumbnail(img: &PhotonImage, max_width: u32) -> PhotonImage {
+    let (width, height) = (img.get_width(), img.get_height());
+    if width <= max_width {
+        return img.clone();
+    }
+    let new_height = (height * max_width / width).max(1);
+    transform::resize(
+        img,
+        max_width,
+        new_height,
+        transform::SamplingFilter::Triangle,
+    )
+}
+
+// 不支持alpha的格式先和背景色合成，避免透明区域变成黑色或者出现杂色
+// JPEG和PNG使用output里的编码参数，其他格式直接交给image处理
+fn image_to_buf(
+    img: PhotonImage,
+    format: ImageOutputFormat,
+    background: [u8; 3],
+    output: &Output,
+) -> Vec<u8> {
     let raw_pixels = img.get_raw_pixels();
     let width = img.get_width();
     let height = img.get_height();
 
-    let img_buffer = ImageBuffer::from_vec(width, height, raw_pixels).unwrap();
-    let dynimage = DynamicImage::ImageRgba8(img_buffer);
+    match format {
+        ImageOutputFormat::Jpeg(quality) => {
+            let pixels = flatten(&raw_pixels, background);
+            encode_jpeg(&pixels, width, height, quality, output).unwrap()
+        }
+        ImageOutputFormat::Png => encode_png(&raw_pixels, width, height, output).unwrap(),
+        _ => {
+            let dynimage = match format {
+                ImageOutputFormat::Bmp | ImageOutputFormat::Pnm(_) => {
+                    let pixels = flatten(&raw_pixels, background);
+                    DynamicImage::ImageRgb8(ImageBuffer::from_vec(width, height, pixels).unwrap())
+                }
+                _ => DynamicImage::ImageRgba8(
+                    ImageBuffer::from_vec(width, height, raw_pixels).unwrap(),
+                ),
+            };
+
+            let mut buffer = Vec::with_capacity(32768);
+            dynimage.write_to(&mut buffer, format).unwrap();
+            buffer
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::focal::subject_image;
+
+    // 生成一张左边红色、右边蓝色的图片，红色占3/4
+    // 宽度不超过64时palette不会缩放，颜色可以精确比较
+    fn red_and_blue(width: u32, height: u32) -> Photon {
+        let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+        for _ in 0..height {
+            for x in 0..width {
+                if x < width * 3 / 4 {
+                    pixels.extend_from_slice(&[255, 0, 0, 255]);
+                } else {
+                    pixels.extend_from_slice(&[0, 0, 255, 255]);
+                }
+            }
+        }
+        PhotonImage::new(pixels, width, height).into()
+    }
+
+    // 检查图片里是否有棋盘格主体：主体区域的亮度非黑即白，背景是灰色
+    fn has_subject(img: &PhotonImage) -> bool {
+        img.get_raw_pixels()
+            .chunks_exact(4)
+            .any(|p| p[0] < 32 || p[0] > 224)
+    }
+
+    #[test]
+    fn cover_should_keep_focal_region() {
+        let img = subject_image(200, 100, (150, 30, 190, 70));
+
+        // 以中心为焦点时主体会被裁掉
+        let mut centered = Photon::from(img.clone());
+        centered.apply(&[Spec::new_resize_cover(
+            50,
+            50,
+            resize::SampleFilter::Nearest,
+            Focal::center(),
+        )]);
+        assert_eq!((centered.0.get_width(), centered.0.get_height()), (50, 50));
+        assert!(!has_subject(&centered.0));
+
+        for focal in [Focal::auto(), Focal::point(0.85, 0.5)] {
+            let mut engine = Photon::from(img.clone());
+            engine.apply(&[Spec::new_resize_cover(
+                50,
+                50,
+                resize::SampleFilter::Nearest,
+                focal,
+            )]);
+            assert_eq!((engine.0.get_width(), engine.0.get_height()), (50, 50));
+            assert!(has_subject(&engine.0));
+        }
+    }
+
+    #[test]
+    fn focal_crop_should_keep_focal_region() {
+        let img = subject_image(200, 100, (10, 10, 40, 40));
+
+        let mut engine = Photon::from(img.clone());
+        engine.apply(&[Spec::new_crop_focal(60, 60, Focal::auto())]);
+        assert_eq!((engine.0.get_width(), engine.0.get_height()), (60, 60));
+        assert!(has_subject(&engine.0));
+
+        // 窗口比图片大时缩小到图片大小
+        let mut engine = Photon::from(img);
+        engine.apply(&[Spec::new_crop_focal(300, 60, Focal::center())]);
+        assert_eq!((engine.0.get_width(), engine.0.get_height()), (200, 60));
+    }
+
+    #[test]
+    fn overlay_should_use_added_source() {
+        let mut buf = Vec::new();
+        DynamicImage::new_rgba8(4, 4)
+            .write_to(&mut buf, ImageOutputFormat::Png)
+            .unwrap();
+        let badge = PhotonImage::new([0, 255, 0, 255].repeat(4), 2, 2);
+
+        let mut engine = red_and_blue(8, 8);
+        engine.1.insert("badge".into(), badge);
+        engine.apply(&[
+            Spec::new_overlay("badge", 0, 0, 2.0, 0.0, overlay::BlendMode::Normal),
+            // 没有加载的图片被忽略
+            Spec::new_overlay("missing", 0, 0, 0.0, 0.0, overlay::BlendMode::Normal),
+        ]);
 
-    let mut buffer = Vec::with_capacity(32768);
-    dynimage.write_to(&mut buffer, format).unwrap();
-    buffer
-}
\ No newline at end of file
+        let pixels = engine.0.get_raw_pixels();
+        // 放大两倍后覆盖了左上角4x4的区域
+        assert_eq!(&pixels[..4], &[0, 255, 0, 255]);
+        assert_eq!(&pixels[(3 * 8 + 3) * 4..(3 * 8 + 4) * 4], &[0, 255, 0, 255]);
+        assert_eq!(&pixels[(4 * 8 + 4) * 4..(4 * 8 + 5) * 4], &[255, 0, 0, 255]);
+
+        let mut engine = red_and_blue(8, 8);
+        assert!(engine.add_source("png", Bytes::from(buf)).is_ok());
+        assert!(engine.add_source("bad", Bytes::from_static(b"xx")).is_err());
+    }
+
+    #[test]
+    fn jpeg_should_flatten_alpha_on_background() {
+        // 左半边不透明的红色，右半边完全透明
+        let pixels: Vec<u8> = (0..16 * 16)
+            .flat_map(|i| match i % 16 < 8 {
+                true => [255, 0, 0, 255],
+                false => [0, 0, 0, 0],
+            })
+            .collect();
+        let source = || Photon::from(PhotonImage::new(pixels.clone(), 16, 16));
+        let pixel = |data: Vec<u8>, x: u32| {
+            let img = image::load_from_memory(&data).unwrap().to_rgb8();
+            img.get_pixel(x, 8).0
+        };
+
+        let data = source().generate(ImageOutputFormat::Jpeg(95));
+        let [r, g, b] = pixel(data, 15);
+        assert!(r > 240 && g > 240 && b > 240, "{:?}", [r, g, b]);
+
+        let mut engine = source();
+        engine.apply(&[Spec::new_background(0x0000ff)]);
+        let [r, g, b] = pixel(engine.generate(ImageOutputFormat::Jpeg(95)), 15);
+        assert!(r < 16 && g < 16 && b > 240, "{:?}", [r, g, b]);
+        let [r, _, _] = pixel(source().generate(ImageOutputFormat::Jpeg(95)), 0);
+        assert!(r > 240);
+
+        // png保留alpha
+        let data = source().generate(ImageOutputFormat::Png);
+        let img = image::load_from_memory(&data).unwrap().to_rgba8();
+        assert_eq!(img.get_pixel(15, 8).0[3], 0);
+    }
+
+    #[test]
+    fn placeholders_should_work() {
+        let engine = red_and_blue(64, 32);
+
+        assert_eq!(engine.palette(2), vec!["#ff0000", "#0000ff"]);
+        assert!(engine.lqip(16).starts_with("data:image/jpeg;base64,"));
+        // 4x3个分量的BlurHash长度固定为 1 + 1 + 4 + 2 * (4 * 3 - 1)
+        assert_eq!(engine.blurhash(4, 3).unwrap().len(), 28);
+        assert!(engine.blurhash(10, 3).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+// SVG输入：用resvg栅格化成位图之后再交给后面的spec处理
+// 矢量图先栅格化成小图再放大会变模糊，所以尽量直接按第一个Resize的目标尺寸栅格化
+//
+// 安全：usvg不支持<script>，也不会发起网络请求；<image>只接受data: url内嵌的图片，
+// 引用本地文件的路径一律忽略。没有加载字体，文字需要转换成路径才会显示
+use crate::pb::*;
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, RgbaImage};
+
+// 只看开头这么多字节判断是不是SVG
+const SNIFF_LEN: usize = 1024;
+
+// 开头是XML声明、注释、DOCTYPE或者<svg，并且能看到<svg标签
+pub(crate) fn is_svg(data: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&data[..data.len().min(SNIFF_LEN)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    let prolog = ["<?xml", "<!--", "<!DOCTYPE svg", "<svg"];
+    prolog.iter().any(|v| head.starts_with(v)) && head.contains("<svg")
+}
+
+// SVG自身声明的尺寸，没有width/height时使用viewBox的大小
+pub(crate) fn dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    Ok(intrinsic_size(&parse(data)?))
+}
+
+// 按SVG自身的尺寸栅格化
+pub(crate) fn decode(data: &[u8]) -> Result<DynamicImage> {
+    let tree = parse(data)?;
+    let (width, height) = intrinsic_size(&tree);
+    render(&tree, width, height)
+}
+
+// 按spec决定的尺寸栅格化，返回图片和接下来需要apply的spec
+pub(crate) fn load(data: &[u8], specs: &[Spec]) -> Result<(DynamicImage, Vec<Spec>)> {
+    let tree = parse(data)?;
+    let ((width, height), specs) = plan(specs, intrinsic_size(&tree));
+    Ok((render(&tree, width, height)?, specs))
+}
+
+// 第一个Resize之前只有不依赖像素坐标和尺寸的spec时，直接按Resize的目标尺寸栅格化：
+// - 普通resize：栅格化成目标尺寸，这个resize已经完成，不再保留
+// - cover：等比栅格化到刚好覆盖目标尺寸，再换成围绕焦点的crop
+// 其他情况（比如之前有crop、overlay）按SVG自身的尺寸栅格化，spec不变
+fn plan(specs: &[Spec], (width, height): (u32, u32)) -> ((u32, u32), Vec<Spec>) {
+    let unchanged = ((width, height), specs.to_vec());
+    let i = match specs.iter().position(|spec| !is_size_independent(spec)) {
+        Some(i) => i,
+        None => return unchanged,
+    };
+    let resize = match specs[i].data {
+        Some(spec::Data::Resize(ref v)) => v,
+        _ => return unchanged,
+    };
+
+    let (size, replacement) = match resize::ResizeType::from_i32(resize.rtype) {
+        Some(resize::ResizeType::Normal) => ((resize.width, resize.height), None),
+        // 和photon里cover的计算方式相同
+        Some(resize::ResizeType::Cover) => {
+            let (w, h) = (width as f32, height as f32);
+            let scale = (resize.width as f32 / w).max(resize.height as f32 / h);
+            let size = (
+                ((w * scale).round() as u32).max(resize.width),
+                ((h * scale).round() as u32).max(resize.height),
+            );
+            let focal = resize.focal.clone().unwrap_or_else(Focal::center);
+            let crop = Spec::new_crop_focal(resize.width, resize.height, focal);
+            (size, Some(crop))
+        }
+        // seam carve需要按内容删除像素，保持原来的处理方式
+        _ => return unchanged,
+    };
+
+    let mut specs = specs.to_vec();
+    match replacement {
+        Some(spec) => specs[i] = spec,
+        None => {
+            specs.remove(i);
+        }
+    }
+    (size, specs)
+}
+
+// 在栅格化尺寸不同时仍然有相同效果的spec
+fn is_size_independent(spec: &Spec) -> bool {
+    matches!(
+        spec.data,
+        Some(spec::Data::Fliph(_))
+            | Some(spec::Data::Flipv(_))
+            | Some(spec::Data::Contrast(_))
+            | Some(spec::Data::Filter(_))
+            | Some(spec::Data::Background(_))
+            | Some(spec::Data::Output(_))
+    )
+}
+
+fn options() -> usvg::Options {
+    usvg::Options {
+        resources_dir: None,
+        image_href_resolver: usvg::ImageHrefResolver {
+            resolve_string: Box::new(|_, _| None),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+fn parse(data: &[u8]) -> Result<usvg::Tree> {
+    Ok(usvg::Tree::from_data(data, &options())?)
+}
+
+fn intrinsic_size(tree: &usvg::Tree) -> (u32, u32) {
+    let size = tree.size.to_screen_size();
+    (size.width(), size.height())
+}
+
+// 拉伸到width x height，和普通resize的行为一致
+fn render(tree: &usvg::Tree, width: u32, height: u32) -> Result<DynamicImage> {
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| anyhow!("svg size {}x{} is invalid", width, height))?;
+    let transform = tiny_skia::Transform::from_scale(
+        width as f32 / tree.size.width() as f32,
+        height as f32 / tree.size.height() as f32,
+    );
+    resvg::render(tree, usvg::FitTo::Original, transform, pixmap.as_mut())
+        .ok_or_else(|| anyhow!("failed to render svg"))?;
+
+    // tiny-skia使用预乘alpha，转换回普通的RGBA
+    let pixels: Vec<u8> = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(width, height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| anyhow!("svg buffer doesn't match {}x{}", width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    // 20x10的SVG，左半边红色，右半边蓝色
+    const HALVES: &[u8] = br##"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="20" height="10" viewBox="0 0 20 10">
+  <rect x="0" y="0" width="10" height="10" fill="#ff0000"/>
+  <rect x="10" y="0" width="10" height="10" fill="#0000ff"/>
+</svg>"##;
+
+    fn pixel(img: &DynamicImage, x: u32, y: u32) -> [u8; 4] {
+        img.get_pixel(x, y).0
+    }
+
+    #[test]
+    fn svg_should_be_sniffed() {
+        assert!(is_svg(HALVES));
+        assert!(is_svg(
+            b"\xef\xbb\xbf  <svg xmlns=\"http://www.w3.org/2000/svg\"/>"
+        ));
+        assert!(is_svg(b"<!-- logo -->\n<svg></svg>"));
+        assert!(!is_svg(
+            b"<!DOCTYPE html><html><body><svg></svg></body></html>"
+        ));
+        assert!(!is_svg(b"<?xml version=\"1.0\"?><rss></rss>"));
+        assert!(!is_svg(b"not an image"));
+    }
+
+    #[test]
+    fn svg_should_render_at_intrinsic_size() {
+        assert_eq!(dimensions(HALVES).unwrap(), (20, 10));
+        let img = decode(HALVES).unwrap();
+        assert_eq!(img.dimensions(), (20, 10));
+        assert_eq!(pixel(&img, 2, 5), [255, 0, 0, 255]);
+        assert_eq!(pixel(&img, 17, 5), [0, 0, 255, 255]);
+        assert!(decode(b"<svg").is_err());
+    }
+
+    #[test]
+    fn first_resize_should_decide_raster_size() {
+        let specs = vec![
+            Spec::new_fliph(),
+            Spec::new_resize(200, 50, resize::SampleFilter::Lanczos3),
+            Spec::new_resize(100, 100, resize::SampleFilter::Nearest),
+        ];
+        let (img, rest) = load(HALVES, &specs).unwrap();
+        // 非等比拉伸到目标尺寸，边界处依然是纯色
+        assert_eq!(img.dimensions(), (200, 50));
+        assert_eq!(pixel(&img, 99, 25), [255, 0, 0, 255]);
+        assert_eq!(pixel(&img, 100, 25), [0, 0, 255, 255]);
+        assert_eq!(rest, vec![specs[0].clone(), specs[2].clone()]);
+
+        let cover = Spec::new_resize_cover(30, 30, resize::SampleFilter::Nearest, Focal::auto());
+        let (img, rest) = load(HALVES, &[cover]).unwrap();
+        assert_eq!(img.dimensions(), (60, 30));
+        assert_eq!(rest, vec![Spec::new_crop_focal(30, 30, Focal::auto())]);
+
+        // Resize之前有依赖坐标的spec时按自身尺寸栅格化
+        let specs = vec![
+            Spec::new_crop(0, 0, 10, 10),
+            Spec::new_resize(40, 40, resize::SampleFilter::Nearest),
+        ];
+        let (img, rest) = load(HALVES, &specs).unwrap();
+        assert_eq!(img.dimensions(), (20, 10));
+        assert_eq!(rest, specs);
+    }
+
+    #[test]
+    fn external_images_should_not_be_loaded() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/formats/logo.png");
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="16" height="16">
+  <script>document.body.remove()</script>
+  <image x="0" y="0" width="16" height="16" xlink:href="{}"/>
+  <image x="0" y="0" width="16" height="16" xlink:href="file://{}"/>
+</svg>"#,
+            path, path
+        );
+        let img = decode(svg.as_bytes()).unwrap();
+        assert!(img.to_rgba8().pixels().all(|p| p.0[3] == 0));
+    }
+}
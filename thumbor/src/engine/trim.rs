@@ -0,0 +1,141 @@
+// 检测四周颜色一致的边框：以左上角的像素作为边框颜色，
+// 和它差别超过tolerance的像素都算作内容，内容的外接矩形就是trim之后保留的区域
+
+// pixels是RGBA，每行width个像素，返回内容区域(x1, y1, x2, y2)，整张图都是边框颜色时返回None
+pub(crate) fn content_bounds(
+    pixels: &[u8],
+    width: u32,
+    tolerance: u8,
+) -> Option<(u32, u32, u32, u32)> {
+    let border = pixels.get(..4)?;
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (i, p) in pixels.chunks_exact(4).enumerate() {
+        if is_border(p, border, tolerance) {
+            continue;
+        }
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        bounds = Some(match bounds {
+            Some((x1, y1, x2, y2)) => (x1.min(x), y1.min(y), x2.max(x + 1), y2.max(y + 1)),
+            None => (x, y, x + 1, y + 1),
+        });
+    }
+    bounds
+}
+
+// 把内容区域向外扩展padding，不超出图片
+pub(crate) fn pad(
+    (x1, y1, x2, y2): (u32, u32, u32, u32),
+    padding: u32,
+    width: u32,
+    height: u32,
+) -> (u32, u32, u32, u32) {
+    (
+        x1.saturating_sub(padding),
+        y1.saturating_sub(padding),
+        x2.saturating_add(padding).min(width),
+        y2.saturating_add(padding).min(height),
+    )
+}
+
+// 透明的边框只比较alpha，完全透明的像素RGB是什么都可以
+fn is_border(p: &[u8], border: &[u8], tolerance: u8) -> bool {
+    if border[3] == 0 {
+        return p[3] <= tolerance;
+    }
+    p.iter()
+        .zip(border)
+        .all(|(a, b)| a.abs_diff(*b) <= tolerance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::{Engine, Photon},
+        pb::Spec,
+    };
+    use image::ImageOutputFormat;
+    use photon_rs::PhotonImage;
+
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+    const RED: [u8; 4] = [200, 0, 0, 255];
+
+    // width x height的图片，四周是border颜色，内部(x1, y1, x2, y2)是content颜色
+    fn bordered(
+        width: u32,
+        height: u32,
+        (x1, y1, x2, y2): (u32, u32, u32, u32),
+        border: [u8; 4],
+        content: [u8; 4],
+    ) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                match (x1..x2).contains(&x) && (y1..y2).contains(&y) {
+                    true => pixels.extend_from_slice(&content),
+                    false => pixels.extend_from_slice(&border),
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn white_border_should_be_detected() {
+        let pixels = bordered(40, 30, (5, 3, 31, 20), WHITE, RED);
+        assert_eq!(content_bounds(&pixels, 40, 0), Some((5, 3, 31, 20)));
+
+        // 接近白色的噪点在容差内时算作边框
+        let mut noisy = pixels.clone();
+        noisy[..4].copy_from_slice(&[250, 252, 255, 255]);
+        noisy[(29 * 40 + 39) * 4..].copy_from_slice(&[248, 255, 251, 255]);
+        assert_eq!(content_bounds(&noisy, 40, 0), Some((0, 0, 40, 30)));
+        assert_eq!(content_bounds(&noisy, 40, 8), Some((5, 3, 31, 20)));
+    }
+
+    #[test]
+    fn transparent_border_should_ignore_color() {
+        let mut pixels = bordered(20, 20, (4, 6, 10, 18), [0, 0, 0, 0], [0, 0, 0, 255]);
+        // 透明区域的RGB不一样也算边框
+        pixels[21 * 4..22 * 4].copy_from_slice(&[255, 255, 255, 0]);
+        // (2, 2)几乎透明，容差内才算边框
+        pixels[42 * 4..43 * 4].copy_from_slice(&[10, 20, 30, 5]);
+        assert_eq!(content_bounds(&pixels, 20, 0), Some((2, 2, 10, 18)));
+        assert_eq!(content_bounds(&pixels, 20, 5), Some((4, 6, 10, 18)));
+    }
+
+    #[test]
+    fn uniform_image_should_have_no_content() {
+        let pixels = [7, 8, 9, 255].repeat(16);
+        assert_eq!(content_bounds(&pixels, 4, 0), None);
+        assert_eq!(content_bounds(&[], 0, 0), None);
+    }
+
+    #[test]
+    fn padding_should_stay_in_image() {
+        assert_eq!(pad((5, 3, 31, 20), 4, 40, 30), (1, 0, 35, 24));
+        assert_eq!(pad((5, 3, 31, 20), u32::MAX, 40, 30), (0, 0, 40, 30));
+    }
+
+    #[test]
+    fn trim_spec_should_remove_borders_with_padding() {
+        let render = |pixels: Vec<u8>, width: u32, height: u32, specs: &[Spec]| {
+            let mut engine = Photon::from(PhotonImage::new(pixels, width, height));
+            engine.apply(specs);
+            engine.generate(ImageOutputFormat::Png)
+        };
+        let source = || bordered(40, 30, (5, 3, 31, 20), WHITE, RED);
+
+        let trimmed = render(source(), 40, 30, &[Spec::new_trim(0, 0)]);
+        assert_eq!(trimmed, render(RED.repeat(26 * 17), 26, 17, &[]));
+
+        // 四周保留4像素的白边，上边原本只有3像素，保留到原图边缘为止
+        let padded = render(source(), 40, 30, &[Spec::new_trim(0, 4)]);
+        let expected = bordered(34, 24, (4, 3, 30, 20), WHITE, RED);
+        assert_eq!(padded, render(expected, 34, 24, &[]));
+
+        // 纯色图片保持不变
+        let plain = render(WHITE.repeat(64), 8, 8, &[Spec::new_trim(10, 2)]);
+        assert_eq!(plain, render(WHITE.repeat(64), 8, 8, &[]));
+    }
+}
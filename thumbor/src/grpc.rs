@@ -0,0 +1,175 @@
+// gRPC接口：和http接口共用缓存、尺寸限制和处理流程
+// 给内部服务使用，不做url签名和限流，部署时不要把gRPC端口暴露到外网
+use crate::{
+    cache::{Cache, Processed},
+    engine::{probe_dimensions, Limits},
+    pb::{
+        process_request::Source,
+        thumbor_server::{Thumbor, ThumborServer},
+        ImageSpec, ProcessRequest, ProcessResponse,
+    },
+    server::{check_spec, generate_image, process_data, render, AppState},
+    trace::AccessRecord,
+};
+use axum::http::StatusCode;
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{info, info_span, warn, Instrument};
+
+// ProcessStream里最多缓存这么多个还没有发送出去的结果
+const STREAM_BUFFER: usize = 4;
+
+#[derive(Clone)]
+pub struct GrpcService {
+    cache: Cache,
+    limits: Limits,
+}
+
+impl GrpcService {
+    // 使用和http服务相同的缓存和限制
+    pub fn new(state: &AppState) -> Self {
+        Self {
+            cache: state.cache.clone(),
+            limits: state.limits,
+        }
+    }
+
+    pub fn into_server(self) -> ThumborServer<Self> {
+        ThumborServer::new(self)
+    }
+
+    async fn handle(&self, req: ProcessRequest) -> Result<ProcessResponse, Status> {
+        let spec = req
+            .spec
+            .ok_or_else(|| Status::invalid_argument("spec is required"))?
+            .upgrade()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let record = AccessRecord::default();
+        let processed = match req.source {
+            Some(Source::Url(url)) => {
+                generate_image(&spec, &url, self.cache.clone(), self.limits, &record).await
+            }
+            Some(Source::Data(data)) => self.process_bytes(&spec, data.into()).await,
+            None => return Err(Status::invalid_argument("source is required")),
+        }
+        .map_err(into_status)?;
+
+        let (width, height) =
+            probe_dimensions(&processed.data).map_err(|e| Status::internal(e.to_string()))?;
+        Ok(ProcessResponse {
+            data: processed.data.to_vec(),
+            content_type: processed.content_type.into(),
+            width,
+            height,
+            cache: record.snapshot().cache.unwrap_or_default().into(),
+            id: req.id,
+            error: String::new(),
+        })
+    }
+
+    // 直接传入的数据没有url可以作为key，不放入缓存
+    async fn process_bytes(&self, spec: &ImageSpec, data: Bytes) -> Result<Processed, StatusCode> {
+        check_spec(spec)?;
+        let engine = process_data(spec, "<bytes>", data, self.cache.clone(), self.limits).await?;
+        let processed = render(engine);
+        info!("Finished processing: image size {}", processed.data.len());
+        Ok(processed)
+    }
+}
+
+#[tonic::async_trait]
+impl Thumbor for GrpcService {
+    async fn process(
+        &self,
+        request: Request<ProcessRequest>,
+    ) -> Result<Response<ProcessResponse>, Status> {
+        let req = request.into_inner();
+        let span = info_span!("grpc", method = "Process", id = %req.id);
+        let resp = self.handle(req).instrument(span).await?;
+        Ok(Response::new(resp))
+    }
+
+    type ProcessStreamStream = ReceiverStream<Result<ProcessResponse, Status>>;
+
+    // 逐个处理，单个请求失败时在error里返回原因，继续处理后面的请求
+    async fn process_stream(
+        &self,
+        request: Request<Streaming<ProcessRequest>>,
+    ) -> Result<Response<Self::ProcessStreamStream>, Status> {
+        let mut requests = request.into_inner();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let service = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let req = match requests.message().await {
+                    Ok(Some(req)) => req,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Failed to read stream request: {}", e);
+                        tx.send(Err(e)).await.ok();
+                        break;
+                    }
+                };
+                let id = req.id.clone();
+                let span = info_span!("grpc", method = "ProcessStream", id = %id);
+                let resp = match service.handle(req).instrument(span).await {
+                    Ok(resp) => resp,
+                    Err(status) => ProcessResponse {
+                        id,
+                        error: status.message().to_string(),
+                        ..Default::default()
+                    },
+                };
+                // 客户端已经断开
+                if tx.send(Ok(resp)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+// 把http接口使用的状态码转换成gRPC的错误
+fn into_status(status: StatusCode) -> Status {
+    let message = status.canonical_reason().unwrap_or("unknown error");
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+            Status::invalid_argument(message)
+        }
+        // 超过了尺寸限制，或者spec对这张图片不成立
+        StatusCode::UNPROCESSABLE_ENTITY => Status::failed_precondition(message),
+        _ => Status::internal(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    #[test]
+    fn status_codes_should_be_mapped() {
+        assert_eq!(
+            into_status(StatusCode::BAD_REQUEST).code(),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            into_status(StatusCode::UNSUPPORTED_MEDIA_TYPE).message(),
+            "Unsupported Media Type"
+        );
+        assert_eq!(
+            into_status(StatusCode::UNPROCESSABLE_ENTITY).code(),
+            Code::FailedPrecondition
+        );
+        assert_eq!(
+            into_status(StatusCode::INTERNAL_SERVER_ERROR).code(),
+            Code::Internal
+        );
+    }
+}
@@ -0,0 +1,20 @@
+// thumbor库：pb和engine可以单独使用，client用来构建/签名url并访问服务，
+// server提供axum路由，grpc提供给内部服务使用的gRPC接口，
+// batch不启动服务直接处理本地文件，二进制只负责解析命令行
+pub mod batch;
+pub mod cache;
+pub mod client;
+pub mod engine;
+pub mod grpc;
+pub mod pb;
+pub mod presets;
+pub mod ratelimit;
+pub mod server;
+pub mod trace;
+
+pub use client::{Client, ImageSpecBuilder, UrlSigner};
+pub use engine::{Engine, Photon};
+pub use grpc::GrpcService;
+pub use presets::Presets;
+pub use ratelimit::RateLimiter;
+pub use server::{app, AdminToken, AppState};
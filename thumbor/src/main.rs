@@ -1,131 +1,280 @@
-use anyhow::Result;
-use axum::{
-    extract::{Extension, Path}, 
-    handler::get, 
-    http::{HeaderMap, HeaderValue, StatusCode}, 
-    Router,
-    AddExtensionLayer,
+use clap::Parser;
+use std::{net::SocketAddr, path::PathBuf, process, time::Duration};
+use thumbor::{
+    app, batch,
+    engine::Limits,
+    pb::*,
+    ratelimit::{Quota, RateLimits},
+    server::PlaceholderKind,
+    AdminToken, AppState, Client, GrpcService, ImageSpecBuilder, Presets, RateLimiter, UrlSigner,
 };
-use bytes::Bytes;
-use lru::LruCache;
-use percent_encoding::{percent_decode_str, percent_encode, NON_ALPHANUMERIC};
-use serde::Deserialize;
-use std::{
-    collections::hash_map::DefaultHasher,
-    convert::TryInto,
-    hash::{Hash, Hasher},
-    sync::Arc,
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::oneshot,
 };
-use tokio::sync::Mutex;
-use tower::ServiceBuilder;
-use tracing::{info, instrument};
+use tracing::{info, warn};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
-mod engine;
-use engine::{Engine, Photon};
-use image::ImageOutputFormat;
+// 收到退出信号后，readyz先返回失败，等待这段时间让负载均衡摘掉本实例
+const DRAIN_DELAY: Duration = Duration::from_secs(5);
+// 停止接收新连接后，等待正在处理的请求完成的最长时间
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+// 检查preset配置文件是否有修改的间隔
+const PRESETS_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
 
+#[derive(Parser, Debug)]
+#[clap(version = "0.1", about = "thumbor image server")]
+struct Opts {
+    // 不带子命令时启动服务
+    #[clap(subcommand)]
+    subcmd: Option<SubCommand>,
+}
 
-mod pb;
-use pb::*;
+#[derive(Parser, Debug)]
+enum SubCommand {
+    #[clap(about = "start the http and gRPC servers (default)")]
+    Serve,
+    #[clap(about = "process local files with an ImageSpec, without starting the server")]
+    Process(Process),
+}
 
-// 参数使用serde 做Deserialize, axum会自动识别并解析
-#[derive(Deserialize)]
-struct Params {
+#[derive(Parser, Debug)]
+struct Process {
+    #[clap(long, help = "spec in text format or base64")]
     spec: String,
-    url: String,
+    #[clap(long = "in", help = "input file, directory or glob pattern")]
+    input: String,
+    #[clap(long = "out", help = "output file, or output directory for multiple inputs")]
+    output: PathBuf,
+    #[clap(long, short, help = "number of parallel workers, defaults to the number of CPUs")]
+    jobs: Option<usize>,
 }
 
-type Cache = Arc<Mutex<LruCache<u64, Bytes>>>;
-
 #[tokio::main]
 async fn main() {
-    // 初始化tracing
-    tracing_subscriber::fmt::init();
-    let cache: Cache = Arc::new(Mutex::new(LruCache::new(1024)));
+    // 初始化tracing，span关闭时输出耗时，可以看到fetch/decode/spec/encode各阶段用了多久
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+
+    let opts = Opts::parse();
+    match opts.subcmd {
+        None | Some(SubCommand::Serve) => serve().await,
+        Some(SubCommand::Process(args)) => {
+            if let Err(e) = run_process(args).await {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+async fn serve() {
+    let state = AppState {
+        limits: load_limits(),
+        signer: std::env::var("THUMBOR_SIGNING_KEY").ok().map(UrlSigner::new),
+        presets: load_presets().await,
+        limiter: load_rate_limiter(),
+        // 没有配置时不开放admin api
+        admin_token: std::env::var("THUMBOR_ADMIN_TOKEN")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(AdminToken::new),
+        ..Default::default()
+    };
+    info!("Pixel limits: {:?}", state.limits);
+    info!("Rate limits: {:?}", state.limiter.limits());
+    let readiness = state.readiness.clone();
+    let signer = state.signer.clone();
+    let grpc = GrpcService::new(&state);
 
     // 构建路由
-    let app = Router::new()
-        .route("/image/:spec/:url", get(generate))
-        .layer(
-            ServiceBuilder::new()
-                .layer(AddExtensionLayer::new(cache))
-                .into_inner(),
-        );
+    let app = app(state);
 
     // 支行web服务器
     let addr = "127.0.0.1:3000".parse().unwrap();
 
-    print_test_url("https://images.pexels.com/photos/1562477/pexels-photo-1562477.jpeg?auto=compress&cs=tinysrgb&dpr=3&h=750&w=1260");
+    print_test_url(
+        "https://images.pexels.com/photos/1562477/pexels-photo-1562477.jpeg?auto=compress&cs=tinysrgb&dpr=3&h=750&w=1260",
+        signer,
+    );
 
     info!("Listening on {}", addr);
-    
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
-}
 
-async fn generate(
-    Path(Params {spec, url}): Path<Params>,
-    Extension(cache): Extension<Cache>,
-) -> Result<(HeaderMap, Vec<u8>), StatusCode> {
-    let spec: ImageSpec = spec
-        .as_str()
-        .try_into()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-    let url: &str = &percent_decode_str(&url).decode_utf8_lossy();
-    let data = retrieve_image(&url, cache)
-        .await
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-    // 使用image engine 处理
-    let mut engine: Photon = data
-        .try_into()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    engine.apply(&spec.specs);
-
-    let image = engine.generate(ImageOutputFormat::Jpeg(85));
-
-    info!("Finished processing: image size {}", image.len());
-
-    let mut headers = HeaderMap::new();
-    headers.insert("content-type", HeaderValue::from_static("image/jpeg"));
-    Ok((headers, image))
-}
+    let (tx, rx) = oneshot::channel::<()>();
+    let server = axum::Server::bind(&addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr, _>())
+        .with_graceful_shutdown(async {
+            rx.await.ok();
+        });
+    let server = tokio::spawn(server);
+
+    // gRPC服务和http服务共用缓存，一起退出
+    let grpc_addr: SocketAddr = std::env::var("THUMBOR_GRPC_ADDR")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| "127.0.0.1:50051".parse().unwrap());
+    info!("gRPC listening on {}", grpc_addr);
+    let (grpc_tx, grpc_rx) = oneshot::channel::<()>();
+    let grpc_server = tonic::transport::Server::builder()
+        .add_service(grpc.into_server())
+        .serve_with_shutdown(grpc_addr, async {
+            grpc_rx.await.ok();
+        });
+    let grpc_server = tokio::spawn(grpc_server);
+
+    shutdown_signal().await;
 
-#[instrument(level="info", skip(cache))]
-async fn retrieve_image(url: &str, cache: Cache) -> Result<Bytes> {
-    let mut hasher = DefaultHasher::new();
-    url.hash(&mut hasher);
-    let key = hasher.finish();
-
-    let g = &mut cache.lock().await;
-    let data = match g.get(&key) {
-        Some(v) => {
-            info!("Match cache {}", key);
-            v.to_owned()
+    // 先让readyz失败，新流量不再打到本实例，已有的请求继续处理
+    info!("Shutdown signal received, draining for {:?}", DRAIN_DELAY);
+    readiness.drain();
+    tokio::time::sleep(DRAIN_DELAY).await;
+
+    // 停止接收新连接，等待正在进行的处理完成
+    tx.send(()).ok();
+    grpc_tx.send(()).ok();
+    let servers = async { tokio::join!(server, grpc_server) };
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, servers).await {
+        Ok((http, grpc)) => {
+            match http {
+                Ok(Ok(())) => info!("Server stopped gracefully"),
+                Ok(Err(e)) => warn!("Server error during shutdown: {}", e),
+                Err(e) => warn!("Server task failed: {}", e),
+            }
+            match grpc {
+                Ok(Ok(())) => info!("gRPC server stopped gracefully"),
+                Ok(Err(e)) => warn!("gRPC server error during shutdown: {}", e),
+                Err(e) => warn!("gRPC server task failed: {}", e),
+            }
         }
-        None => {
-            info!("Retrieve url");
-            let resp = reqwest::get(url).await?;
-            let data = resp.bytes().await?;
-            g.put(key, data.clone());
-            data
+        Err(_) => warn!("In-flight requests not finished in {:?}, exiting", SHUTDOWN_TIMEOUT),
+    }
+}
+
+// 处理本地文件，有任何文件失败时返回错误
+async fn run_process(args: Process) -> anyhow::Result<()> {
+    let spec = ImageSpec::parse(&args.spec)?;
+    let jobs = batch::plan(&args.input, &args.output, &spec)?;
+    // 单个文件时输出格式可以由扩展名决定
+    let spec = match jobs.as_slice() {
+        [job] if job.output == args.output => batch::spec_for_path(spec, &args.output)?,
+        _ => spec,
+    };
+    let sources = batch::load_overlays(&spec).await?;
+    let workers = args.jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+
+    // 处理过程是CPU密集的同步代码，放到blocking线程里执行
+    let results = {
+        let jobs = jobs.clone();
+        tokio::task::spawn_blocking(move || batch::run(&jobs, &spec, &sources, workers)).await?
+    };
+
+    let mut failed = 0;
+    for (job, result) in jobs.iter().zip(results) {
+        match result {
+            Ok(size) => println!(
+                "{} -> {} ({} bytes)",
+                job.input.display(),
+                job.output.display(),
+                size
+            ),
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}", job.input.display(), e);
+            }
         }
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(anyhow::anyhow!("{} of {} files failed", n, jobs.len())),
+    }
+}
+
+// 从环境变量读取像素上限，单位是百万像素
+fn load_limits() -> Limits {
+    let default = Limits::default();
+    let read = |name: &str, default: u64| {
+        std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse::<f64>().ok())
+            .unwrap_or(default as f64 / 1_000_000.0)
     };
+    Limits::from_megapixels(
+        read("THUMBOR_MAX_INPUT_MEGAPIXELS", default.max_input_pixels),
+        read("THUMBOR_MAX_OUTPUT_MEGAPIXELS", default.max_output_pixels),
+    )
+}
 
-    Ok(data)
+// 从环境变量读取每个客户端的限流配置，THUMBOR_API_KEYS是逗号分隔的api key
+fn load_rate_limiter() -> RateLimiter {
+    let default = RateLimits::default();
+    let read = |name: &str, default: f64| {
+        std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse::<f64>().ok())
+            .unwrap_or(default)
+    };
+    let quota = |prefix: &str, default: Quota| {
+        Quota::new(
+            read(&format!("{}_BURST", prefix), default.burst as f64) as u32,
+            read(&format!("{}_PER_SECOND", prefix), default.per_second),
+        )
+    };
+    let limits = RateLimits {
+        hit: quota("THUMBOR_RATE_HIT", default.hit),
+        miss: quota("THUMBOR_RATE_MISS", default.miss),
+        max_concurrent: read("THUMBOR_MAX_CONCURRENT", default.max_concurrent as f64) as u32,
+    };
+    let keys = std::env::var("THUMBOR_API_KEYS").unwrap_or_default();
+    RateLimiter::new(limits).with_api_keys(
+        keys.split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty()),
+    )
+}
+
+// 加载preset配置，之后配置文件有修改时自动重新加载
+async fn load_presets() -> Presets {
+    let path: PathBuf = std::env::var("THUMBOR_PRESETS")
+        .unwrap_or_else(|_| "presets.toml".into())
+        .into();
+    let presets = Presets::default();
+    match presets.load(&path).await {
+        Ok(n) => info!("Loaded {} presets from {}", n, path.display()),
+        Err(e) => warn!("Failed to load presets from {}: {}", path.display(), e),
+    }
+    presets.watch(path, PRESETS_RELOAD_INTERVAL);
+    presets
+}
+
+// 等待SIGTERM或者Ctrl-C
+async fn shutdown_signal() {
+    let mut term = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = term.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
 }
 
 // 高度辅助函数
-fn print_test_url(url: &str) {
-    use std::borrow::Borrow;
-    let spec1 = Spec::new_resize(500, 800, resize::SampleFilter::CatmullRom);
-    let spec2 = Spec::new_watermark(20, 20);
-    let spec3 = Spec::new_filter(filter::Filter::Marine);
-    let image_spec = ImageSpec::new(vec![spec1, spec2, spec3]);
-    let s: String = image_spec.borrow().into();
-    let test_image = percent_encode(url.as_bytes(), NON_ALPHANUMERIC).to_string();
-    println!("test url: http://localhost:3000/image/{}/{}", s, test_image);
-}
\ No newline at end of file
+fn print_test_url(url: &str, signer: Option<UrlSigner>) {
+    let mut client = Client::new("http://localhost:3000");
+    if let Some(signer) = signer {
+        client = client.with_signer(signer);
+    }
+    let image_spec = ImageSpecBuilder::new()
+        .resize(500, 800, resize::SampleFilter::CatmullRom)
+        .watermark(20, 20)
+        .filter(filter::Filter::Marine)
+        .build();
+    println!("test url: {}", client.image_url(&image_spec, url));
+    println!(
+        "placeholder url: {}",
+        client.placeholder_url(PlaceholderKind::Blurhash, &image_spec, url)
+    );
+}
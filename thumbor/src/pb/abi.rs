@@ -3,6 +3,33 @@
 pub struct ImageSpec {
     #[prost(message, repeated, tag="1")]
     pub specs: ::prost::alloc::vec::Vec<Spec>,
+    /// 生成这个spec时的版本，修改了字段的含义时增加版本号，服务器按旧的含义处理旧版本的spec
+    /// 0表示加入版本号之前生成的spec
+    #[prost(uint32, tag="2")]
+    pub version: u32,
+}
+/// 焦点：crop和cover resize时尽量让焦点区域留在画面内
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Focal {
+    #[prost(enumeration="focal::Mode", tag="1")]
+    pub mode: i32,
+    #[prost(float, tag="2")]
+    pub x: f32,
+    #[prost(float, tag="3")]
+    pub y: f32,
+}
+/// Nested message and enum types in `Focal`.
+pub mod focal {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Mode {
+        /// 以图片中心为焦点
+        Center = 0,
+        /// 使用x, y指定的焦点，取值0-1
+        Point = 1,
+        /// 根据图片的边缘能量自动检测
+        Auto = 2,
+    }
 }
 /// 处理图片改变大小
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -15,6 +42,12 @@ pub struct Resize {
     pub rtype: i32,
     #[prost(enumeration="resize::SampleFilter", tag="4")]
     pub filter: i32,
+    /// 只对COVER有效
+    #[prost(message, optional, tag="5")]
+    pub focal: ::core::option::Option<Focal>,
+    /// 在线性空间中缩放，对SEAM_CARVE无效
+    #[prost(bool, tag="6")]
+    pub linear: bool,
 }
 /// Nested message and enum types in `Resize`.
 pub mod resize {
@@ -23,6 +56,8 @@ pub mod resize {
     pub enum ResizeType {
         Normal = 0,
         SeamCarve = 1,
+        /// 等比缩放到刚好覆盖目标尺寸，多余部分围绕焦点裁掉
+        Cover = 2,
     }
     #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
     #[repr(i32)]
@@ -46,6 +81,9 @@ pub struct Crop {
     pub x2: u32,
     #[prost(uint32, tag="4")]
     pub y2: u32,
+    /// 设置了focal时只使用(x2-x1, y2-y1)作为截取的大小，位置由焦点决定
+    #[prost(message, optional, tag="5")]
+    pub focal: ::core::option::Option<Focal>,
 }
 /// 处理水平翻转
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -86,10 +124,105 @@ pub struct Watermark {
     #[prost(uint32, tag="2")]
     pub y: u32,
 }
+/// 把另一张图片（通过url获取）叠加到当前图片上
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Overlay {
+    #[prost(string, tag="1")]
+    pub url: ::prost::alloc::string::String,
+    /// 叠加图片左上角的位置
+    #[prost(uint32, tag="2")]
+    pub x: u32,
+    #[prost(uint32, tag="3")]
+    pub y: u32,
+    /// 叠加图片的缩放比例，0表示不缩放
+    #[prost(float, tag="4")]
+    pub scale: f32,
+    /// 不透明度0-1，0表示完全不透明
+    #[prost(float, tag="5")]
+    pub opacity: f32,
+    #[prost(enumeration="overlay::BlendMode", tag="6")]
+    pub blend: i32,
+}
+/// Nested message and enum types in `Overlay`.
+pub mod overlay {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum BlendMode {
+        Normal = 0,
+        Multiply = 1,
+        Screen = 2,
+        Overlay = 3,
+    }
+}
+/// 输出JPEG这类不支持透明的格式时，用来合成alpha的背景色，默认是白色
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Background {
+    /// 0xRRGGBB
+    #[prost(uint32, tag="1")]
+    pub color: u32,
+}
+/// 输出格式和编码参数，有多个Output时最后一个生效，没有时输出质量85的JPEG
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Output {
+    #[prost(enumeration="output::Format", tag="1")]
+    pub format: i32,
+    /// JPEG的质量1-100，0表示默认的85
+    #[prost(uint32, tag="2")]
+    pub quality: u32,
+    /// 输出渐进式JPEG，否则是baseline
+    #[prost(bool, tag="3")]
+    pub progressive: bool,
+    #[prost(enumeration="output::ChromaSubsampling", tag="4")]
+    pub subsampling: i32,
+    #[prost(enumeration="output::PngCompression", tag="5")]
+    pub compression: i32,
+    /// PNG量化成最多palette种颜色的调色板图片，取值2-256，0表示不量化
+    #[prost(uint32, tag="6")]
+    pub palette: u32,
+    /// 输出大小的上限，0表示不限制
+    /// JPEG会自动降低质量，PNG会逐步减少调色板的颜色
+    #[prost(uint32, tag="7")]
+    pub max_bytes: u32,
+}
+/// Nested message and enum types in `Output`.
+pub mod output {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Format {
+        Jpeg = 0,
+        Png = 1,
+    }
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum ChromaSubsampling {
+        Chroma420 = 0,
+        Chroma422 = 1,
+        Chroma444 = 2,
+    }
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum PngCompression {
+        Default = 0,
+        Fast = 1,
+        Best = 2,
+    }
+}
+/// 去掉四周颜色一致的边框，比如商品图四周的白边或者透明边
+/// 以左上角的像素作为边框颜色，整张图都是边框颜色时不做处理
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Trim {
+    /// 每个通道和边框颜色相差不超过tolerance的像素都算作边框，取值0-255
+    /// 边框是透明的时候只比较alpha
+    #[prost(uint32, tag="1")]
+    pub tolerance: u32,
+    /// 裁剪后在内容四周保留的边距，单位是像素，不会超出原图
+    #[prost(uint32, tag="2")]
+    pub padding: u32,
+}
 /// 一个spec可以包含上述的处理方式之一
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Spec {
-    #[prost(oneof="spec::Data", tags="1, 2, 3, 4, 5, 6, 7")]
+    #[prost(oneof="spec::Data", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11")]
     pub data: ::core::option::Option<spec::Data>,
 }
 /// Nested message and enum types in `Spec`.
@@ -110,5 +243,283 @@ pub mod spec {
         Filter(super::Filter),
         #[prost(message, tag="7")]
         Watermark(super::Watermark),
+        #[prost(message, tag="8")]
+        Overlay(super::Overlay),
+        #[prost(message, tag="9")]
+        Background(super::Background),
+        #[prost(message, tag="10")]
+        Output(super::Output),
+        #[prost(message, tag="11")]
+        Trim(super::Trim),
+    }
+}
+/// gRPC接口的请求：源图片可以是url，也可以直接传入图片数据
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProcessRequest {
+    #[prost(message, optional, tag="1")]
+    pub spec: ::core::option::Option<ImageSpec>,
+    /// 调用方自己定义的标识，原样放在response里，ProcessStream里用来对应请求和结果
+    #[prost(string, tag="4")]
+    pub id: ::prost::alloc::string::String,
+    #[prost(oneof="process_request::Source", tags="2, 3")]
+    pub source: ::core::option::Option<process_request::Source>,
+}
+/// Nested message and enum types in `ProcessRequest`.
+pub mod process_request {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Source {
+        /// 和http接口一样通过缓存获取
+        #[prost(string, tag="2")]
+        Url(::prost::alloc::string::String),
+        /// 直接传入的数据不会缓存
+        #[prost(bytes, tag="3")]
+        Data(::prost::alloc::vec::Vec<u8>),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProcessResponse {
+    #[prost(bytes="vec", tag="1")]
+    pub data: ::prost::alloc::vec::Vec<u8>,
+    #[prost(string, tag="2")]
+    pub content_type: ::prost::alloc::string::String,
+    /// 输出图片的宽高
+    #[prost(uint32, tag="3")]
+    pub width: u32,
+    #[prost(uint32, tag="4")]
+    pub height: u32,
+    /// url源图片的缓存状态：hit、miss或者processed，直接传入数据时为空
+    #[prost(string, tag="5")]
+    pub cache: ::prost::alloc::string::String,
+    #[prost(string, tag="6")]
+    pub id: ::prost::alloc::string::String,
+    /// 只在ProcessStream里使用：单个请求失败时data为空，error是失败原因，不影响后面的请求
+    #[prost(string, tag="7")]
+    pub error: ::prost::alloc::string::String,
+}
+#[doc = r" Generated client implementations."]
+pub mod thumbor_client {
+    #![allow(unused_variables, dead_code, missing_docs)]
+    use tonic::codegen::*;
+    #[doc = " 给内部服务使用的gRPC接口，直接传ImageSpec，不需要编码到url里，也不需要签名"]
+    #[derive(Debug, Clone)]
+    pub struct ThumborClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl ThumborClient<tonic::transport::Channel> {
+        #[doc = r" Attempt to create a new client by connecting to a given endpoint."]
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: std::convert::TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> ThumborClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::ResponseBody: Body + Send + Sync + 'static,
+        T::Error: Into<StdError>,
+        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> ThumborClient<InterceptedService<T, F>>
+        where
+            F: FnMut(tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status>,
+            T: Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as Service<http::Request<tonic::body::BoxBody>>>::Error:
+                Into<StdError> + Send + Sync,
+        {
+            ThumborClient::new(InterceptedService::new(inner, interceptor))
+        }
+        pub async fn process(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ProcessRequest>,
+        ) -> Result<tonic::Response<super::ProcessResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.Thumbor/Process");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        #[doc = " 批量处理，按请求的顺序逐个返回结果"]
+        pub async fn process_stream(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::ProcessRequest>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::ProcessResponse>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.Thumbor/ProcessStream");
+            self.inner
+                .streaming(request.into_streaming_request(), path, codec)
+                .await
+        }
+    }
+}
+#[doc = r" Generated server implementations."]
+pub mod thumbor_server {
+    #![allow(unused_variables, dead_code, missing_docs)]
+    use tonic::codegen::*;
+    #[doc = "Generated trait containing gRPC methods that should be implemented for use with ThumborServer."]
+    #[async_trait]
+    pub trait Thumbor: Send + Sync + 'static {
+        async fn process(
+            &self,
+            request: tonic::Request<super::ProcessRequest>,
+        ) -> Result<tonic::Response<super::ProcessResponse>, tonic::Status>;
+        #[doc = "Server streaming response type for the ProcessStream method."]
+        type ProcessStreamStream: futures_core::Stream<Item = Result<super::ProcessResponse, tonic::Status>>
+            + Send
+            + Sync
+            + 'static;
+        #[doc = " 批量处理，按请求的顺序逐个返回结果"]
+        async fn process_stream(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::ProcessRequest>>,
+        ) -> Result<tonic::Response<Self::ProcessStreamStream>, tonic::Status>;
+    }
+    #[doc = " 给内部服务使用的gRPC接口，直接传ImageSpec，不需要编码到url里，也不需要签名"]
+    #[derive(Debug)]
+    pub struct ThumborServer<T: Thumbor> {
+        inner: _Inner<T>,
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: Thumbor> ThumborServer<T> {
+        pub fn new(inner: T) -> Self {
+            let inner = Arc::new(inner);
+            let inner = _Inner(inner);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
+        where
+            F: FnMut(tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status>,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+    }
+    impl<T, B> Service<http::Request<B>> for ThumborServer<T>
+    where
+        T: Thumbor,
+        B: Body + Send + Sync + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = Never;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/abi.Thumbor/Process" => {
+                    #[allow(non_camel_case_types)]
+                    struct ProcessSvc<T: Thumbor>(pub Arc<T>);
+                    impl<T: Thumbor> tonic::server::UnaryService<super::ProcessRequest> for ProcessSvc<T> {
+                        type Response = super::ProcessResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ProcessRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).process(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ProcessSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.Thumbor/ProcessStream" => {
+                    #[allow(non_camel_case_types)]
+                    struct ProcessStreamSvc<T: Thumbor>(pub Arc<T>);
+                    impl<T: Thumbor> tonic::server::StreamingService<super::ProcessRequest>
+                        for ProcessStreamSvc<T>
+                    {
+                        type Response = super::ProcessResponse;
+                        type ResponseStream = T::ProcessStreamStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<tonic::Streaming<super::ProcessRequest>>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).process_stream(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ProcessStreamSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => Box::pin(async move {
+                    Ok(http::Response::builder()
+                        .status(200)
+                        .header("grpc-status", "12")
+                        .header("content-type", "application/grpc")
+                        .body(empty_body())
+                        .unwrap())
+                }),
+            }
+        }
+    }
+    impl<T: Thumbor> Clone for ThumborServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self { inner }
+        }
+    }
+    impl<T: Thumbor> Clone for _Inner<T> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+    impl<T: std::fmt::Debug> std::fmt::Debug for _Inner<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl<T: Thumbor> tonic::transport::NamedService for ThumborServer<T> {
+        const NAME: &'static str = "abi.Thumbor";
     }
 }
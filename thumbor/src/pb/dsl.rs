@@ -0,0 +1,350 @@
+// 一个简单的文本格式来描述ImageSpec，方便写在配置文件和命令行里
+// 多个spec用 | 分隔，按顺序执行，比如：
+//   crop(0, 0, 800, 600) | resize(400, 300, lanczos3) | filter(marine)
+// 焦点可以写成 auto、center 或者 x/y（0-1的比例），比如：
+//   cover(400, 300, lanczos3, auto) | crop(0, 0, 200, 200, 0.3/0.6)
+// resize和cover最后加上linear表示在线性空间中缩放，background设置合成透明区域的背景色：
+//   resize(400, 300, lanczos3, linear) | background(#ffcc00)
+// overlay的参数是 url, x, y[, scale[, opacity[, blend]]]，url里不能有 , 和 |：
+//   overlay(https://example.com/badge.png, 10, 10, 0.5, 0.8, multiply)
+// trim去掉颜色一致的边框，参数是 [tolerance[, padding]]：
+//   trim | trim(10) | trim(10, 4)
+// jpeg和png设置输出格式，后面的选项顺序不限，max=N表示输出不超过N字节：
+//   jpeg(80, progressive, 444, max=50000) | png(best, palette=64)
+use super::*;
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+
+impl ImageSpec {
+    // 先按文本格式解析，失败的话再当成base64编码的protobuf
+    pub fn parse(s: &str) -> Result<Self> {
+        match Self::from_dsl(s) {
+            Ok(spec) => Ok(spec),
+            Err(e) => s.trim().try_into().map_err(|_| e),
+        }
+    }
+
+    pub fn from_dsl(s: &str) -> Result<Self> {
+        let specs = s
+            .split('|')
+            .map(|item| item.parse())
+            .collect::<Result<Vec<Spec>>>()?;
+        Ok(Self::new(specs))
+    }
+}
+
+impl FromStr for Spec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, args) = match s.find('(') {
+            Some(i) if s.ends_with(')') => {
+                let args: Vec<&str> = s[i + 1..s.len() - 1]
+                    .split(',')
+                    .map(|v| v.trim())
+                    .filter(|v| !v.is_empty())
+                    .collect();
+                (s[..i].trim(), args)
+            }
+            Some(_) => return Err(anyhow!("spec {} is missing ')'", s)),
+            None => (s, vec![]),
+        };
+
+        let spec = match (name, args.as_slice()) {
+            ("resize", [w, h]) => {
+                Spec::new_resize(w.parse()?, h.parse()?, resize::SampleFilter::Undefined)
+            }
+            ("resize", [w, h, f]) => Spec::new_resize(w.parse()?, h.parse()?, f.parse()?),
+            ("resize", [w, h, f, "linear"]) => {
+                Spec::new_resize(w.parse()?, h.parse()?, f.parse()?).with_linear()
+            }
+            ("seam_carve", [w, h]) => Spec::new_resize_seam_carve(w.parse()?, h.parse()?),
+            ("cover", [w, h]) => Spec::new_resize_cover(
+                w.parse()?,
+                h.parse()?,
+                resize::SampleFilter::Undefined,
+                Focal::center(),
+            ),
+            ("cover", [w, h, f]) => {
+                Spec::new_resize_cover(w.parse()?, h.parse()?, f.parse()?, Focal::center())
+            }
+            ("cover", [w, h, f, focal]) => {
+                Spec::new_resize_cover(w.parse()?, h.parse()?, f.parse()?, focal.parse()?)
+            }
+            ("cover", [w, h, f, focal, "linear"]) => {
+                Spec::new_resize_cover(w.parse()?, h.parse()?, f.parse()?, focal.parse()?)
+                    .with_linear()
+            }
+            ("crop", [x1, y1, x2, y2]) => {
+                Spec::new_crop(x1.parse()?, y1.parse()?, x2.parse()?, y2.parse()?)
+            }
+            // 有焦点时坐标只用来表示截取的大小
+            ("crop", [x1, y1, x2, y2, focal]) => {
+                let width = x2.parse::<u32>()?.saturating_sub(x1.parse()?);
+                let height = y2.parse::<u32>()?.saturating_sub(y1.parse()?);
+                Spec::new_crop_focal(width, height, focal.parse()?)
+            }
+            ("fliph", []) => Spec::new_fliph(),
+            ("flipv", []) => Spec::new_flipv(),
+            ("contrast", [c]) => Spec::new_contrast(c.parse()?),
+            ("filter", [f]) => Spec::new_filter(f.parse()?),
+            ("trim", []) => Spec::new_trim(0, 0),
+            ("trim", [tolerance]) => Spec::new_trim(tolerance.parse()?, 0),
+            ("trim", [tolerance, padding]) => Spec::new_trim(tolerance.parse()?, padding.parse()?),
+            ("watermark", [x, y]) => Spec::new_watermark(x.parse()?, y.parse()?),
+            ("background", [color]) => match color.strip_prefix('#') {
+                Some(hex) if hex.len() == 6 => Spec::new_background(u32::from_str_radix(hex, 16)?),
+                _ => return Err(anyhow!("color {} should be #rrggbb", color)),
+            },
+            ("overlay", [url, x, y, rest @ ..]) if rest.len() <= 3 => Spec::new_overlay(
+                *url,
+                x.parse()?,
+                y.parse()?,
+                rest.first().map_or(Ok(0.0), |v| v.parse())?,
+                rest.get(1).map_or(Ok(0.0), |v| v.parse())?,
+                rest.get(2)
+                    .map_or(Ok(overlay::BlendMode::Normal), |v| v.parse())?,
+            ),
+            ("jpeg", [quality, options @ ..]) => {
+                let mut encoding = Output {
+                    quality: quality.parse()?,
+                    ..Default::default()
+                };
+                for option in options {
+                    match *option {
+                        "progressive" => encoding.progressive = true,
+                        "420" => encoding.subsampling = output::ChromaSubsampling::Chroma420 as i32,
+                        "422" => encoding.subsampling = output::ChromaSubsampling::Chroma422 as i32,
+                        "444" => encoding.subsampling = output::ChromaSubsampling::Chroma444 as i32,
+                        v => encoding.max_bytes = parse_max_bytes(v)?,
+                    }
+                }
+                Spec {
+                    data: Some(spec::Data::Output(encoding)),
+                }
+            }
+            ("png", options) => {
+                let mut encoding = Output {
+                    format: output::Format::Png as i32,
+                    ..Default::default()
+                };
+                for option in options {
+                    match *option {
+                        "fast" => encoding.compression = output::PngCompression::Fast as i32,
+                        "best" => encoding.compression = output::PngCompression::Best as i32,
+                        v => match v.strip_prefix("palette=") {
+                            Some(n) => encoding.palette = n.parse()?,
+                            None => encoding.max_bytes = parse_max_bytes(v)?,
+                        },
+                    }
+                }
+                Spec {
+                    data: Some(spec::Data::Output(encoding)),
+                }
+            }
+            _ => return Err(anyhow!("spec {} is not supported", s)),
+        };
+        Ok(spec)
+    }
+}
+
+fn parse_max_bytes(s: &str) -> Result<u32> {
+    match s.strip_prefix("max=") {
+        Some(v) => Ok(v.parse()?),
+        None => Err(anyhow!("output option {} is not supported", s)),
+    }
+}
+
+impl FromStr for resize::SampleFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(resize::SampleFilter::Nearest),
+            "triangle" => Ok(resize::SampleFilter::Triangle),
+            "catmull_rom" => Ok(resize::SampleFilter::CatmullRom),
+            "gaussian" => Ok(resize::SampleFilter::Gaussian),
+            "lanczos3" => Ok(resize::SampleFilter::Lanczos3),
+            v => Err(anyhow!("sample filter {} is not supported", v)),
+        }
+    }
+}
+
+impl FromStr for Focal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Focal::auto()),
+            "center" => Ok(Focal::center()),
+            v => match v.split_once('/') {
+                Some((x, y)) => Ok(Focal::point(x.trim().parse()?, y.trim().parse()?)),
+                None => Err(anyhow!("focal {} is not supported", v)),
+            },
+        }
+    }
+}
+
+impl FromStr for overlay::BlendMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(overlay::BlendMode::Normal),
+            "multiply" => Ok(overlay::BlendMode::Multiply),
+            "screen" => Ok(overlay::BlendMode::Screen),
+            "overlay" => Ok(overlay::BlendMode::Overlay),
+            v => Err(anyhow!("blend mode {} is not supported", v)),
+        }
+    }
+}
+
+impl FromStr for filter::Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oceanic" => Ok(filter::Filter::Oceanic),
+            "islands" => Ok(filter::Filter::Islands),
+            "marine" => Ok(filter::Filter::Marine),
+            v => Err(anyhow!("filter {} is not supported", v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Borrow;
+
+    #[test]
+    fn dsl_should_be_parsed() {
+        let spec = ImageSpec::from_dsl(
+            "crop(0, 0, 800, 600) | resize(400, 300, lanczos3) | fliph | filter(marine)",
+        )
+        .unwrap();
+        assert_eq!(
+            spec,
+            ImageSpec::new(vec![
+                Spec::new_crop(0, 0, 800, 600),
+                Spec::new_resize(400, 300, resize::SampleFilter::Lanczos3),
+                Spec::new_fliph(),
+                Spec::new_filter(filter::Filter::Marine),
+            ])
+        );
+    }
+
+    #[test]
+    fn focal_dsl_should_be_parsed() {
+        let spec =
+            ImageSpec::from_dsl("cover(400, 300, lanczos3, auto) | crop(0, 0, 200, 100, 0.3/0.6)")
+                .unwrap();
+        assert_eq!(
+            spec,
+            ImageSpec::new(vec![
+                Spec::new_resize_cover(400, 300, resize::SampleFilter::Lanczos3, Focal::auto()),
+                Spec::new_crop_focal(200, 100, Focal::point(0.3, 0.6)),
+            ])
+        );
+        assert!(ImageSpec::from_dsl("cover(400, 300, lanczos3, left)").is_err());
+    }
+
+    #[test]
+    fn overlay_dsl_should_be_parsed() {
+        let spec = ImageSpec::from_dsl(
+            "overlay(https://example.com/badge.png, 10, 20) | overlay(logo.png, 0, 0, 0.5, 0.8, screen)",
+        )
+        .unwrap();
+        assert_eq!(
+            spec,
+            ImageSpec::new(vec![
+                Spec::new_overlay(
+                    "https://example.com/badge.png",
+                    10,
+                    20,
+                    0.0,
+                    0.0,
+                    overlay::BlendMode::Normal
+                ),
+                Spec::new_overlay("logo.png", 0, 0, 0.5, 0.8, overlay::BlendMode::Screen),
+            ])
+        );
+        assert!(ImageSpec::from_dsl("overlay(logo.png, 0, 0, 1, 1, dodge)").is_err());
+        assert!(ImageSpec::from_dsl("overlay(logo.png, 0, 0, 1, 1, screen, 3)").is_err());
+    }
+
+    #[test]
+    fn color_dsl_should_be_parsed() {
+        let spec = ImageSpec::from_dsl(
+            "resize(400, 300, lanczos3, linear) | cover(100, 100, triangle, auto, linear) | background(#FFcc00)",
+        )
+        .unwrap();
+        assert_eq!(
+            spec,
+            ImageSpec::new(vec![
+                Spec::new_resize(400, 300, resize::SampleFilter::Lanczos3).with_linear(),
+                Spec::new_resize_cover(100, 100, resize::SampleFilter::Triangle, Focal::auto())
+                    .with_linear(),
+                Spec::new_background(0xffcc00),
+            ])
+        );
+        assert!(ImageSpec::from_dsl("background(ffcc00)").is_err());
+        assert!(ImageSpec::from_dsl("background(#fc0)").is_err());
+        assert!(ImageSpec::from_dsl("resize(400, 300, lanczos3, gamma)").is_err());
+    }
+
+    #[test]
+    fn trim_dsl_should_be_parsed() {
+        let spec = ImageSpec::from_dsl("trim | trim(10) | trim(10, 4) | trim()").unwrap();
+        assert_eq!(
+            spec,
+            ImageSpec::new(vec![
+                Spec::new_trim(0, 0),
+                Spec::new_trim(10, 0),
+                Spec::new_trim(10, 4),
+                Spec::new_trim(0, 0),
+            ])
+        );
+        assert!(ImageSpec::from_dsl("trim(10, 4, 2)").is_err());
+        assert!(ImageSpec::from_dsl("trim(-1)").is_err());
+    }
+
+    #[test]
+    fn output_dsl_should_be_parsed() {
+        let spec = ImageSpec::from_dsl(
+            "jpeg(80) | jpeg(0, progressive, 444, max=50000) | png | png(best, palette=64, max=8000)",
+        )
+        .unwrap();
+        assert_eq!(
+            spec,
+            ImageSpec::new(vec![
+                Spec::new_jpeg(80, false, output::ChromaSubsampling::Chroma420),
+                Spec::new_jpeg(0, true, output::ChromaSubsampling::Chroma444)
+                    .with_max_bytes(50000),
+                Spec::new_png(output::PngCompression::Default, 0),
+                Spec::new_png(output::PngCompression::Best, 64).with_max_bytes(8000),
+            ])
+        );
+        assert!(ImageSpec::from_dsl("jpeg").is_err());
+        assert!(ImageSpec::from_dsl("jpeg(80, interlaced)").is_err());
+        assert!(ImageSpec::from_dsl("png(palette=many)").is_err());
+        assert!(ImageSpec::from_dsl("png(max=-1)").is_err());
+    }
+
+    #[test]
+    fn invalid_dsl_should_fail() {
+        assert!(ImageSpec::from_dsl("resize(400)").is_err());
+        assert!(ImageSpec::from_dsl("blur(3)").is_err());
+        assert!(ImageSpec::from_dsl("filter(unknown)").is_err());
+        assert!(ImageSpec::from_dsl("crop(0, 0, 1, 1").is_err());
+    }
+
+    #[test]
+    fn parse_should_accept_dsl_and_base64() {
+        let spec = ImageSpec::new(vec![Spec::new_watermark(10, 20)]);
+        let s: String = spec.borrow().into();
+        assert_eq!(ImageSpec::parse(&s).unwrap(), spec);
+        assert_eq!(ImageSpec::parse("watermark(10, 20)").unwrap(), spec);
+    }
+}
@@ -1,14 +1,63 @@
+use anyhow::anyhow;
 use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
 use photon_rs::transform::SamplingFilter;
 use prost::Message;
 use std::convert::TryFrom;
 
 mod abi;
+mod dsl;
 pub use abi::*;  // 这样可以在其它mod里导入abi里的内容
 
+// 当前生成的ImageSpec的版本
+// 以后修改了某个字段的含义时增加版本号，并在upgrade里把旧版本的spec转换成新的写法，
+// 保证已经发布出去的url渲染结果不变；fixtures/specs下保存了各个版本的spec用来做回归测试
+pub const SPEC_VERSION: u32 = 1;
+
 impl ImageSpec {
     pub fn new(specs: Vec<Spec>) -> Self {
-        Self { specs }
+        Self {
+            specs,
+            version: SPEC_VERSION,
+        }
+    }
+
+    // 把旧版本的spec逐个版本转换到当前版本，比当前版本新的spec无法处理
+    pub fn upgrade(mut self) -> anyhow::Result<Self> {
+        if self.version > SPEC_VERSION {
+            return Err(anyhow!(
+                "spec version {} is newer than the supported version {}",
+                self.version,
+                SPEC_VERSION
+            ));
+        }
+        while self.version < SPEC_VERSION {
+            self = match self.version {
+                // v0 -> v1 只是加入了版本号，字段含义没有变化
+                0 => Self {
+                    version: 1,
+                    ..self
+                },
+                v => unreachable!("spec version {} has no upgrade", v),
+            };
+        }
+        Ok(self)
+    }
+
+    // 按顺序返回所有的Overlay spec，处理之前需要先加载它们引用的图片
+    pub fn overlays(&self) -> impl Iterator<Item = &Overlay> {
+        self.specs.iter().filter_map(|spec| match spec.data {
+            Some(spec::Data::Overlay(ref v)) => Some(v),
+            _ => None,
+        })
+    }
+
+    // 只包含每个spec的名字，用于日志，比如 resize|fliph|output
+    pub fn summary(&self) -> String {
+        self.specs
+            .iter()
+            .map(|spec| spec.kind())
+            .collect::<Vec<_>>()
+            .join("|")
     }
 }
 
@@ -21,12 +70,13 @@ impl From<&ImageSpec> for String {
 }
 
 // 让ImageSpec可以通过一个字符串创建，比如s.parse().unwrap()
+// 旧版本的spec会被转换成当前版本
 impl TryFrom<&str> for ImageSpec {
     type Error = anyhow::Error;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         let data = decode_config(value, URL_SAFE_NO_PAD)?;
-        Ok(ImageSpec::decode(&data[..])?)
+        ImageSpec::decode(&data[..])?.upgrade()
     }
 }
 
@@ -56,8 +106,53 @@ impl From<resize::SampleFilter> for SamplingFilter {
     }
 }
 
+impl Focal {
+    pub fn center() -> Self {
+        Self {
+            mode: focal::Mode::Center as i32,
+            x: 0.5,
+            y: 0.5,
+        }
+    }
+
+    // x, y 是相对于图片宽高的比例，取值0-1
+    pub fn point(x: f32, y: f32) -> Self {
+        Self {
+            mode: focal::Mode::Point as i32,
+            x,
+            y,
+        }
+    }
+
+    pub fn auto() -> Self {
+        Self {
+            mode: focal::Mode::Auto as i32,
+            x: 0.5,
+            y: 0.5,
+        }
+    }
+}
+
 // 提供一些辅助函数，让创建一个spec的过程简单一些
 impl Spec {
+    // spec的名字，用于日志和tracing
+    pub fn kind(&self) -> &'static str {
+        match self.data {
+            Some(spec::Data::Resize(_)) => "resize",
+            Some(spec::Data::Crop(_)) => "crop",
+            Some(spec::Data::Flipv(_)) => "flipv",
+            Some(spec::Data::Fliph(_)) => "fliph",
+            Some(spec::Data::Contrast(_)) => "contrast",
+            Some(spec::Data::Filter(_)) => "filter",
+            Some(spec::Data::Watermark(_)) => "watermark",
+            Some(spec::Data::Overlay(_)) => "overlay",
+            Some(spec::Data::Background(_)) => "background",
+            Some(spec::Data::Output(_)) => "output",
+            Some(spec::Data::Trim(_)) => "trim",
+            None => "empty",
+        }
+    }
+
     pub fn new_resize_seam_carve(width: u32, height: u32) -> Self {
         Self {
             data: Some(spec::Data::Resize(Resize {
@@ -65,6 +160,8 @@ impl Spec {
                 height,
                 rtype: resize::ResizeType::SeamCarve as i32,
                 filter: resize::SampleFilter::Undefined as i32,
+                focal: None,
+                linear: false,
             })),
         }
     }
@@ -76,10 +173,73 @@ impl Spec {
                 height,
                 rtype: resize::ResizeType::Normal as i32,
                 filter: filter as i32,
+                focal: None,
+                linear: false,
+            })),
+        }
+    }
+
+    pub fn new_resize_cover(
+        width: u32,
+        height: u32,
+        filter: resize::SampleFilter,
+        focal: Focal,
+    ) -> Self {
+        Self {
+            data: Some(spec::Data::Resize(Resize {
+                width,
+                height,
+                rtype: resize::ResizeType::Cover as i32,
+                filter: filter as i32,
+                focal: Some(focal),
+                linear: false,
             })),
         }
     }
 
+    pub fn new_crop(x1: u32, y1: u32, x2: u32, y2: u32) -> Self {
+        Self {
+            data: Some(spec::Data::Crop(Crop {
+                x1,
+                y1,
+                x2,
+                y2,
+                focal: None,
+            })),
+        }
+    }
+
+    // 截取width x height大小的区域，位置由焦点决定
+    pub fn new_crop_focal(width: u32, height: u32, focal: Focal) -> Self {
+        Self {
+            data: Some(spec::Data::Crop(Crop {
+                x1: 0,
+                y1: 0,
+                x2: width,
+                y2: height,
+                focal: Some(focal),
+            })),
+        }
+    }
+
+    pub fn new_fliph() -> Self {
+        Self {
+            data: Some(spec::Data::Fliph(Fliph {})),
+        }
+    }
+
+    pub fn new_flipv() -> Self {
+        Self {
+            data: Some(spec::Data::Flipv(Flipv {})),
+        }
+    }
+
+    pub fn new_contrast(contrast: f32) -> Self {
+        Self {
+            data: Some(spec::Data::Contrast(Contrast { contrast })),
+        }
+    }
+
     pub fn new_filter(filter: filter::Filter) -> Self {
         Self {
             data: Some(spec::Data::Filter(Filter {
@@ -88,11 +248,90 @@ impl Spec {
         }
     }
 
+    pub fn new_overlay(
+        url: impl Into<String>,
+        x: u32,
+        y: u32,
+        scale: f32,
+        opacity: f32,
+        blend: overlay::BlendMode,
+    ) -> Self {
+        Self {
+            data: Some(spec::Data::Overlay(Overlay {
+                url: url.into(),
+                x,
+                y,
+                scale,
+                opacity,
+                blend: blend as i32,
+            })),
+        }
+    }
+
     pub fn new_watermark(x: u32, y: u32) -> Self {
         Self {
             data: Some(spec::Data::Watermark(Watermark {x, y})),
         }
     }
+
+    // tolerance是和边框颜色允许的差别，padding是裁剪后保留的边距
+    pub fn new_trim(tolerance: u32, padding: u32) -> Self {
+        Self {
+            data: Some(spec::Data::Trim(Trim { tolerance, padding })),
+        }
+    }
+
+    // color是0xRRGGBB
+    pub fn new_background(color: u32) -> Self {
+        Self {
+            data: Some(spec::Data::Background(Background { color })),
+        }
+    }
+
+    // quality为0时使用默认的85
+    pub fn new_jpeg(
+        quality: u32,
+        progressive: bool,
+        subsampling: output::ChromaSubsampling,
+    ) -> Self {
+        Self {
+            data: Some(spec::Data::Output(Output {
+                format: output::Format::Jpeg as i32,
+                quality,
+                progressive,
+                subsampling: subsampling as i32,
+                ..Default::default()
+            })),
+        }
+    }
+
+    // palette为0时输出RGBA的PNG，否则量化成最多palette种颜色
+    pub fn new_png(compression: output::PngCompression, palette: u32) -> Self {
+        Self {
+            data: Some(spec::Data::Output(Output {
+                format: output::Format::Png as i32,
+                compression: compression as i32,
+                palette,
+                ..Default::default()
+            })),
+        }
+    }
+
+    // 让resize在线性空间中进行，对其他spec没有影响
+    pub fn with_linear(mut self) -> Self {
+        if let Some(spec::Data::Resize(ref mut v)) = self.data {
+            v.linear = true;
+        }
+        self
+    }
+
+    // 设置输出大小的上限，只对Output有效
+    pub fn with_max_bytes(mut self, max_bytes: u32) -> Self {
+        if let Some(spec::Data::Output(ref mut v)) = self.data {
+            v.max_bytes = max_bytes;
+        }
+        self
+    }
 }
 
 #[cfg(test)]
@@ -109,4 +348,33 @@ mod tests {
         let s: String = image_spec.borrow().into();
         assert_eq!(image_spec, s.as_str().try_into().unwrap());
     }
+
+    #[test]
+    fn old_versions_should_be_upgraded() {
+        // 加入版本号之前生成的spec：resize(64, 48, catmull_rom)
+        let spec: ImageSpec = "CggKBghAEDAgAw".try_into().unwrap();
+        assert_eq!(spec.version, SPEC_VERSION);
+        assert_eq!(
+            spec.specs,
+            vec![Spec::new_resize(64, 48, resize::SampleFilter::CatmullRom)]
+        );
+
+        let newer = ImageSpec {
+            version: SPEC_VERSION + 1,
+            ..ImageSpec::new(vec![Spec::new_fliph()])
+        };
+        let s: String = newer.borrow().into();
+        assert!(ImageSpec::try_from(s.as_str()).is_err());
+    }
+
+    #[test]
+    fn summary_should_list_spec_kinds() {
+        let image_spec = ImageSpec::new(vec![
+            Spec::new_resize(600, 600, resize::SampleFilter::CatmullRom),
+            Spec::new_fliph(),
+            Spec::new_png(output::PngCompression::Best, 0),
+        ]);
+        assert_eq!(image_spec.summary(), "resize|fliph|output");
+        assert_eq!(ImageSpec::default().summary(), "");
+    }
 }
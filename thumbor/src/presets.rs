@@ -0,0 +1,152 @@
+// 命名的preset：把常用的spec组合定义在配置文件里，通过 /preset/:name/:url 使用
+// 配置文件是toml格式，每一项是 名字 = "spec"，spec可以是文本格式或者base64：
+//   thumb = "resize(150, 150, lanczos3)"
+//   avatar = "crop(0, 0, 400, 400) | resize(96, 96, catmull_rom)"
+use crate::pb::ImageSpec;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Borrow,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::RwLock, task::JoinHandle};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+    // 配置文件里的原始定义
+    pub definition: String,
+    pub spec: ImageSpec,
+}
+
+// 列出preset时返回的信息
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct PresetInfo {
+    pub name: String,
+    pub definition: String,
+    // base64编码后的ImageSpec
+    pub spec: String,
+}
+
+#[derive(Clone, Default)]
+pub struct Presets(Arc<RwLock<BTreeMap<String, Preset>>>);
+
+impl Presets {
+    pub fn parse(content: &str) -> Result<BTreeMap<String, Preset>> {
+        let raw: BTreeMap<String, String> = toml::from_str(content)?;
+        raw.into_iter()
+            .map(|(name, definition)| {
+                let spec = ImageSpec::parse(&definition)
+                    .map_err(|e| anyhow!("preset {} is invalid: {}", name, e))?;
+                Ok((name, Preset { definition, spec }))
+            })
+            .collect()
+    }
+
+    // 读取配置文件并整体替换当前的preset，文件有任何错误都保留原来的preset
+    pub async fn load(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let content = tokio::fs::read_to_string(path).await?;
+        let presets = Self::parse(&content)?;
+        let count = presets.len();
+        *self.0.write().await = presets;
+        Ok(count)
+    }
+
+    pub async fn get(&self, name: &str) -> Option<ImageSpec> {
+        self.0.read().await.get(name).map(|p| p.spec.clone())
+    }
+
+    pub async fn list(&self) -> Vec<PresetInfo> {
+        self.0
+            .read()
+            .await
+            .iter()
+            .map(|(name, preset)| PresetInfo {
+                name: name.clone(),
+                definition: preset.definition.clone(),
+                spec: preset.spec.borrow().into(),
+            })
+            .collect()
+    }
+
+    // 定时检查配置文件的修改时间，有变化就重新加载，不需要重启服务
+    pub fn watch(&self, path: PathBuf, interval: Duration) -> JoinHandle<()> {
+        let presets = self.clone();
+        tokio::spawn(async move {
+            let mut last = modified(&path).await;
+            loop {
+                tokio::time::sleep(interval).await;
+                let current = modified(&path).await;
+                if current == last {
+                    continue;
+                }
+                last = current;
+                match presets.load(&path).await {
+                    Ok(n) => info!("Reloaded {} presets from {}", n, path.display()),
+                    Err(e) => warn!("Failed to reload presets from {}: {}", path.display(), e),
+                }
+            }
+        })
+    }
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::*;
+
+    #[test]
+    fn presets_should_be_parsed() {
+        let presets = Presets::parse(
+            r#"
+            thumb = "resize(150, 150, lanczos3)"
+            mirror = "fliph | flipv"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(presets.len(), 2);
+        assert_eq!(
+            presets["mirror"].spec,
+            ImageSpec::new(vec![Spec::new_fliph(), Spec::new_flipv()])
+        );
+
+        assert!(Presets::parse(r#"bad = "blur(3)""#).is_err());
+    }
+
+    #[tokio::test]
+    async fn reload_should_keep_old_presets_on_error() {
+        let path =
+            std::env::temp_dir().join(format!("thumbor-presets-{}.toml", std::process::id()));
+        let presets = Presets::default();
+
+        tokio::fs::write(&path, r#"thumb = "fliph""#).await.unwrap();
+        assert_eq!(presets.load(&path).await.unwrap(), 1);
+        assert!(presets.get("thumb").await.is_some());
+
+        tokio::fs::write(&path, r#"thumb = "not a spec""#)
+            .await
+            .unwrap();
+        assert!(presets.load(&path).await.is_err());
+        assert_eq!(
+            presets.get("thumb").await,
+            Some(ImageSpec::new(vec![Spec::new_fliph()]))
+        );
+
+        tokio::fs::write(&path, r#"hero = "flipv""#).await.unwrap();
+        presets.load(&path).await.unwrap();
+        assert!(presets.get("thumb").await.is_none());
+        assert_eq!(presets.list().await[0].name, "hero");
+
+        tokio::fs::remove_file(&path).await.ok();
+    }
+}
@@ -0,0 +1,287 @@
+// 按客户端限流：配置过的x-api-key请求头作为客户端标识，否则使用来源ip
+// 每个客户端有两个令牌桶，源图片已经在缓存中的请求和需要获取源图片的请求分开计算，
+// 另外还限制每个客户端同时在处理的请求数
+use lru::LruCache;
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+// 最多记录这么多个客户端的状态，超出时淘汰最久没有请求的
+const MAX_CLIENTS: usize = 10_000;
+
+// 令牌桶：最多攒burst个令牌，每秒补充per_second个，burst为0表示不限制
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl Quota {
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self { burst, per_second }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(0, 0.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+    // 源图片已经在缓存中，只需要做处理
+    pub hit: Quota,
+    // 需要先获取源图片，代价更高
+    pub miss: Quota,
+    // 每个客户端同时处理的请求数，0表示不限制
+    pub max_concurrent: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            hit: Quota::new(100, 50.0),
+            miss: Quota::new(20, 5.0),
+            max_concurrent: 8,
+        }
+    }
+}
+
+// 请求里能拿到的客户端信息
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientId {
+    pub api_key: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+// 被限流时返回，retry_after是建议客户端等待的时间
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limited {
+    pub retry_after: Duration,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(quota: &Quota, now: Instant) -> Self {
+        Self {
+            tokens: quota.burst as f64,
+            updated: now,
+        }
+    }
+
+    // 先按经过的时间补充令牌，再取走一个；不够时返回还需要等待的时间
+    fn take(&mut self, quota: &Quota, now: Instant) -> Result<(), Duration> {
+        if quota.burst == 0 {
+            return Ok(());
+        }
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * quota.per_second).min(quota.burst as f64);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if quota.per_second > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / quota.per_second))
+        } else {
+            Err(Duration::from_secs(u32::MAX as u64))
+        }
+    }
+}
+
+struct ClientState {
+    hit: Bucket,
+    miss: Bucket,
+    in_flight: u32,
+}
+
+// 状态只在很短的同步代码里访问，Permit在drop时也需要修改，所以用std的Mutex
+#[derive(Clone)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    api_keys: Arc<HashSet<String>>,
+    clients: Arc<Mutex<LruCache<String, ClientState>>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimits::default())
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            api_keys: Arc::new(HashSet::new()),
+            clients: Arc::new(Mutex::new(LruCache::new(MAX_CLIENTS))),
+        }
+    }
+
+    // 只有配置过的api key才单独计算，否则随便换一个key就能绕过限流
+    pub fn with_api_keys(mut self, keys: impl IntoIterator<Item = String>) -> Self {
+        self.api_keys = Arc::new(keys.into_iter().collect());
+        self
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    // 客户端在限流里使用的key
+    pub fn client_key(&self, client: &ClientId) -> String {
+        match (&client.api_key, client.ip) {
+            (Some(key), _) if self.api_keys.contains(key) => format!("key:{}", key),
+            (_, Some(ip)) => format!("ip:{}", ip),
+            _ => "anonymous".to_string(),
+        }
+    }
+
+    // 成功时返回Permit，请求处理完之前一直持有，drop时释放并发名额
+    pub fn acquire(&self, client: &ClientId, cache_hit: bool) -> Result<Permit, Limited> {
+        let key = self.client_key(client);
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if !clients.contains(&key) {
+            let state = ClientState {
+                hit: Bucket::new(&self.limits.hit, now),
+                miss: Bucket::new(&self.limits.miss, now),
+                in_flight: 0,
+            };
+            clients.put(key.clone(), state);
+        }
+        let state = clients.get_mut(&key).unwrap();
+
+        // 并发请求数超出时，大致等一秒再重试
+        if self.limits.max_concurrent > 0 && state.in_flight >= self.limits.max_concurrent {
+            return Err(Limited {
+                retry_after: Duration::from_secs(1),
+            });
+        }
+
+        let result = match cache_hit {
+            true => state.hit.take(&self.limits.hit, now),
+            false => state.miss.take(&self.limits.miss, now),
+        };
+        result.map_err(|retry_after| Limited { retry_after })?;
+
+        state.in_flight += 1;
+        Ok(Permit {
+            limiter: self.clone(),
+            key,
+        })
+    }
+}
+
+pub struct Permit {
+    limiter: RateLimiter,
+    key: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().unwrap();
+        // 客户端状态可能已经被LRU淘汰，这时不需要处理
+        if let Some(state) = clients.get_mut(&self.key) {
+            state.in_flight = state.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> ClientId {
+        ClientId {
+            api_key: None,
+            ip: Some(s.parse().unwrap()),
+        }
+    }
+
+    fn limiter(hit: Quota, miss: Quota, max_concurrent: u32) -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            hit,
+            miss,
+            max_concurrent,
+        })
+    }
+
+    #[test]
+    fn bucket_should_refill_over_time() {
+        let quota = Quota::new(2, 4.0);
+        let now = Instant::now();
+        let mut bucket = Bucket::new(&quota, now);
+
+        assert!(bucket.take(&quota, now).is_ok());
+        assert!(bucket.take(&quota, now).is_ok());
+        let wait = bucket.take(&quota, now).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(250));
+
+        // 经过10秒令牌补满，但不会超过burst
+        let later = now + Duration::from_secs(10);
+        assert!(bucket.take(&quota, later).is_ok());
+        assert!(bucket.take(&quota, later).is_ok());
+        assert!(bucket.take(&quota, later).is_err());
+
+        let mut unlimited = Bucket::new(&Quota::unlimited(), now);
+        for _ in 0..100 {
+            assert!(unlimited.take(&Quota::unlimited(), now).is_ok());
+        }
+    }
+
+    #[test]
+    fn hits_and_misses_should_use_separate_buckets() {
+        let limiter = limiter(Quota::new(3, 0.001), Quota::new(1, 0.001), 0);
+        let client = ip("10.0.0.1");
+
+        assert!(limiter.acquire(&client, false).is_ok());
+        let limited = limiter.acquire(&client, false).err().unwrap();
+        assert!(limited.retry_after > Duration::from_secs(60));
+
+        // miss用完了不影响hit
+        for _ in 0..3 {
+            assert!(limiter.acquire(&client, true).is_ok());
+        }
+        assert!(limiter.acquire(&client, true).is_err());
+
+        // 其他客户端不受影响
+        assert!(limiter.acquire(&ip("10.0.0.2"), false).is_ok());
+    }
+
+    #[test]
+    fn concurrency_should_be_limited_per_client() {
+        let limiter = limiter(Quota::unlimited(), Quota::unlimited(), 2);
+        let client = ip("10.0.0.1");
+
+        let first = limiter.acquire(&client, true).unwrap();
+        let _second = limiter.acquire(&client, false).unwrap();
+        let limited = limiter.acquire(&client, true).err().unwrap();
+        assert_eq!(limited.retry_after, Duration::from_secs(1));
+
+        // 一个请求处理完之后可以继续
+        drop(first);
+        assert!(limiter.acquire(&client, true).is_ok());
+    }
+
+    #[test]
+    fn only_known_api_keys_should_be_used() {
+        let limiter = RateLimiter::default().with_api_keys(vec!["known".to_string()]);
+        let client = |key: &str| ClientId {
+            api_key: Some(key.to_string()),
+            ip: Some("10.0.0.1".parse().unwrap()),
+        };
+
+        assert_eq!(limiter.client_key(&client("known")), "key:known");
+        assert_eq!(limiter.client_key(&client("random")), "ip:10.0.0.1");
+        assert_eq!(limiter.client_key(&ClientId::default()), "anonymous");
+    }
+}
@@ -0,0 +1,649 @@
+use crate::{
+    cache::{Cache, CacheStats, Processed, Purge, PurgeResult},
+    client::UrlSigner,
+    engine::{
+        optimize, probe_dimensions, validate, Engine, InputFormat, Limits, Photon, Placeholder,
+    },
+    pb::*,
+    presets::{PresetInfo, Presets},
+    ratelimit::{ClientId, Permit, RateLimiter, API_KEY_HEADER},
+    trace::{AccessRecord, RequestTraceLayer},
+};
+use anyhow::{anyhow, Result};
+use axum::{
+    async_trait,
+    body::Full,
+    extract::{ConnectInfo, Extension, FromRequest, Path, Query, RequestParts},
+    handler::{get, post},
+    http::{HeaderMap, HeaderValue, Response, StatusCode},
+    response::IntoResponse,
+    routing::BoxRoute,
+    AddExtensionLayer, Json, Router,
+};
+use bytes::Bytes;
+use image::ImageOutputFormat;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    convert::{Infallible, TryInto},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tower::ServiceBuilder;
+use tracing::{info, info_span, instrument, warn};
+
+// 参数使用serde 做Deserialize, axum会自动识别并解析
+#[derive(Deserialize)]
+struct Params {
+    spec: String,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct PlaceholderParams {
+    kind: String,
+    spec: String,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct PresetParams {
+    name: String,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct PresetWithSpecParams {
+    name: String,
+    spec: String,
+    url: String,
+}
+
+// 配置了签名key时，请求需要带上?sig=
+#[derive(Deserialize)]
+struct SignatureParams {
+    sig: Option<String>,
+}
+
+// 占位信息的返回格式，kind字段标明是哪一种占位
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PlaceholderBody {
+    Blurhash { hash: String },
+    Lqip { uri: String },
+    Palette { colors: Vec<String> },
+}
+
+// 支持的占位类型，对应url里的:kind
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaceholderKind {
+    Blurhash,
+    Lqip,
+    Palette,
+}
+
+impl PlaceholderKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlaceholderKind::Blurhash => "blurhash",
+            PlaceholderKind::Lqip => "lqip",
+            PlaceholderKind::Palette => "palette",
+        }
+    }
+}
+
+impl FromStr for PlaceholderKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blurhash" => Ok(PlaceholderKind::Blurhash),
+            "lqip" => Ok(PlaceholderKind::Lqip),
+            "palette" => Ok(PlaceholderKind::Palette),
+            v => Err(anyhow!("placeholder kind {} is not supported", v)),
+        }
+    }
+}
+
+// handler返回的错误，被限流时带上Retry-After
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppError {
+    Status(StatusCode),
+    RateLimited(Duration),
+}
+
+impl From<StatusCode> for AppError {
+    fn from(status: StatusCode) -> Self {
+        AppError::Status(status)
+    }
+}
+
+impl IntoResponse for AppError {
+    type Body = Full<Bytes>;
+    type BodyError = Infallible;
+
+    fn into_response(self) -> Response<Self::Body> {
+        let mut res = Response::new(Full::new(Bytes::new()));
+        match self {
+            AppError::Status(status) => *res.status_mut() = status,
+            AppError::RateLimited(retry_after) => {
+                *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                // Retry-After只能是整数秒，向上取整
+                let secs = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+                res.headers_mut()
+                    .insert("retry-after", HeaderValue::from(secs.max(1)));
+            }
+        }
+        res
+    }
+}
+
+// 从请求头里取api key，从连接信息里取来源ip；测试里没有连接信息时ip为None
+#[async_trait]
+impl<B: Send> FromRequest<B> for ClientId {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let api_key = req
+            .headers()
+            .and_then(|headers| headers.get(API_KEY_HEADER))
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let ip = req
+            .extensions()
+            .and_then(|ext| ext.get::<ConnectInfo<SocketAddr>>())
+            .map(|ConnectInfo(addr)| addr.ip());
+        Ok(ClientId { api_key, ip })
+    }
+}
+
+// admin api使用的token，请求需要带上 Authorization: Bearer <token>
+#[derive(Clone)]
+pub struct AdminToken(Arc<String>);
+
+impl AdminToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(Arc::new(token.into()))
+    }
+
+    // 逐字节比较完所有内容，耗时不会泄露匹配了多少
+    pub fn verify(&self, token: &str) -> bool {
+        let (a, b) = (self.0.as_bytes(), token.as_bytes());
+        a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+}
+
+// admin handler的参数里带上Admin，没有通过认证的请求不会进入handler
+// 没有配置token时admin api不开放，返回404
+pub struct Admin;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Admin {
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let token = req
+            .extensions()
+            .and_then(|ext| ext.get::<Option<AdminToken>>())
+            .cloned()
+            .flatten()
+            .ok_or(StatusCode::NOT_FOUND)?;
+        let bearer = req
+            .headers()
+            .and_then(|headers| headers.get("authorization"))
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        match bearer {
+            Some(v) if token.verify(v) => Ok(Admin),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+// 清除缓存的请求，url、prefix和all只能指定一个
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PurgeRequest {
+    pub url: Option<String>,
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub all: bool,
+}
+
+impl PurgeRequest {
+    fn into_purge(self) -> Option<Purge> {
+        match (self.url, self.prefix, self.all) {
+            (Some(url), None, false) => Some(Purge::Url(url)),
+            (None, Some(prefix), false) if !prefix.is_empty() => Some(Purge::Prefix(prefix)),
+            (None, None, true) => Some(Purge::All),
+            _ => None,
+        }
+    }
+}
+
+// 服务是否可以接收新请求，draining期间为false
+#[derive(Clone)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn drain(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 服务运行需要的状态，每一项都会作为Extension注入到handler
+#[derive(Clone)]
+pub struct AppState {
+    pub cache: Cache,
+    pub readiness: Readiness,
+    pub limits: Limits,
+    pub signer: Option<UrlSigner>,
+    pub presets: Presets,
+    pub limiter: RateLimiter,
+    pub admin_token: Option<AdminToken>,
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self {
+            cache: Cache::default(),
+            readiness: Readiness::new(),
+            limits: Limits::default(),
+            signer: None,
+            presets: Presets::default(),
+            limiter: RateLimiter::default(),
+            admin_token: None,
+        }
+    }
+}
+
+// 构建路由，测试里可以不启动http服务直接调用
+pub fn app(state: AppState) -> Router<BoxRoute> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/image/:spec/:url", get(generate))
+        .route("/placeholder/:kind/:spec/:url", get(placeholder))
+        .route("/presets", get(list_presets))
+        .route("/preset/:name/:url", get(preset))
+        .route("/preset/:name/:spec/:url", get(preset_with_spec))
+        .route("/admin/cache/stats", get(cache_stats))
+        .route("/admin/cache/purge", post(purge_cache))
+        .layer(
+            ServiceBuilder::new()
+                .layer(RequestTraceLayer)
+                .layer(AddExtensionLayer::new(state.cache))
+                .layer(AddExtensionLayer::new(state.readiness))
+                .layer(AddExtensionLayer::new(state.limits))
+                .layer(AddExtensionLayer::new(state.signer))
+                .layer(AddExtensionLayer::new(state.presets))
+                .layer(AddExtensionLayer::new(state.limiter))
+                .layer(AddExtensionLayer::new(state.admin_token))
+                .into_inner(),
+        )
+        .boxed()
+}
+
+// 存活检查：进程能响应就认为存活
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+// 就绪检查：draining期间返回503
+async fn readyz(Extension(readiness): Extension<Readiness>) -> (StatusCode, &'static str) {
+    if readiness.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "draining")
+    }
+}
+
+async fn generate(
+    Path(Params {spec, url}): Path<Params>,
+    Query(SignatureParams { sig }): Query<SignatureParams>,
+    Extension(cache): Extension<Cache>,
+    Extension(limits): Extension<Limits>,
+    Extension(signer): Extension<Option<UrlSigner>>,
+    Extension(limiter): Extension<RateLimiter>,
+    client: ClientId,
+    Extension(record): Extension<AccessRecord>,
+) -> Result<(HeaderMap, Bytes), AppError> {
+    let url = percent_decode_str(&url).decode_utf8_lossy().to_string();
+    verify_signature(signer.as_ref(), &spec, &url, sig.as_deref())?;
+    let _permit = admit(&limiter, &client, &url, &cache).await?;
+
+    let spec = parse_spec(&spec)?;
+    let processed = generate_image(&spec, &url, cache, limits, &record).await?;
+    Ok(image_response(processed))
+}
+
+// 使用配置里的preset处理图片
+async fn preset(
+    Path(PresetParams { name, url }): Path<PresetParams>,
+    Query(SignatureParams { sig }): Query<SignatureParams>,
+    Extension(cache): Extension<Cache>,
+    Extension(limits): Extension<Limits>,
+    Extension(signer): Extension<Option<UrlSigner>>,
+    Extension(presets): Extension<Presets>,
+    Extension(limiter): Extension<RateLimiter>,
+    client: ClientId,
+    Extension(record): Extension<AccessRecord>,
+) -> Result<(HeaderMap, Bytes), AppError> {
+    let url = percent_decode_str(&url).decode_utf8_lossy().to_string();
+    verify_signature(signer.as_ref(), &preset_key(&name, None), &url, sig.as_deref())?;
+    let _permit = admit(&limiter, &client, &url, &cache).await?;
+
+    let spec = presets.get(&name).await.ok_or(StatusCode::NOT_FOUND)?;
+    let processed = generate_image(&spec, &url, cache, limits, &record).await?;
+    Ok(image_response(processed))
+}
+
+// 先执行preset里的spec，再执行url里额外的spec
+async fn preset_with_spec(
+    Path(PresetWithSpecParams { name, spec, url }): Path<PresetWithSpecParams>,
+    Query(SignatureParams { sig }): Query<SignatureParams>,
+    Extension(cache): Extension<Cache>,
+    Extension(limits): Extension<Limits>,
+    Extension(signer): Extension<Option<UrlSigner>>,
+    Extension(presets): Extension<Presets>,
+    Extension(limiter): Extension<RateLimiter>,
+    client: ClientId,
+    Extension(record): Extension<AccessRecord>,
+) -> Result<(HeaderMap, Bytes), AppError> {
+    let url = percent_decode_str(&url).decode_utf8_lossy().to_string();
+    verify_signature(signer.as_ref(), &preset_key(&name, Some(&spec)), &url, sig.as_deref())?;
+    let _permit = admit(&limiter, &client, &url, &cache).await?;
+
+    let extra = parse_spec(&spec)?;
+    let mut spec = presets.get(&name).await.ok_or(StatusCode::NOT_FOUND)?;
+    spec.specs.extend(extra.specs);
+    let processed = generate_image(&spec, &url, cache, limits, &record).await?;
+    Ok(image_response(processed))
+}
+
+async fn cache_stats(_: Admin, Extension(cache): Extension<Cache>) -> Json<CacheStats> {
+    Json(cache.stats().await)
+}
+
+async fn purge_cache(
+    _: Admin,
+    Json(req): Json<PurgeRequest>,
+    Extension(cache): Extension<Cache>,
+) -> Result<Json<PurgeResult>, StatusCode> {
+    let purge = req.into_purge().ok_or(StatusCode::BAD_REQUEST)?;
+    let result = cache.purge(&purge).await;
+    info!(
+        "Purged {:?}: {} raw, {} processed",
+        purge, result.raw, result.processed
+    );
+    Ok(Json(result))
+}
+
+async fn list_presets(Extension(presets): Extension<Presets>) -> Json<Vec<PresetInfo>> {
+    Json(presets.list().await)
+}
+
+// preset url签名时用来代替spec的部分
+pub(crate) fn preset_key(name: &str, spec: Option<&str>) -> String {
+    match spec {
+        Some(spec) => format!("preset:{}/{}", name, spec),
+        None => format!("preset:{}", name),
+    }
+}
+
+// 先查处理结果的缓存，没有命中时获取源图片处理，编码后放入缓存
+pub(crate) async fn generate_image(
+    spec: &ImageSpec,
+    url: &str,
+    cache: Cache,
+    limits: Limits,
+    record: &AccessRecord,
+) -> Result<Processed, StatusCode> {
+    let key: String = spec.into();
+    let processed = match cache.get_processed(&key, url).await {
+        Some(v) => {
+            describe(spec, url, record);
+            record.update(|info| info.cache = Some("processed"));
+            v
+        }
+        None => {
+            let engine = process(spec, url, cache.clone(), limits, record).await?;
+            let processed = render(engine);
+            cache.put_processed(&key, url, processed.clone()).await;
+            processed
+        }
+    };
+
+    info!("Finished processing: image size {}", processed.data.len());
+    record.update(|info| info.output_size = Some(processed.data.len()));
+    Ok(processed)
+}
+
+fn image_response(processed: Processed) -> (HeaderMap, Bytes) {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static(processed.content_type));
+    (headers, processed.data)
+}
+
+// 输出格式由spec里的Output决定，默认是JPEG
+pub(crate) fn render(engine: Photon) -> Processed {
+    let format = engine.format();
+    let content_type = match format {
+        ImageOutputFormat::Png => "image/png",
+        _ => "image/jpeg",
+    };
+    let image = info_span!("encode", content_type).in_scope(|| engine.generate(format));
+
+    Processed {
+        data: image.into(),
+        content_type,
+    }
+}
+
+async fn placeholder(
+    Path(PlaceholderParams {kind, spec, url}): Path<PlaceholderParams>,
+    Query(SignatureParams { sig }): Query<SignatureParams>,
+    Extension(cache): Extension<Cache>,
+    Extension(limits): Extension<Limits>,
+    Extension(signer): Extension<Option<UrlSigner>>,
+    Extension(limiter): Extension<RateLimiter>,
+    client: ClientId,
+    Extension(record): Extension<AccessRecord>,
+) -> Result<Json<PlaceholderBody>, AppError> {
+    let kind: PlaceholderKind = kind.parse().map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let url = percent_decode_str(&url).decode_utf8_lossy().to_string();
+    verify_signature(signer.as_ref(), &spec, &url, sig.as_deref())?;
+    let _permit = admit(&limiter, &client, &url, &cache).await?;
+
+    let spec = parse_spec(&spec)?;
+    let engine = process(&spec, &url, cache, limits, &record).await?;
+
+    let body = match kind {
+        PlaceholderKind::Blurhash => PlaceholderBody::Blurhash {
+            hash: engine
+                .blurhash(4, 3)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        },
+        PlaceholderKind::Lqip => PlaceholderBody::Lqip { uri: engine.lqip(16) },
+        PlaceholderKind::Palette => PlaceholderBody::Palette { colors: engine.palette(5) },
+    };
+
+    info!("Finished placeholder: {}", kind.as_str());
+
+    Ok(Json(body))
+}
+
+// 没有配置签名key时不做检查
+fn verify_signature(
+    signer: Option<&UrlSigner>,
+    spec: &str,
+    url: &str,
+    sig: Option<&str>,
+) -> Result<(), StatusCode> {
+    match (signer, sig) {
+        (None, _) => Ok(()),
+        (Some(signer), Some(sig)) if signer.verify(spec, url, sig) => Ok(()),
+        _ => Err(StatusCode::FORBIDDEN),
+    }
+}
+
+fn parse_spec(spec: &str) -> Result<ImageSpec, StatusCode> {
+    spec.try_into().map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+// 按客户端限流，源图片已经在缓存里的请求使用单独的配额
+async fn admit(
+    limiter: &RateLimiter,
+    client: &ClientId,
+    url: &str,
+    cache: &Cache,
+) -> Result<Permit, AppError> {
+    let hit = cache.contains_raw(url).await;
+    limiter.acquire(client, hit).map_err(|limited| {
+        warn!(
+            "Rate limited {} for {:?}",
+            limiter.client_key(client),
+            limited.retry_after
+        );
+        AppError::RateLimited(limited.retry_after)
+    })
+}
+
+// 在访问日志里记录spec和源图片的host
+fn describe(spec: &ImageSpec, url: &str, record: &AccessRecord) {
+    record.update(|info| {
+        info.spec = Some(spec.summary());
+        info.source_host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(|v| v.to_string()));
+    });
+}
+
+// 获取源图片，然后按照spec的顺序处理
+async fn process(
+    spec: &ImageSpec,
+    url: &str,
+    cache: Cache,
+    limits: Limits,
+    record: &AccessRecord,
+) -> Result<Photon, StatusCode> {
+    describe(spec, url, record);
+    // 获取源图片之前先检查spec本身
+    check_spec(spec)?;
+
+    let (data, hit) = retrieve_image(url, cache.clone())
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    record.update(|info| info.cache = Some(if hit { "hit" } else { "miss" }));
+    process_data(spec, url, data, cache, limits).await
+}
+
+pub(crate) fn check_spec(spec: &ImageSpec) -> Result<(), StatusCode> {
+    validate(&spec.specs).map_err(|e| {
+        warn!("Invalid spec: {}", e);
+        StatusCode::BAD_REQUEST
+    })
+}
+
+// 处理已经获取到的源图片，调用前需要先check_spec；source只用于日志
+pub(crate) async fn process_data(
+    spec: &ImageSpec,
+    source: &str,
+    data: Bytes,
+    cache: Cache,
+    limits: Limits,
+) -> Result<Photon, StatusCode> {
+    let format = sniff(source, &data)?;
+
+    // 解码之前只读图片头，检查输入尺寸和每个spec的输出尺寸
+    let (width, height) =
+        probe_dimensions(&data).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    limits
+        .check_input(width, height)
+        .and_then(|_| limits.check_specs(width, height, &spec.specs))
+        .map_err(|e| {
+            warn!("Rejected {}: {}", source, e);
+            StatusCode::UNPROCESSABLE_ENTITY
+        })?;
+    let specs = optimize(&spec.specs, width, height).map_err(|e| {
+        warn!("Rejected {}: {}", source, e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    // 使用image engine 处理，SVG按第一个Resize的尺寸栅格化
+    let (mut engine, specs) = info_span!("decode", format = format.as_str(), width, height)
+        .in_scope(|| Photon::load(&data, &specs))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // overlay用到的图片和源图片一样通过缓存获取，同样要先检查尺寸
+    let mut loaded = HashSet::new();
+    for overlay in spec.overlays() {
+        let (data, _) = retrieve_image(&overlay.url, cache.clone())
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        sniff(&overlay.url, &data)?;
+        let (width, height) =
+            probe_dimensions(&data).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        limits
+            .check_overlay(width, height, overlay)
+            .map_err(|e| {
+                warn!("Rejected overlay {}: {}", overlay.url, e);
+                StatusCode::UNPROCESSABLE_ENTITY
+            })?;
+        if loaded.insert(overlay.url.as_str()) {
+            engine
+                .add_source(&overlay.url, data)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+    }
+    engine.apply(&specs);
+
+    Ok(engine)
+}
+
+// 按文件头识别图片格式，不支持的格式返回415
+fn sniff(url: &str, data: &[u8]) -> Result<InputFormat, StatusCode> {
+    match InputFormat::sniff(data) {
+        Some(format) if format.is_supported() => Ok(format),
+        format => {
+            warn!("Unsupported input {}: {:?}", url, format);
+            Err(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        }
+    }
+}
+
+// 返回图片数据，以及是否命中了缓存
+// 获取源图片时不持有缓存的锁，避免一个慢请求挡住其他请求
+#[instrument(name = "fetch", level = "info", skip(cache))]
+async fn retrieve_image(url: &str, cache: Cache) -> Result<(Bytes, bool)> {
+    if let Some(data) = cache.get_raw(url).await {
+        info!("Match cache");
+        return Ok((data, true));
+    }
+
+    info!("Retrieve url");
+    let resp = reqwest::get(url).await?;
+    let data = resp.bytes().await?;
+    cache.put_raw(url, data.clone()).await;
+    Ok((data, false))
+}
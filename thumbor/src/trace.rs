@@ -0,0 +1,179 @@
+// 请求追踪：给每个请求分配X-Request-Id（客户端传了就沿用），处理过程中的span都挂在
+// 这个请求的span下面，请求结束时输出一行JSON格式的访问日志
+use axum::http::{HeaderValue, Request, Response};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tower::{Layer, Service};
+use tracing::{info, info_span, Instrument};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// 访问日志使用的target，可以用RUST_LOG单独控制
+pub const ACCESS_LOG_TARGET: &str = "thumbor::access";
+
+// 客户端传入的request id超过这个长度就重新生成，避免日志被撑大
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+lazy_static! {
+    // 生成的request id以进程启动时间开头，重启后不会重复
+    static ref START: u64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// 访问日志里由handler填写的部分，没有填写的字段输出为null
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct AccessInfo {
+    pub spec: Option<String>,
+    pub source_host: Option<String>,
+    pub cache: Option<&'static str>,
+    pub output_size: Option<usize>,
+}
+
+// 放在请求的extensions里，handler通过Extension<AccessRecord>取出来填写
+#[derive(Debug, Clone, Default)]
+pub struct AccessRecord(Arc<Mutex<AccessInfo>>);
+
+impl AccessRecord {
+    pub fn update(&self, f: impl FnOnce(&mut AccessInfo)) {
+        f(&mut self.0.lock().unwrap())
+    }
+
+    pub fn snapshot(&self) -> AccessInfo {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+// 一行访问日志
+#[derive(Debug, Serialize)]
+pub struct AccessLog {
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: f64,
+    #[serde(flatten)]
+    pub info: AccessInfo,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestTraceLayer;
+
+impl<S> Layer<S> for RequestTraceLayer {
+    type Service = RequestTrace<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestTrace { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestTrace<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RequestTrace<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LEN)
+            .map(|v| v.to_string())
+            .unwrap_or_else(new_request_id);
+        let record = AccessRecord::default();
+        req.extensions_mut().insert(record.clone());
+
+        let method = req.method().to_string();
+        let path = req.uri().path().to_string();
+        let span = info_span!("request", request_id = %request_id, method = %method);
+        let start = Instant::now();
+        let fut = span.in_scope(|| self.inner.call(req));
+
+        Box::pin(
+            async move {
+                let mut resp = fut.await?;
+                // request id只包含可见字符，这里不会失败
+                if let Ok(v) = HeaderValue::from_str(&request_id) {
+                    resp.headers_mut().insert(REQUEST_ID_HEADER, v);
+                }
+
+                let log = AccessLog {
+                    request_id,
+                    method,
+                    path,
+                    status: resp.status().as_u16(),
+                    duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+                    info: record.snapshot(),
+                };
+                if let Ok(line) = serde_json::to_string(&log) {
+                    info!(target: ACCESS_LOG_TARGET, "{}", line);
+                }
+                Ok(resp)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+fn new_request_id() -> String {
+    format!("{:x}-{:x}", *START, NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_ids_should_be_unique() {
+        let (a, b) = (new_request_id(), new_request_id());
+        assert_ne!(a, b);
+        assert!(a.starts_with(&format!("{:x}-", *START)));
+    }
+
+    #[test]
+    fn access_log_should_be_flat_json() {
+        let record = AccessRecord::default();
+        record.update(|info| {
+            info.spec = Some("resize|fliph".into());
+            info.cache = Some("hit");
+        });
+        let log = AccessLog {
+            request_id: "abc".into(),
+            method: "GET".into(),
+            path: "/image/x/y".into(),
+            status: 200,
+            duration_ms: 1.5,
+            info: record.snapshot(),
+        };
+        let value = serde_json::to_value(&log).unwrap();
+        assert_eq!(value["request_id"], "abc");
+        assert_eq!(value["spec"], "resize|fliph");
+        assert_eq!(value["cache"], "hit");
+        assert!(value["source_host"].is_null());
+    }
+}
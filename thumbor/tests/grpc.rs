@@ -0,0 +1,150 @@
+use axum::{handler::get, Router};
+use image::GenericImageView;
+use std::net::TcpListener;
+use thumbor::{
+    pb::{process_request::Source, thumbor_client::ThumborClient, *},
+    AppState, GrpcService, ImageSpecBuilder,
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Channel, Code};
+
+const GRADIENT: &[u8] = include_bytes!("../fixtures/samples/gradient.png");
+
+// 在本地随机端口上提供测试图片，作为thumbor的源站
+fn serve_fixture() -> String {
+    let source = Router::new().route("/gradient.png", get(|| async { GRADIENT.to_vec() }));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(source.into_make_service()),
+    );
+    format!("http://{}/gradient.png", addr)
+}
+
+// 在随机端口上启动gRPC服务，返回连接好的client
+async fn connect(state: AppState) -> ThumborClient<Channel> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(GrpcService::new(&state).into_server())
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    ThumborClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap()
+}
+
+fn request(spec: &ImageSpec, source: Source, id: &str) -> ProcessRequest {
+    ProcessRequest {
+        spec: Some(spec.clone()),
+        source: Some(source),
+        id: id.into(),
+    }
+}
+
+#[tokio::test]
+async fn process_should_accept_url_and_bytes() {
+    let mut client = connect(AppState::default()).await;
+    let spec = ImageSpecBuilder::new()
+        .resize(64, 48, resize::SampleFilter::CatmullRom)
+        .fliph()
+        .png(output::PngCompression::Fast, 0)
+        .build();
+
+    let url = Source::Url(serve_fixture());
+    let resp = client
+        .process(request(&spec, url.clone(), "a"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!((resp.width, resp.height), (64, 48));
+    assert_eq!(
+        (resp.content_type.as_str(), resp.cache.as_str()),
+        ("image/png", "miss")
+    );
+    assert_eq!(resp.id, "a");
+
+    // 第二次命中处理结果的缓存
+    let cached = client
+        .process(request(&spec, url, "b"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(cached.cache, "processed");
+    assert_eq!(cached.data, resp.data);
+
+    // 直接传入数据和通过url获取的结果相同
+    let bytes = Source::Data(GRADIENT.to_vec());
+    let direct = client
+        .process(request(&spec, bytes, "c"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(direct.cache, "");
+    assert_eq!(direct.data, resp.data);
+    let img = image::load_from_memory(&direct.data).unwrap();
+    assert_eq!(img.dimensions(), (64, 48));
+}
+
+#[tokio::test]
+async fn invalid_requests_should_return_status() {
+    let mut client = connect(AppState::default()).await;
+    let spec = ImageSpecBuilder::new().fliph().build();
+
+    let mut req = request(&spec, Source::Data(GRADIENT.to_vec()), "");
+    req.spec = None;
+    let err = client.process(req).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let mut req = request(&spec, Source::Data(GRADIENT.to_vec()), "");
+    req.source = None;
+    let err = client.process(req).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let req = request(&spec, Source::Data(b"not an image".to_vec()), "");
+    let err = client.process(req).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let empty = ImageSpec::new(vec![Spec { data: None }]);
+    let req = request(&empty, Source::Data(GRADIENT.to_vec()), "");
+    let err = client.process(req).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn process_stream_should_keep_order_and_report_errors() {
+    let mut client = connect(AppState::default()).await;
+    let spec = ImageSpecBuilder::new()
+        .resize(32, 24, resize::SampleFilter::Nearest)
+        .build();
+
+    let requests = vec![
+        request(&spec, Source::Data(GRADIENT.to_vec()), "1"),
+        request(&spec, Source::Data(b"not an image".to_vec()), "2"),
+        request(&spec, Source::Url(serve_fixture()), "3"),
+    ];
+    let mut stream = client
+        .process_stream(tokio_stream::iter(requests))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let mut results = Vec::new();
+    while let Some(resp) = stream.message().await.unwrap() {
+        results.push(resp);
+    }
+    let ids: Vec<&str> = results.iter().map(|r| r.id.as_str()).collect();
+    assert_eq!(ids, ["1", "2", "3"]);
+
+    // 失败的请求不影响后面的请求
+    assert!(results[1].data.is_empty());
+    assert!(!results[1].error.is_empty());
+    for resp in [&results[0], &results[2]] {
+        assert!(resp.error.is_empty());
+        assert_eq!((resp.width, resp.height), (32, 24));
+        assert_eq!(resp.content_type, "image/jpeg");
+    }
+}
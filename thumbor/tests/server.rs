@@ -0,0 +1,401 @@
+use axum::{
+    body::Body,
+    handler::get,
+    http::{Request, StatusCode},
+    Router,
+};
+use image::GenericImageView;
+use std::{borrow::Borrow, net::TcpListener};
+use thumbor::{
+    app,
+    cache::{CacheStats, PurgeResult},
+    pb::*,
+    presets::PresetInfo,
+    ratelimit::{Quota, RateLimits, API_KEY_HEADER},
+    server::{PlaceholderBody, PlaceholderKind, Readiness},
+    trace::REQUEST_ID_HEADER,
+    AdminToken, AppState, Client, ImageSpecBuilder, Presets, RateLimiter, UrlSigner,
+};
+use tower::ServiceExt;
+
+const GRADIENT: &[u8] = include_bytes!("../fixtures/samples/gradient.png");
+
+// 在本地随机端口上提供测试图片，作为thumbor的源站
+fn serve_fixture() -> String {
+    serve_bytes("gradient.png", GRADIENT)
+}
+
+fn serve_bytes(name: &str, data: &'static [u8]) -> String {
+    let source = Router::new().route(&format!("/{}", name), get(move || async move { data.to_vec() }));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(source.into_make_service()),
+    );
+    format!("http://{}/{}", addr, name)
+}
+
+async fn get_uri(state: AppState, uri: &str) -> (StatusCode, Vec<u8>) {
+    let resp = app(state)
+        .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = resp.status();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    (status, body.to_vec())
+}
+
+#[tokio::test]
+async fn health_and_readiness_should_work() {
+    let readiness = Readiness::new();
+    let state = AppState {
+        readiness: readiness.clone(),
+        ..Default::default()
+    };
+
+    assert_eq!(get_uri(state.clone(), "/healthz").await.0, StatusCode::OK);
+    assert_eq!(get_uri(state.clone(), "/readyz").await.0, StatusCode::OK);
+
+    readiness.drain();
+    assert_eq!(get_uri(state.clone(), "/healthz").await.0, StatusCode::OK);
+    assert_eq!(
+        get_uri(state, "/readyz").await.0,
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+}
+
+#[tokio::test]
+async fn request_id_should_be_kept_or_generated() {
+    let request = |id: Option<&str>| {
+        let mut builder = Request::builder().uri("/healthz");
+        if let Some(id) = id {
+            builder = builder.header(REQUEST_ID_HEADER, id);
+        }
+        app(AppState::default()).oneshot(builder.body(Body::empty()).unwrap())
+    };
+
+    let resp = request(Some("req-123")).await.unwrap();
+    assert_eq!(resp.headers()[REQUEST_ID_HEADER], "req-123");
+
+    let first = request(None).await.unwrap();
+    let second = request(None).await.unwrap();
+    assert_ne!(
+        first.headers()[REQUEST_ID_HEADER],
+        second.headers()[REQUEST_ID_HEADER]
+    );
+}
+
+#[tokio::test]
+async fn image_should_be_processed() {
+    let source = serve_fixture();
+    let client = Client::new("");
+    let spec = ImageSpecBuilder::new()
+        .resize(64, 48, resize::SampleFilter::CatmullRom)
+        .fliph()
+        .build();
+
+    let (status, body) = get_uri(AppState::default(), &client.image_url(&spec, &source)).await;
+    assert_eq!(status, StatusCode::OK);
+    let img = image::load_from_memory(&body).unwrap();
+    assert_eq!((img.width(), img.height()), (64, 48));
+}
+
+#[tokio::test]
+async fn output_spec_should_choose_format() {
+    let source = serve_fixture();
+    let client = Client::new("");
+
+    let spec = ImageSpecBuilder::new()
+        .png(output::PngCompression::Best, 32)
+        .build();
+    let (status, body) = get_uri(AppState::default(), &client.image_url(&spec, &source)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(image::guess_format(&body).unwrap(), image::ImageFormat::Png);
+
+    let spec = ImageSpecBuilder::new()
+        .jpeg(95, true, output::ChromaSubsampling::Chroma444)
+        .max_bytes(4000)
+        .build();
+    let (status, body) = get_uri(AppState::default(), &client.image_url(&spec, &source)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(image::guess_format(&body).unwrap(), image::ImageFormat::Jpeg);
+    assert!(body.len() <= 4000);
+}
+
+#[tokio::test]
+async fn requests_should_be_rate_limited() {
+    let source = serve_fixture();
+    let uri = Client::new("").image_url(&ImageSpecBuilder::new().fliph().build(), &source);
+    let state = AppState {
+        limiter: RateLimiter::new(RateLimits {
+            hit: Quota::new(1, 0.01),
+            miss: Quota::new(1, 0.01),
+            max_concurrent: 0,
+        })
+        .with_api_keys(vec!["known".to_string()]),
+        ..Default::default()
+    };
+    let request = |api_key: Option<&str>| {
+        let mut builder = Request::builder().uri(uri.as_str());
+        if let Some(key) = api_key {
+            builder = builder.header(API_KEY_HEADER, key);
+        }
+        app(state.clone()).oneshot(builder.body(Body::empty()).unwrap())
+    };
+
+    // 第一次需要获取源图片，第二次命中缓存，两者的配额分开计算
+    assert_eq!(request(None).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(request(None).await.unwrap().status(), StatusCode::OK);
+
+    let resp = request(None).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = resp.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!(retry_after >= 60);
+
+    // 未知的api key仍然按ip（这里是anonymous）限流，配置过的key有自己的配额
+    let resp = request(Some("random")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(request(Some("known")).await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn input_formats_should_be_sniffed() {
+    let client = Client::new("");
+    let spec = ImageSpecBuilder::new()
+        .resize(32, 32, resize::SampleFilter::Nearest)
+        .build();
+
+    // url的扩展名不影响格式识别
+    let sources = [
+        serve_bytes("logo.webp", include_bytes!("../fixtures/formats/logo.webp")),
+        serve_bytes("logo.tiff", include_bytes!("../fixtures/formats/logo.tiff")),
+        serve_bytes("logo.png", include_bytes!("../fixtures/formats/logo.ico")),
+    ];
+    for source in sources {
+        let (status, body) = get_uri(AppState::default(), &client.image_url(&spec, &source)).await;
+        assert_eq!(status, StatusCode::OK, "{}", source);
+        let img = image::load_from_memory(&body).unwrap();
+        assert_eq!((img.width(), img.height()), (32, 32));
+    }
+
+    let source = serve_bytes("text.png", b"not an image");
+    let (status, _) = get_uri(AppState::default(), &client.image_url(&spec, &source)).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn svg_should_be_rasterized_at_resize_size() {
+    let source = serve_bytes("logo.svg", include_bytes!("../fixtures/formats/logo.svg"));
+    let client = Client::new("");
+    let spec = ImageSpecBuilder::new()
+        .resize(160, 80, resize::SampleFilter::CatmullRom)
+        .fliph()
+        .png(output::PngCompression::Fast, 0)
+        .build();
+
+    let (status, body) = get_uri(AppState::default(), &client.image_url(&spec, &source)).await;
+    assert_eq!(status, StatusCode::OK);
+    let img = image::load_from_memory(&body).unwrap().to_rgba8();
+    assert_eq!(img.dimensions(), (160, 80));
+    // 直接按目标尺寸栅格化，放大10倍后红蓝交界处仍然没有过渡色；翻转之后蓝色在左边
+    assert_eq!(img.get_pixel(79, 40).0, [0, 0, 255, 255]);
+    assert_eq!(img.get_pixel(80, 40).0, [255, 0, 0, 255]);
+}
+
+#[tokio::test]
+async fn invalid_requests_should_be_rejected() {
+    let source = serve_fixture();
+    let client = Client::new("");
+
+    // spec不是合法的base64 protobuf
+    let (status, _) = get_uri(AppState::default(), "/image/!!!/abc").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // 没有内容的spec在获取源图片之前就被拒绝
+    let spec = ImageSpec::new(vec![Spec::new_fliph(), Spec { data: None }]);
+    let (status, _) = get_uri(AppState::default(), &client.image_url(&spec, &source)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // resize超过了输出像素上限
+    let spec = ImageSpecBuilder::new()
+        .resize(100000, 100000, resize::SampleFilter::Nearest)
+        .build();
+    let (status, _) = get_uri(AppState::default(), &client.image_url(&spec, &source)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn overlay_should_be_fetched_and_checked() {
+    let source = serve_fixture();
+    let client = Client::new("");
+    let spec = ImageSpecBuilder::new()
+        .resize(64, 48, resize::SampleFilter::Triangle)
+        .overlay(&source, 8, 8, 0.25, 0.5, overlay::BlendMode::Multiply)
+        .build();
+    let (status, body) = get_uri(AppState::default(), &client.image_url(&spec, &source)).await;
+    assert_eq!(status, StatusCode::OK);
+    let img = image::load_from_memory(&body).unwrap();
+    assert_eq!((img.width(), img.height()), (64, 48));
+
+    // 放大后的overlay超过了输出像素上限
+    let spec = ImageSpecBuilder::new()
+        .overlay(&source, 0, 0, 1000.0, 0.0, overlay::BlendMode::Normal)
+        .build();
+    let (status, _) = get_uri(AppState::default(), &client.image_url(&spec, &source)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn placeholder_should_return_json() {
+    let source = serve_fixture();
+    let client = Client::new("");
+    // 空的ImageSpec编码后是空字符串，无法匹配路由，所以至少放一个spec
+    let spec = ImageSpec::new(vec![Spec::new_fliph()]);
+
+    let uri = client.placeholder_url(PlaceholderKind::Palette, &spec, &source);
+    let (status, body) = get_uri(AppState::default(), &uri).await;
+    assert_eq!(status, StatusCode::OK);
+    match serde_json::from_slice(&body).unwrap() {
+        PlaceholderBody::Palette { colors } => assert_eq!(colors.len(), 5),
+        v => panic!("unexpected placeholder {:?}", v),
+    }
+
+    let s: String = spec.borrow().into();
+    let (status, _) = get_uri(
+        AppState::default(),
+        &format!("/placeholder/unknown/{}/x", s),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn signed_url_should_be_required_with_signer() {
+    let source = serve_fixture();
+    let state = AppState {
+        signer: Some(UrlSigner::new("secret")),
+        ..Default::default()
+    };
+    let spec = ImageSpecBuilder::new().flipv().build();
+
+    let unsigned = Client::new("").image_url(&spec, &source);
+    assert_eq!(
+        get_uri(state.clone(), &unsigned).await.0,
+        StatusCode::FORBIDDEN
+    );
+
+    let wrong = Client::new("")
+        .with_signer(UrlSigner::new("wrong"))
+        .image_url(&spec, &source);
+    assert_eq!(
+        get_uri(state.clone(), &wrong).await.0,
+        StatusCode::FORBIDDEN
+    );
+
+    let signed = Client::new("")
+        .with_signer(UrlSigner::new("secret"))
+        .image_url(&spec, &source);
+    assert_eq!(get_uri(state, &signed).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn presets_should_be_listed_and_applied() {
+    let source = serve_fixture();
+    let path = std::env::temp_dir().join(format!("thumbor-it-presets-{}.toml", std::process::id()));
+    tokio::fs::write(&path, r#"thumb = "resize(32, 24, nearest)""#)
+        .await
+        .unwrap();
+    let presets = Presets::default();
+    presets.load(&path).await.unwrap();
+    tokio::fs::remove_file(&path).await.ok();
+
+    let state = AppState {
+        presets,
+        ..Default::default()
+    };
+    let client = Client::new("");
+
+    let (status, body) = get_uri(state.clone(), "/presets").await;
+    assert_eq!(status, StatusCode::OK);
+    let list: Vec<PresetInfo> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(list[0].name, "thumb");
+
+    let (status, body) = get_uri(state.clone(), &client.preset_url("thumb", None, &source)).await;
+    assert_eq!(status, StatusCode::OK);
+    let img = image::load_from_memory(&body).unwrap();
+    assert_eq!((img.width(), img.height()), (32, 24));
+
+    // 额外的spec在preset之后执行
+    let extra = ImageSpecBuilder::new().crop(0, 0, 16, 16).build();
+    let uri = client.preset_url("thumb", Some(&extra), &source);
+    let (status, body) = get_uri(state.clone(), &uri).await;
+    assert_eq!(status, StatusCode::OK);
+    let img = image::load_from_memory(&body).unwrap();
+    assert_eq!((img.width(), img.height()), (16, 16));
+
+    let (status, _) = get_uri(state, &client.preset_url("unknown", None, &source)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn admin_api_should_show_stats_and_purge_cache() {
+    let source = serve_fixture();
+    let state = AppState {
+        admin_token: Some(AdminToken::new("admin")),
+        ..Default::default()
+    };
+    let admin = |method: &str, uri: &str, token: Option<&str>, body: &str| {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            builder = builder.header("authorization", format!("Bearer {}", token));
+        }
+        app(state.clone()).oneshot(builder.body(Body::from(body.to_string())).unwrap())
+    };
+
+    // 第二次请求直接使用处理后的结果
+    let uri = Client::new("").image_url(&ImageSpecBuilder::new().fliph().build(), &source);
+    let (_, first) = get_uri(state.clone(), &uri).await;
+    let (_, second) = get_uri(state.clone(), &uri).await;
+    assert_eq!(first, second);
+
+    let resp = admin("GET", "/admin/cache/stats", None, "").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = admin("GET", "/admin/cache/stats", Some("wrong"), "").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = admin("GET", "/admin/cache/stats", Some("admin"), "").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let stats: CacheStats = serde_json::from_slice(&body).unwrap();
+    assert_eq!((stats.raw.entries, stats.raw.bytes), (1, GRADIENT.len()));
+    assert_eq!((stats.processed.entries, stats.processed.hits), (1, 1));
+    assert_eq!(stats.processed.top_keys[0].bytes, first.len());
+
+    // url和prefix同时指定是无效的请求
+    let body = format!(r#"{{"url": "{}", "prefix": "http://"}}"#, source);
+    let resp = admin("POST", "/admin/cache/purge", Some("admin"), &body).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let body = r#"{"prefix": "http://127.0.0.1:"}"#;
+    let resp = admin("POST", "/admin/cache/purge", Some("admin"), body).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let result: PurgeResult = serde_json::from_slice(&body).unwrap();
+    assert_eq!(result, PurgeResult { raw: 1, processed: 1 });
+
+    let resp = admin("POST", "/admin/cache/purge", Some("admin"), r#"{"all": true}"#)
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let result: PurgeResult = serde_json::from_slice(&body).unwrap();
+    assert_eq!(result, PurgeResult::default());
+
+    // 没有配置token时admin api不存在
+    let (status, _) = get_uri(AppState::default(), "/admin/cache/stats").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}